@@ -1,13 +1,22 @@
 use std::env;
 
 use golgi::{api::friends::RelationshipQuery, sbot::Keystore, Sbot};
+use log::warn;
 
 /// Initialise a connection to a Scuttlebutt server.
 pub async fn init_sbot() -> Result<Sbot, String> {
+    let go_sbot_host = match env::var("GO_SBOT_HOST") {
+        Ok(host) if !host.trim().is_empty() => host.trim().to_string(),
+        Ok(_) => {
+            warn!("GO_SBOT_HOST is empty; using default of 127.0.0.1");
+            "127.0.0.1".to_string()
+        }
+        Err(_) => "127.0.0.1".to_string(),
+    };
     let go_sbot_port = env::var("GO_SBOT_PORT").unwrap_or_else(|_| "8021".to_string());
 
     let keystore = Keystore::GoSbot;
-    let ip_port = Some(format!("127.0.0.1:{}", go_sbot_port));
+    let ip_port = Some(format!("{}:{}", go_sbot_host, go_sbot_port));
     let net_id = None;
 
     Sbot::init(keystore, ip_port, net_id)
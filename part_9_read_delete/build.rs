@@ -0,0 +1,37 @@
+//! Capture build information which is exposed by the `/version` route.
+//!
+//! Cargo reruns this script whenever a file in the package changes, so the
+//! commit and timestamp reflect the most recent build of the sources.
+
+use std::{
+    env,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+fn main() {
+    // The abbreviated hash of the checked-out commit, if built from a git
+    // repository.
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=LYKIN_GIT_COMMIT={}", commit);
+
+    // Honour `SOURCE_DATE_EPOCH` so that reproducible builds embed a fixed
+    // timestamp.
+    let timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0)
+        });
+    println!("cargo:rustc-env=LYKIN_BUILD_TIMESTAMP={}", timestamp);
+}
@@ -0,0 +1,118 @@
+//! An in-memory log of recent activity, such as fetches and follows, which
+//! may be viewed or followed live.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use chrono::Utc;
+use rocket::tokio::sync::broadcast;
+use serde::Serialize;
+
+/// The maximum number of events retained in the activity log. The oldest
+/// events are dropped once the limit is reached.
+const ACTIVITY_LIMIT: usize = 200;
+
+/// A single entry in the activity log.
+#[derive(Clone, Debug, Serialize)]
+pub struct ActivityEvent {
+    /// The time at which the event occurred, as a Unix timestamp.
+    pub timestamp: i64,
+    pub message: String,
+}
+
+/// A bounded log of recent activity events. New events are also broadcast to
+/// any live subscribers.
+#[derive(Clone)]
+pub struct ActivityLog {
+    events: Arc<Mutex<VecDeque<ActivityEvent>>>,
+    sender: broadcast::Sender<ActivityEvent>,
+}
+
+impl Default for ActivityLog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(ACTIVITY_LIMIT);
+
+        ActivityLog {
+            events: Arc::new(Mutex::new(VecDeque::with_capacity(ACTIVITY_LIMIT))),
+            sender,
+        }
+    }
+}
+
+impl ActivityLog {
+    /// Append an event with the given message to the log and broadcast it to
+    /// live subscribers.
+    pub fn record(&self, message: impl Into<String>) {
+        let event = ActivityEvent {
+            timestamp: Utc::now().timestamp(),
+            message: message.into(),
+        };
+
+        let mut events = self.events.lock().unwrap();
+        if events.len() == ACTIVITY_LIMIT {
+            events.pop_front();
+        }
+        events.push_back(event.clone());
+
+        // Sending only fails if there are no live subscribers.
+        let _ = self.sender.send(event);
+    }
+
+    /// Return up to the given number of the most recent events, most recent
+    /// first.
+    pub fn recent(&self, limit: usize) -> Vec<ActivityEvent> {
+        let events = self.events.lock().unwrap();
+
+        events.iter().rev().take(limit).cloned().collect()
+    }
+
+    /// Subscribe to the events recorded from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<ActivityEvent> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn log_is_bounded_and_lists_most_recent_first() {
+        let activity = ActivityLog::default();
+        for i in 0..ACTIVITY_LIMIT + 5 {
+            activity.record(format!("event {}", i));
+        }
+
+        let events = activity.recent(ACTIVITY_LIMIT * 2);
+        assert_eq!(events.len(), ACTIVITY_LIMIT);
+        assert_eq!(events[0].message, format!("event {}", ACTIVITY_LIMIT + 4));
+        assert_eq!(events[ACTIVITY_LIMIT - 1].message, "event 5");
+
+        let messages: Vec<String> = activity
+            .recent(2)
+            .into_iter()
+            .map(|event| event.message)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                format!("event {}", ACTIVITY_LIMIT + 4),
+                format!("event {}", ACTIVITY_LIMIT + 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn subscribers_receive_new_events() {
+        let activity = ActivityLog::default();
+        activity.record("before subscribing");
+
+        let mut receiver = activity.subscribe();
+        activity.record("after subscribing");
+
+        assert_eq!(receiver.try_recv().unwrap().message, "after subscribing");
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,83 @@
+//! The configuration in effect, as resolved from the environment at startup.
+
+use std::path::Path;
+
+use log::info;
+use serde::Serialize;
+
+use crate::{health, history, routes, sbot, webhook};
+
+/// The settings resolved from the environment (or their defaults), used to
+/// verify which settings took effect.
+#[derive(Debug, Serialize)]
+pub struct ResolvedConfig {
+    pub database_path: String,
+    pub sbot_host: String,
+    pub sbot_port: String,
+    /// Whether a network key other than the default (main network) key is
+    /// used to connect to the sbot.
+    pub custom_network_key: bool,
+    /// The path of the secret key file of the identity used, if other than
+    /// that of the local go-sbot.
+    pub key_path: Option<String>,
+    pub read_only: bool,
+    pub date_format: String,
+    pub duplicate_window: usize,
+    /// The number of words for which posts are filtered.
+    pub filter_words: usize,
+    pub subscribe_fetch: String,
+    pub refresh_on_view: bool,
+    /// In seconds.
+    pub refresh_stale_after: i64,
+    pub auto_read_on_open: bool,
+    /// In seconds.
+    pub health_check_interval: u64,
+    /// In seconds.
+    pub unread_sample_interval: u64,
+    pub feed_limit: usize,
+    /// The scheme and host of the webhook URL; the path and query, which may
+    /// hold a secret token, are redacted.
+    pub webhook: Option<String>,
+}
+
+impl ResolvedConfig {
+    /// Resolve the configuration from the environment, given the path of the
+    /// database.
+    pub fn resolve(database_path: &Path) -> ResolvedConfig {
+        let subscribe_fetch = match sbot::subscribe_fetch() {
+            sbot::SubscribeFetch::Latest(0) => "none".to_string(),
+            sbot::SubscribeFetch::Latest(limit) => format!("latest:{}", limit),
+            sbot::SubscribeFetch::All => "all".to_string(),
+        };
+
+        ResolvedConfig {
+            database_path: database_path.display().to_string(),
+            sbot_host: sbot::sbot_host(),
+            sbot_port: sbot::sbot_port(),
+            custom_network_key: sbot::network_key().is_some(),
+            key_path: sbot::key_path(),
+            read_only: sbot::read_only(),
+            date_format: sbot::date_format().to_string(),
+            duplicate_window: sbot::duplicate_window(),
+            filter_words: sbot::filter_words().len(),
+            subscribe_fetch,
+            refresh_on_view: sbot::refresh_on_view(),
+            refresh_stale_after: sbot::refresh_stale_after(),
+            auto_read_on_open: sbot::auto_read_on_open(),
+            health_check_interval: health::check_interval().as_secs(),
+            unread_sample_interval: history::sample_interval().as_secs(),
+            feed_limit: routes::feed_limit(),
+            webhook: webhook::webhook_url().map(|url| webhook::redact_url(&url)),
+        }
+    }
+
+    /// Log each setting at the info level.
+    pub fn log(&self) {
+        info!("Resolved configuration:");
+        if let Ok(serde_json::Value::Object(settings)) = serde_json::to_value(self) {
+            for (name, value) in settings {
+                info!("  {}: {}", name, value);
+            }
+        }
+    }
+}
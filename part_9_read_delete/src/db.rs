@@ -1,8 +1,37 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    path::Path,
+};
 
+use chrono::Utc;
 use log::{debug, info};
 use serde::{Deserialize, Serialize};
-use sled::{Batch, Db, IVec, Result, Tree};
+use serde_json::{json, Value};
+use sled::{
+    transaction::{TransactionError, TransactionResult, Transactional},
+    Batch, Db, IVec, Result, Tree,
+};
+
+use crate::{sbot, utils};
+
+/// The key in the default database tree under which the storage format of
+/// the post tree is recorded.
+const POST_FORMAT_KEY: &str = "post_format";
+
+/// The current storage format of the post tree. Version 1 drops the stored
+/// date of each post, which is instead derived from the timestamp, and adds
+/// the author, the duplicate, filtered and starred states, the like count,
+/// the key of the quoted message, the time at which the post was read and the
+/// channels mentioned in the post.
+const POST_FORMAT_VERSION: u8 = 1;
+
+/// The maximum number of unread count samples retained in the unread history
+/// tree. The oldest samples are removed once the limit is reached.
+const UNREAD_HISTORY_LIMIT: usize = 1000;
+
+/// The number of posts after which the progress of an index rebuild is
+/// logged.
+const REINDEX_PROGRESS_INTERVAL: usize = 1000;
 
 /// Scuttlebutt peer data.
 #[derive(Debug, Deserialize, Serialize)]
@@ -10,6 +39,50 @@ pub struct Peer {
     pub public_key: String,
     pub name: String,
     pub latest_sequence: u64,
+    /// The archived state of the peer; true if the peer has been unfollowed
+    /// but their posts have been kept.
+    #[serde(default)]
+    pub archived: bool,
+    /// Free-form notes about the peer, which are kept locally and never
+    /// published.
+    #[serde(default)]
+    pub notes: String,
+    /// The time at which posts were last fetched for the peer, as a Unix
+    /// timestamp; zero if they have never been fetched.
+    #[serde(default)]
+    pub last_fetched: i64,
+    /// The blob ID of the profile image of the peer, if any.
+    #[serde(default)]
+    pub image_blob: Option<String>,
+    /// The self-description of the peer, as set in their latest about
+    /// message with a description.
+    #[serde(default)]
+    pub description: String,
+    /// The sequence number of the latest about message published by the
+    /// peer about themselves, as of the last time their name was fetched;
+    /// zero if unknown.
+    #[serde(default)]
+    pub latest_about_sequence: u64,
+}
+
+/// The layout of the Peer struct used by earlier versions of lykin, before
+/// any additional fields were introduced.
+///
+/// bincode is not self-describing and does not honour `#[serde(default)]`,
+/// so peer data written in this layout is deserialized separately.
+#[derive(Deserialize)]
+struct LegacyPeer {
+    public_key: String,
+    name: String,
+    latest_sequence: u64,
+}
+
+impl From<LegacyPeer> for Peer {
+    fn from(peer: LegacyPeer) -> Peer {
+        Peer::new(&peer.public_key)
+            .set_name(&peer.name)
+            .set_latest_sequence(peer.latest_sequence)
+    }
 }
 
 impl Peer {
@@ -20,9 +93,24 @@ impl Peer {
             public_key: public_key.to_string(),
             name: "".to_string(),
             latest_sequence: 0,
+            archived: false,
+            notes: "".to_string(),
+            last_fetched: 0,
+            image_blob: None,
+            description: "".to_string(),
+            latest_about_sequence: 0,
         }
     }
 
+    /// Deserialize peer data from bincode, falling back to the legacy layout
+    /// of the Peer struct for data written by earlier versions of lykin.
+    fn from_bincode(bytes: &[u8]) -> Peer {
+        bincode::deserialize(bytes).unwrap_or_else(|_| {
+            let legacy_peer: LegacyPeer = bincode::deserialize(bytes).unwrap();
+            legacy_peer.into()
+        })
+    }
+
     /// Modify the name field of an instance of the Peer struct, leaving
     /// the other values unchanged.
     pub fn set_name(self, name: &str) -> Peer {
@@ -40,16 +128,93 @@ impl Peer {
             ..self
         }
     }
+
+    /// Modify the archived field of an instance of the Peer struct, leaving
+    /// the other values unchanged.
+    pub fn set_archived(self, archived: bool) -> Peer {
+        Self { archived, ..self }
+    }
+
+    /// Modify the last_fetched field of an instance of the Peer struct,
+    /// leaving the other values unchanged.
+    pub fn set_last_fetched(self, last_fetched: i64) -> Peer {
+        Self {
+            last_fetched,
+            ..self
+        }
+    }
+
+    /// Modify the description field of an instance of the Peer struct,
+    /// leaving the other values unchanged.
+    pub fn set_description(self, description: &str) -> Peer {
+        Self {
+            description: description.to_string(),
+            ..self
+        }
+    }
+
+    /// Modify the image_blob field of an instance of the Peer struct, leaving
+    /// the other values unchanged.
+    pub fn set_image_blob(self, image_blob: Option<String>) -> Peer {
+        Self { image_blob, ..self }
+    }
+
+    /// Modify the latest_about_sequence field of an instance of the Peer
+    /// struct, leaving the other values unchanged.
+    pub fn set_latest_about_sequence(self, latest_about_sequence: u64) -> Peer {
+        Self {
+            latest_about_sequence,
+            ..self
+        }
+    }
+
+    /// Merge the metadata of the given duplicate of this peer into this peer,
+    /// preferring non-empty fields and the greater latest sequence number.
+    fn merge(self, duplicate: Peer) -> Peer {
+        let name = if self.name.is_empty() {
+            duplicate.name
+        } else {
+            self.name
+        };
+        let notes = if self.notes.is_empty() {
+            duplicate.notes
+        } else {
+            self.notes
+        };
+        let description = if self.description.is_empty() {
+            duplicate.description
+        } else {
+            self.description
+        };
+
+        Self {
+            name,
+            notes,
+            description,
+            latest_sequence: self.latest_sequence.max(duplicate.latest_sequence),
+            last_fetched: self.last_fetched.max(duplicate.last_fetched),
+            image_blob: self.image_blob.or(duplicate.image_blob),
+            latest_about_sequence: self
+                .latest_about_sequence
+                .max(duplicate.latest_about_sequence),
+            ..self
+        }
+    }
 }
 
 /// The text and metadata of a Scuttlebutt root post.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Serialize)]
 pub struct Post {
     /// The key of the post-type message, also known as a message reference.
     pub key: String,
+    /// The public key of the author of the post. It is set when the post is
+    /// inserted into the post tree.
+    pub author: String,
     /// The text of the post (may be formatted as markdown).
     pub text: String,
-    /// The date the post was published (e.g. 17 May 2021).
+    /// The date the post was published (e.g. 17 May 2021). The date is not
+    /// stored; it is formatted from the timestamp using the configured date
+    /// format.
     pub date: String,
     /// The sequence number of the post-type message.
     pub sequence: u64,
@@ -57,71 +222,769 @@ pub struct Post {
     pub read: bool,
     /// The timestamp representing the date the post was published.
     pub timestamp: i64,
-    /// The subject of the post, represented as the first 53 characters of
-    /// the post text.
+    /// The subject of the post, represented as the first 52 characters of
+    /// the post text with markdown stripped. Only posts too long to be shown
+    /// in full have a subject.
     pub subject: Option<String>,
+    /// The duplicate state of the post; true if the text matches that of one
+    /// of the preceding posts by the same author.
+    pub duplicate: bool,
+    /// The number of peers we follow who like the post.
+    pub like_count: u32,
+    /// The filtered state of the post; true if the text contains one of the
+    /// configured filter words. Filtered posts are marked as read when they
+    /// are fetched and are hidden from post lists by default.
+    pub filtered: bool,
+    /// The starred state of the post; true if starred.
+    pub starred: bool,
+    /// The key of the message quoted by the post, if any.
+    pub quotes: Option<String>,
+    /// The timestamp at which the post was marked as read, if it is read and
+    /// was marked as read since this was recorded.
+    pub read_at: Option<i64>,
+    /// The channels (hashtags) mentioned in the text of the post, in
+    /// lowercase and without the `#` prefix.
+    pub channels: Vec<String>,
 }
 
 impl Post {
-    // Create a new instance of the Post struct. A default value of `false` is
-    // set for `read`.
-    pub fn new(
-        key: String,
-        text: String,
-        date: String,
-        sequence: u64,
-        timestamp: i64,
-        subject: Option<String>,
-    ) -> Post {
+    /// Return a builder for an instance of the Post struct with the given
+    /// key. Default values are used for any fields which are not set.
+    pub fn builder(key: impl Into<String>) -> PostBuilder {
+        PostBuilder::new(key)
+    }
+
+    /// Set the read state of the post. The time at which the post was marked
+    /// as read is recorded, unless it already was, and cleared if the post is
+    /// marked as unread.
+    pub fn set_read(&mut self, read: bool) {
+        self.read_at = match read {
+            true => self.read_at.or_else(|| Some(Utc::now().timestamp())),
+            false => None,
+        };
+        self.read = read;
+    }
+
+    /// Serialize the post to bincode using the storage layout of the post
+    /// tree.
+    fn to_bincode(&self) -> Vec<u8> {
+        let stored_post = StoredPost {
+            key: self.key.as_str(),
+            author: self.author.as_str(),
+            text: self.text.as_str(),
+            sequence: self.sequence,
+            read: self.read,
+            timestamp: self.timestamp,
+            subject: self.subject.as_deref(),
+            duplicate: self.duplicate,
+            like_count: self.like_count,
+            filtered: self.filtered,
+            starred: self.starred,
+            quotes: self.quotes.as_deref(),
+            read_at: self.read_at,
+            channels: self.channels.iter().map(String::as_str).collect(),
+        };
+
+        bincode::serialize(&stored_post).unwrap()
+    }
+
+    /// Deserialize a post from bincode stored in the post tree, formatting
+    /// the date from the timestamp.
+    fn from_bincode(bytes: &[u8]) -> Post {
+        let stored_post: StoredPost<String> = bincode::deserialize(bytes).unwrap();
+
         Post {
-            key,
+            date: format_post_date(stored_post.timestamp),
+            key: stored_post.key,
+            author: stored_post.author,
+            text: stored_post.text,
+            sequence: stored_post.sequence,
+            read: stored_post.read,
+            timestamp: stored_post.timestamp,
+            subject: stored_post.subject,
+            duplicate: stored_post.duplicate,
+            like_count: stored_post.like_count,
+            filtered: stored_post.filtered,
+            starred: stored_post.starred,
+            quotes: stored_post.quotes,
+            read_at: stored_post.read_at,
+            channels: stored_post.channels,
+        }
+    }
+}
+
+/// Format the given timestamp as the date a post was published, using the
+/// configured date format.
+fn format_post_date(timestamp: i64) -> String {
+    sbot::format_date(timestamp, sbot::date_format())
+}
+
+/// The layout in which instances of the Post struct are stored in the post
+/// tree. The date is omitted since it is derived from the timestamp.
+///
+/// The layout is generic over the string type so that posts may be
+/// serialized, and their text deserialized, without copying.
+#[derive(Deserialize, Serialize)]
+struct StoredPost<S> {
+    key: S,
+    author: S,
+    text: S,
+    sequence: u64,
+    read: bool,
+    timestamp: i64,
+    subject: Option<S>,
+    duplicate: bool,
+    like_count: u32,
+    filtered: bool,
+    starred: bool,
+    quotes: Option<S>,
+    read_at: Option<i64>,
+    channels: Vec<S>,
+}
+
+/// Return `true` if the post stored as the given bincode is unread and not
+/// filtered, without copying its text.
+fn is_unread(post_bytes: &[u8]) -> bool {
+    let stored_post: StoredPost<&str> = bincode::deserialize(post_bytes).unwrap();
+
+    !stored_post.read && !stored_post.filtered
+}
+
+/// The layout in which instances of the Post struct were stored before the
+/// date was dropped from the post tree (version 0 of the storage format).
+#[derive(Deserialize)]
+struct PostV0 {
+    key: String,
+    text: String,
+    #[allow(dead_code)]
+    date: String,
+    sequence: u64,
+    read: bool,
+    timestamp: i64,
+    subject: Option<String>,
+}
+
+impl From<PostV0> for StoredPost<String> {
+    fn from(post: PostV0) -> StoredPost<String> {
+        StoredPost {
+            key: post.key,
+            author: String::new(),
+            text: post.text,
+            sequence: post.sequence,
+            read: post.read,
+            timestamp: post.timestamp,
+            subject: post.subject,
+            duplicate: false,
+            like_count: 0,
+            filtered: false,
+            starred: false,
+            quotes: None,
+            read_at: None,
+            channels: Vec::new(),
+        }
+    }
+}
+
+/// Return the public key of the author of a post from the key under which the
+/// post is stored, `{public_key}_{msg_id}`. Public keys are base64 encoded
+/// with the standard alphabet, which does not include underscores.
+fn author_from_post_key(post_key: &[u8]) -> String {
+    let post_key = String::from_utf8_lossy(post_key);
+    match post_key.split_once('_') {
+        Some((author, _)) => author.to_string(),
+        None => post_key.to_string(),
+    }
+}
+
+/// Return the key under which the post with the given key, authored by the
+/// given public key and published at the given timestamp, is stored in the
+/// post date index.
+///
+/// The timestamp is zero-padded so that the keys of the posts of each peer
+/// sort chronologically; posts dated before the epoch are indexed at the
+/// epoch.
+fn date_index_key(public_key: &str, timestamp: i64, msg_key: &str) -> String {
+    format!("{}_{:020}_{}", public_key, timestamp.max(0), msg_key)
+}
+
+/// Return the key of the like of the message with the given key by the given
+/// public key in the like tree.
+fn like_key(msg_key: &str, public_key: &str) -> String {
+    format!("{}{}", msg_key, public_key)
+}
+
+/// Return the given name of a database tree or key, suffixed with the given
+/// identity, if any.
+fn namespaced(name: &str, identity: Option<&str>) -> String {
+    match identity {
+        Some(identity) => format!("{}_{}", name, identity),
+        None => name.to_string(),
+    }
+}
+
+/// Open the database tree with the given name of the given identity, if any.
+fn open_tree(db: &Db, name: &str, identity: Option<&str>) -> Tree {
+    let tree_name = namespaced(name, identity);
+    debug!("Opening '{}' database tree", tree_name);
+    db.open_tree(&tree_name)
+        .unwrap_or_else(|e| panic!("Failed to open '{}' database tree: {}", tree_name, e))
+}
+
+/// The metadata of a Scuttlebutt root post, used for list views where the
+/// full text of each post is not required.
+#[derive(Debug, Serialize)]
+pub struct PostSummary {
+    /// The key of the post-type message, also known as a message reference.
+    pub key: String,
+    /// The text of the post if it has no subject, otherwise an empty string.
+    /// Posts without a subject are short enough to be shown in full.
+    pub text: String,
+    /// The date the post was published (e.g. 17 May 2021).
+    pub date: String,
+    /// The sequence number of the post-type message.
+    pub sequence: u64,
+    /// The read state of the post; true if read, false if unread.
+    pub read: bool,
+    /// The timestamp representing the date the post was published.
+    pub timestamp: i64,
+    /// The subject of the post, represented as the first 52 characters of
+    /// the post text with markdown stripped. Only posts too long to be shown
+    /// in full have a subject.
+    pub subject: Option<String>,
+    /// The duplicate state of the post; true if the text matches that of one
+    /// of the preceding posts by the same author.
+    pub duplicate: bool,
+    /// The filtered state of the post; true if the text contains one of the
+    /// configured filter words.
+    pub filtered: bool,
+    /// The starred state of the post; true if starred.
+    pub starred: bool,
+}
+
+impl From<StoredPost<&str>> for PostSummary {
+    fn from(stored_post: StoredPost<&str>) -> PostSummary {
+        // The text is borrowed from the serialized bytes and only copied if
+        // the post has no subject.
+        let text = match stored_post.subject {
+            Some(_) => String::new(),
+            None => stored_post.text.to_string(),
+        };
+
+        PostSummary {
+            key: stored_post.key.to_string(),
             text,
-            date,
-            sequence,
-            timestamp,
-            subject,
+            date: format_post_date(stored_post.timestamp),
+            sequence: stored_post.sequence,
+            read: stored_post.read,
+            timestamp: stored_post.timestamp,
+            subject: stored_post.subject.map(|subject| subject.to_string()),
+            duplicate: stored_post.duplicate,
+            filtered: stored_post.filtered,
+            starred: stored_post.starred,
+        }
+    }
+}
+
+/// A builder for instances of the Post struct.
+#[derive(Debug, Default)]
+pub struct PostBuilder {
+    key: String,
+    author: String,
+    text: String,
+    sequence: u64,
+    timestamp: i64,
+    subject: Option<String>,
+    quotes: Option<String>,
+    channels: Vec<String>,
+}
+
+impl PostBuilder {
+    /// Create a new instance of the PostBuilder struct using the given key.
+    /// Default values are set for all other fields.
+    pub fn new(key: impl Into<String>) -> PostBuilder {
+        PostBuilder {
+            key: key.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the public key of the author of the post.
+    pub fn author(self, author: impl Into<String>) -> PostBuilder {
+        Self {
+            author: author.into(),
+            ..self
+        }
+    }
+
+    /// Set the text of the post.
+    pub fn text(self, text: impl Into<String>) -> PostBuilder {
+        Self {
+            text: text.into(),
+            ..self
+        }
+    }
+
+    /// Set the sequence number of the post-type message.
+    pub fn sequence(self, sequence: u64) -> PostBuilder {
+        Self { sequence, ..self }
+    }
+
+    /// Set the timestamp representing the date the post was published.
+    pub fn timestamp(self, timestamp: i64) -> PostBuilder {
+        Self { timestamp, ..self }
+    }
+
+    /// Set the subject of the post.
+    pub fn subject(self, subject: Option<String>) -> PostBuilder {
+        Self { subject, ..self }
+    }
+
+    /// Set the key of the message quoted by the post.
+    pub fn quotes(self, quotes: Option<String>) -> PostBuilder {
+        Self { quotes, ..self }
+    }
+
+    /// Set the channels (hashtags) mentioned in the post.
+    pub fn channels(self, channels: Vec<String>) -> PostBuilder {
+        Self { channels, ..self }
+    }
+
+    /// Build an instance of the Post struct from the values of the builder.
+    /// A default value of `false` is set for `read`, `duplicate`, `filtered`
+    /// and `starred`, the like count is zero and the date is formatted from
+    /// the timestamp.
+    pub fn build(self) -> Post {
+        Post {
+            key: self.key,
+            author: self.author,
+            text: self.text,
+            date: format_post_date(self.timestamp),
+            sequence: self.sequence,
             read: false,
+            timestamp: self.timestamp,
+            subject: self.subject,
+            duplicate: false,
+            like_count: 0,
+            filtered: false,
+            starred: false,
+            quotes: self.quotes,
+            read_at: None,
+            channels: self.channels,
+        }
+    }
+}
+
+/// A peer and their posts, in the shape produced by `Database::export_peer`.
+#[derive(Debug, Deserialize)]
+pub struct PeerImport {
+    pub peer: Peer,
+    pub posts: Vec<ImportedPost>,
+}
+
+/// A post of an imported peer. Fields which are derived when a post is
+/// loaded (such as the date) are ignored.
+#[derive(Debug, Deserialize)]
+pub struct ImportedPost {
+    pub key: String,
+    pub text: String,
+    pub sequence: u64,
+    pub read: bool,
+    pub timestamp: i64,
+    pub subject: Option<String>,
+    /// Absent from documents exported before posts could be starred.
+    #[serde(default)]
+    pub starred: bool,
+    /// Absent from documents exported before quoted messages were stored.
+    #[serde(default)]
+    pub quotes: Option<String>,
+    /// Absent from documents exported before the time at which posts were
+    /// read was stored.
+    #[serde(default)]
+    pub read_at: Option<i64>,
+}
+
+/// The number of posts added and skipped when importing a peer.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ImportCounts {
+    /// Posts which were not yet in the database.
+    pub added: usize,
+    /// Posts which were already in the database and kept their local state.
+    pub skipped: usize,
+    /// Posts which were not imported because they have been deleted.
+    pub deleted: usize,
+}
+
+/// The outcome of rebuilding the data derived from the stored posts.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct ReindexCounts {
+    /// Posts which were checked.
+    pub posts: usize,
+    /// Posts whose derived data was out of date and has been rewritten.
+    pub updated: usize,
+    /// Replies which were removed because their root post is not stored.
+    pub removed_replies: usize,
+}
+
+/// The order in which posts are sorted by timestamp.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    /// Oldest posts first.
+    Ascending,
+    /// Most recent posts first.
+    Descending,
+}
+
+impl SortOrder {
+    /// Return the order selected by the given `sort` query parameter:
+    /// `oldest` for oldest posts first and `newest` for most recent posts
+    /// first. Most recent posts are first if the parameter is absent or
+    /// unrecognized.
+    pub fn from_query(sort: Option<&str>) -> SortOrder {
+        match sort {
+            Some("oldest") => SortOrder::Ascending,
+            _ => SortOrder::Descending,
+        }
+    }
+
+    /// Return the value of the `sort` query parameter selecting the order.
+    pub fn as_query(&self) -> &'static str {
+        match self {
+            SortOrder::Ascending => "oldest",
+            SortOrder::Descending => "newest",
         }
     }
 }
 
+/// An action applied to several posts at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BulkAction {
+    /// Mark the posts as read.
+    Read,
+    /// Mark the posts as unread.
+    Unread,
+    /// Remove the posts from the post tree.
+    Delete,
+    /// Mark the posts as starred.
+    Star,
+    /// Mark the posts as not starred.
+    Unstar,
+}
+
 /// An instance of the key-value database and relevant trees.
 #[allow(dead_code)]
 #[derive(Clone)]
 pub struct Database {
     /// The sled database instance.
     db: Db,
+    /// The key under which the post format version of the trees is stored.
+    post_format_key: String,
     /// A database tree containing Peer struct instances for all the peers
     /// we are subscribed to.
     peer_tree: Tree,
     /// A database tree containing Post struct instances for all of the posts
     /// we have downloaded from the peer to whom we subscribe.
     pub post_tree: Tree,
+    /// A database tree containing Post struct instances for the replies of
+    /// each peer to their own root posts, keyed by the public key of the
+    /// peer, the key of the root post and the key of the reply.
+    reply_tree: Tree,
+    /// A database tree containing Post struct instances for the replies of
+    /// any peer to a root post, keyed by the key of the root post and the key
+    /// of the reply. Replies are kept even if the root post has not been
+    /// downloaded, so that they are shown with it once it is.
+    comment_tree: Tree,
+    /// A database tree containing the keys of deleted posts, keyed by the
+    /// public key of the author and the key of the post. Posts with a key in
+    /// this tree are not inserted again when they are fetched.
+    deleted_tree: Tree,
+    /// A database tree containing samples of the total number of unread
+    /// posts, keyed by the timestamp at which each sample was taken.
+    unread_history_tree: Tree,
+    /// A database tree containing the keys of the posts in the post tree,
+    /// keyed by the public key of the author, the timestamp of the post and
+    /// the key of the post (see `date_index_key`), so that the posts of a
+    /// peer can be retrieved by date without reading all of them. Entries of
+    /// posts which have since been removed or redated are skipped on lookup.
+    post_date_index: Tree,
+    /// A database tree containing Post struct instances for the decrypted
+    /// private messages (DMs) which we can read, keyed by the public key of
+    /// the author and the key of the message. Private messages are kept
+    /// apart from public posts.
+    private_tree: Tree,
+    /// A database tree containing the sequence number of the latest message
+    /// which has been searched for private messages, keyed by the public key
+    /// of the author of the feed, so that each feed is only searched from
+    /// where the previous search ended.
+    private_sequence_tree: Tree,
+    /// A database tree containing an entry for each like of a message by a
+    /// peer we follow, keyed by the key of the message and the public key of
+    /// the liker. Entries are removed when the like is cancelled by an
+    /// unvote.
+    like_tree: Tree,
+    /// A database tree containing the sequence number of the latest message
+    /// which has been searched for votes, keyed by the public key of the
+    /// author of the feed, so that each feed is only searched from where the
+    /// previous tally ended.
+    vote_sequence_tree: Tree,
 }
 
 impl Database {
     /// Initialise the database by opening the database file, loading the
     /// peers tree and returning an instantiated Database struct.
-    pub fn init(path: &Path) -> Self {
+    ///
+    /// If an identity (public key) is given, the trees of that identity are
+    /// loaded (see `open_trees`).
+    pub fn init(path: &Path, identity: Option<&str>) -> Self {
         // Open the database at the given path.
         // The database will be created if it does not yet exist.
         // This code will panic if an IO error is encountered.
         info!("Initialising sled database");
         let db = sled::open(path).expect("Failed to open database");
-        debug!("Opening 'peers' database tree");
-        let peer_tree = db
-            .open_tree("peers")
-            .expect("Failed to open 'peers' database tree");
-        debug!("Opening 'posts' database tree");
-        let post_tree = db
-            .open_tree("posts")
-            .expect("Failed to open 'posts' database tree");
-
-        Database {
+
+        Database::open_trees(db, identity)
+    }
+
+    /// Initialise a temporary database which is kept in memory and removed
+    /// when dropped. This is intended for use in tests.
+    #[cfg(test)]
+    pub fn init_temp() -> Self {
+        info!("Initialising temporary sled database");
+        let db = sled::Config::new()
+            .temporary(true)
+            .open()
+            .expect("Failed to open temporary database");
+
+        Database::open_trees(db, None)
+    }
+
+    /// Open the peers and posts trees of the given sled database and return
+    /// an instantiated Database struct.
+    ///
+    /// If an identity is given, the trees (and the post format version) of
+    /// that identity are used, so that the data of different identities is
+    /// kept apart. Otherwise the trees shared by all identities are used.
+    fn open_trees(db: Db, identity: Option<&str>) -> Self {
+        let peer_tree = open_tree(&db, "peers", identity);
+        let post_tree = open_tree(&db, "posts", identity);
+        let reply_tree = open_tree(&db, "replies", identity);
+        let comment_tree = open_tree(&db, "comments", identity);
+        let deleted_tree = open_tree(&db, "deleted", identity);
+        let unread_history_tree = open_tree(&db, "unread_history", identity);
+        let post_date_index = open_tree(&db, "post_date_index", identity);
+        let private_tree = open_tree(&db, "private", identity);
+        let private_sequence_tree = open_tree(&db, "private_sequences", identity);
+        let like_tree = open_tree(&db, "likes", identity);
+        let vote_sequence_tree = open_tree(&db, "vote_sequences", identity);
+
+        let database = Database {
             db,
+            post_format_key: namespaced(POST_FORMAT_KEY, identity),
             peer_tree,
             post_tree,
+            reply_tree,
+            comment_tree,
+            deleted_tree,
+            unread_history_tree,
+            post_date_index,
+            private_tree,
+            private_sequence_tree,
+            like_tree,
+            vote_sequence_tree,
+        };
+        database
+            .migrate_posts()
+            .expect("Failed to migrate 'posts' database tree");
+        // Posts stored before the date index was introduced are indexed once.
+        if database.post_date_index.is_empty() && !database.post_tree.is_empty() {
+            database
+                .rebuild_post_date_index()
+                .expect("Failed to build 'post_date_index' database tree");
+        }
+
+        database
+    }
+
+    /// Migrate the posts in the post tree to the current storage format, if
+    /// they are stored in an older format.
+    ///
+    /// Version 0 posts are rewritten without the stored date and marked as
+    /// not duplicate, without likes, not filtered, not starred, quoting no
+    /// message and without a time at which they were read. The author of
+    /// each post is backfilled from the key under which it is stored and the
+    /// channels of each post are extracted from its text. The format version
+    /// is recorded once the migration is complete, so that each database is
+    /// only migrated once.
+    fn migrate_posts(&self) -> Result<()> {
+        let version = self
+            .db
+            .get(&self.post_format_key)?
+            .and_then(|version| version.first().copied())
+            .unwrap_or(0);
+
+        if version < POST_FORMAT_VERSION {
+            info!(
+                "Migrating 'posts' database tree from format version {} to {}",
+                version, POST_FORMAT_VERSION
+            );
+            // The other trees of posts did not exist before the current
+            // format, so only the post tree holds posts to migrate.
+            let mut post_batch = Batch::default();
+
+            for post in self.post_tree.iter() {
+                let (post_key, post_bytes) = post?;
+                let stored_post: StoredPost<String> =
+                    bincode::deserialize::<PostV0>(&post_bytes).unwrap().into();
+                let stored_post = StoredPost {
+                    author: author_from_post_key(&post_key),
+                    channels: utils::extract_channels(&stored_post.text),
+                    ..stored_post
+                };
+                post_batch.insert(post_key, bincode::serialize(&stored_post).unwrap());
+            }
+
+            self.post_tree.apply_batch(post_batch)?;
+            self.db
+                .insert(&self.post_format_key, &[POST_FORMAT_VERSION])?;
+        }
+
+        Ok(())
+    }
+
+    /// Recompute the data derived from the stored posts: the author, channels,
+    /// filtered and duplicate state of each post are derived again from its
+    /// key and text, using the given filter words and duplicate window, and replies
+    /// whose root post is no longer stored are removed. The post date index
+    /// is rebuilt from scratch.
+    ///
+    /// The local read and starred states are kept. Only posts which change
+    /// are rewritten, so rebuilding the indexes again has no further effect.
+    /// Progress is logged as the posts of each peer are checked.
+    pub fn rebuild_indexes(
+        &self,
+        filter_words: &[String],
+        duplicate_window: usize,
+    ) -> Result<ReindexCounts> {
+        info!("Rebuilding indexes of 'posts' and 'replies' database trees");
+        let mut counts = ReindexCounts::default();
+
+        let mut authors = BTreeSet::new();
+        for post in self.post_tree.iter() {
+            let (post_key, _) = post?;
+            authors.insert(author_from_post_key(&post_key));
+        }
+
+        for author in authors {
+            let prefix = format!("{}_", author);
+            let mut posts = Vec::new();
+            for post in self.post_tree.scan_prefix(prefix.as_bytes()) {
+                let (post_key, post_bytes) = post?;
+                posts.push((post_key, Post::from_bincode(&post_bytes)));
+            }
+            // Duplicates are detected in the order in which the posts were
+            // published, as when they are fetched.
+            posts.sort_by_key(|(_, post)| post.sequence);
+
+            let mut post_batch = Batch::default();
+            let mut recent_texts: VecDeque<String> = VecDeque::new();
+            for (post_key, mut post) in posts {
+                let author = author_from_post_key(&post_key);
+                let channels = utils::extract_channels(&post.text);
+                let filtered = utils::contains_filter_word(&post.text, filter_words);
+                let duplicate = recent_texts.contains(&post.text);
+                if post.author != author
+                    || post.channels != channels
+                    || post.filtered != filtered
+                    || post.duplicate != duplicate
+                {
+                    post.author = author;
+                    post.channels = channels;
+                    post.filtered = filtered;
+                    post.duplicate = duplicate;
+                    post_batch.insert(post_key, post.to_bincode());
+                    counts.updated += 1;
+                }
+
+                recent_texts.push_back(post.text);
+                if recent_texts.len() > duplicate_window {
+                    recent_texts.pop_front();
+                }
+
+                counts.posts += 1;
+                if counts.posts % REINDEX_PROGRESS_INTERVAL == 0 {
+                    info!("Checked {} posts", counts.posts);
+                }
+            }
+
+            self.post_tree.apply_batch(post_batch)?;
+            debug!("Rebuilt indexes of posts of peer {}", &author);
+        }
+
+        // Replies are stored under `{public_key}_{root_key}_{reply_key}`, and
+        // message keys do not include underscores.
+        let mut reply_batch = Batch::default();
+        for reply in self.reply_tree.iter() {
+            let (reply_key, _) = reply?;
+            let reply_key_str = String::from_utf8_lossy(&reply_key);
+            let root_post_key = match reply_key_str.rsplit_once('_') {
+                Some((root_post_key, _)) => root_post_key,
+                None => continue,
+            };
+            if !self.post_tree.contains_key(root_post_key.as_bytes())? {
+                reply_batch.remove(reply_key.clone());
+                counts.removed_replies += 1;
+            }
+        }
+        self.reply_tree.apply_batch(reply_batch)?;
+
+        self.rebuild_post_date_index()?;
+
+        info!(
+            "Rebuilt indexes: checked {} posts, updated {}, removed {} replies",
+            counts.posts, counts.updated, counts.removed_replies
+        );
+
+        Ok(counts)
+    }
+
+    /// Replace the entries of the post date index with entries for all of
+    /// the posts in the post tree.
+    fn rebuild_post_date_index(&self) -> Result<()> {
+        info!("Rebuilding 'post_date_index' database tree");
+        self.post_date_index.clear()?;
+
+        let mut index_batch = Batch::default();
+        for post in self.post_tree.iter() {
+            let (post_key, post_bytes) = post?;
+            let post = Post::from_bincode(&post_bytes);
+            let author = author_from_post_key(&post_key);
+            index_batch.insert(
+                date_index_key(&author, post.timestamp, &post.key).as_bytes(),
+                post_key,
+            );
+        }
+
+        self.post_date_index.apply_batch(index_batch)
+    }
+
+    /// Replace the entries of the post date index for the posts authored by
+    /// the given public key with entries for their stored posts.
+    fn reindex_post_dates(&self, public_key: &str) -> Result<()> {
+        let prefix = format!("{}_", public_key);
+        let mut index_batch = Batch::default();
+        for entry in self.post_date_index.scan_prefix(prefix.as_bytes()) {
+            let (index_key, _) = entry?;
+            index_batch.remove(index_key);
+        }
+        for post in self.post_tree.scan_prefix(prefix.as_bytes()) {
+            let (post_key, post_bytes) = post?;
+            let post = Post::from_bincode(&post_bytes);
+            index_batch.insert(
+                date_index_key(public_key, post.timestamp, &post.key).as_bytes(),
+                post_key,
+            );
         }
+
+        self.post_date_index.apply_batch(index_batch)
     }
 
     /// Add a peer to the database by inserting the public key into the peer
@@ -151,12 +1014,46 @@ impl Database {
             .unwrap()
             .map(|peer| {
                 debug!("Deserializing peer data for {} from bincode", &public_key);
-                bincode::deserialize(&peer).unwrap()
+                Peer::from_bincode(&peer)
             });
 
         Ok(peer)
     }
 
+    /// Update the peer represented by the given public key with the given
+    /// function and return the updated peer, or `None` if the peer is not in
+    /// the peer tree.
+    ///
+    /// The update is applied atomically, so that concurrent updates of other
+    /// fields of the peer (e.g. by a fetch and a route) are not lost. The
+    /// function may be called more than once if the peer changes meanwhile.
+    pub fn update_peer<F>(&self, public_key: &str, mut update: F) -> Result<Option<Peer>>
+    where
+        F: FnMut(Peer) -> Peer,
+    {
+        debug!("Updating peer {} in 'peers' database tree", &public_key);
+
+        let updated_peer = self.peer_tree.update_and_fetch(public_key, |peer_bytes| {
+            peer_bytes.map(|bytes| bincode::serialize(&update(Peer::from_bincode(bytes))).unwrap())
+        })?;
+
+        Ok(updated_peer.map(|bytes| Peer::from_bincode(&bytes)))
+    }
+
+    /// Set the notes of the peer represented by the given public key. The
+    /// update is applied atomically. Returns `true` if the peer was found and
+    /// updated.
+    pub fn set_peer_notes(&self, public_key: &str, notes: &str) -> Result<bool> {
+        debug!("Setting notes of peer {}", &public_key);
+
+        let updated_peer = self.update_peer(public_key, |peer| Peer {
+            notes: notes.to_string(),
+            ..peer
+        })?;
+
+        Ok(updated_peer.is_some())
+    }
+
     /// Get a list of all peers in the peer tree. The byte value for each
     /// peer entry is deserialized from bincode into an instance of the Peer
     /// struct.
@@ -172,132 +1069,2805 @@ impl Database {
                     "Deserializing peer data for {} from bincode",
                     String::from_utf8_lossy(&peer.0).into_owned()
                 );
-                peers.push(bincode::deserialize(&peer.1).unwrap())
+                peers.push(Peer::from_bincode(&peer.1))
             });
 
         peers
     }
 
-    /// Remove a peer from the database, as represented by the given public
-    /// key.
-    pub fn remove_peer(&self, public_key: &str) -> Result<()> {
-        debug!("Removing peer {} from 'peers' database tree", &public_key);
-        self.peer_tree.remove(&public_key).map(|_| ())
-    }
-
-    /// Add a post to the database by inserting an instance of the Post struct
-    /// into the post tree.
-    pub fn add_post(&self, public_key: &str, post: Post) -> Result<Option<IVec>> {
-        let post_key = format!("{}_{}", public_key, post.key);
-        debug!("Serializing post data for {} to bincode", &post_key);
-        let post_bytes = bincode::serialize(&post).unwrap();
+    /// Export the peer represented by the given public key, along with all of
+    /// the posts authored by the peer, as a JSON value. Returns `None` if the
+    /// peer is not found in the peer tree.
+    pub fn export_peer(&self, public_key: &str) -> Result<Option<Value>> {
+        debug!("Exporting peer data and posts for {}", &public_key);
+        let peer = match self.get_peer(public_key)? {
+            Some(peer) => peer,
+            None => return Ok(None),
+        };
+        let posts = self.get_posts(public_key)?;
 
-        debug!("Inserting post {} into 'posts' database tree", &post_key);
-        self.post_tree.insert(post_key.as_bytes(), post_bytes)
+        Ok(Some(json!({ "peer": peer, "posts": posts })))
     }
 
-    /// Add a batch of posts to the database by inserting a vector of instances
-    /// of the Post struct into the post tree.
-    pub fn add_post_batch(&self, public_key: &str, posts: Vec<Post>) -> Result<()> {
-        let mut post_batch = Batch::default();
+    /// Import a peer and their posts, merging them with any existing data.
+    ///
+    /// The peer is only added if it is not yet in the peer tree. New posts are
+    /// added with their imported read state, while posts which already exist
+    /// keep their local read state and only have their content updated. Posts
+    /// which have been deleted are not imported.
+    ///
+    /// The peer, the posts and their date index entries are written in a
+    /// single transaction, so that a failed import leaves no partial data.
+    /// The index entry of an existing post whose timestamp changes is
+    /// replaced.
+    pub fn import_peer(&self, import: PeerImport) -> Result<ImportCounts> {
+        let public_key = import.peer.public_key.as_str();
+        debug!("Importing peer data and posts for {}", public_key);
+        let peer_bytes = bincode::serialize(&import.peer).unwrap();
 
-        for post in posts {
-            let post_key = format!("{}_{}", public_key, post.key);
-            debug!("Serializing post data for {} to bincode", &post_key);
-            let post_bytes = bincode::serialize(&post).unwrap();
+        let result: TransactionResult<ImportCounts, sled::Error> = (
+            &self.peer_tree,
+            &self.post_tree,
+            &self.deleted_tree,
+            &self.post_date_index,
+        )
+            .transaction(|(peer_tree, post_tree, deleted_tree, post_date_index)| {
+                if peer_tree.get(public_key.as_bytes())?.is_none() {
+                    peer_tree.insert(public_key.as_bytes(), peer_bytes.as_slice())?;
+                }
 
-            debug!("Inserting post {} into 'posts' database tree", &post_key);
-            post_batch.insert(post_key.as_bytes(), post_bytes)
-        }
+                let mut counts = ImportCounts::default();
+                for imported_post in &import.posts {
+                    let post_key = format!("{}_{}", public_key, imported_post.key);
+                    if deleted_tree.get(post_key.as_bytes())?.is_some() {
+                        debug!("Skipping deleted post {}", &post_key);
+                        counts.deleted += 1;
+                        continue;
+                    }
 
-        debug!("Applying batch insertion into 'posts' database tree");
-        self.post_tree.apply_batch(post_batch)
-    }
+                    let post = match post_tree.get(post_key.as_bytes())? {
+                        Some(post_bytes) => {
+                            counts.skipped += 1;
+                            let local_post = Post::from_bincode(&post_bytes);
+                            if local_post.timestamp != imported_post.timestamp {
+                                post_date_index.remove(
+                                    date_index_key(
+                                        public_key,
+                                        local_post.timestamp,
+                                        &local_post.key,
+                                    )
+                                    .as_bytes(),
+                                )?;
+                            }
+                            Post {
+                                channels: utils::extract_channels(&imported_post.text),
+                                text: imported_post.text.clone(),
+                                sequence: imported_post.sequence,
+                                timestamp: imported_post.timestamp,
+                                subject: imported_post.subject.clone(),
+                                quotes: imported_post.quotes.clone(),
+                                ..local_post
+                            }
+                        }
+                        None => {
+                            counts.added += 1;
+                            let mut post = Post::builder(imported_post.key.as_str())
+                                .author(public_key)
+                                .channels(utils::extract_channels(&imported_post.text))
+                                .text(imported_post.text.as_str())
+                                .sequence(imported_post.sequence)
+                                .timestamp(imported_post.timestamp)
+                                .subject(imported_post.subject.clone())
+                                .quotes(imported_post.quotes.clone())
+                                .build();
+                            post.read = imported_post.read;
+                            post.read_at = imported_post.read_at.filter(|_| imported_post.read);
+                            post.starred = imported_post.starred;
+                            post
+                        }
+                    };
 
-    /// Get a list of all posts in the post tree authored by the given public
-    /// key and sort them by timestamp in descending order. The byte value for
-    /// each matching entry is deserialized from bincode into an instance of
-    /// the Post struct.
-    pub fn get_posts(&self, public_key: &str) -> Result<Vec<Post>> {
-        debug!("Retrieving data for all posts in the 'posts' database tree");
-        let mut posts = Vec::new();
+                    post_date_index.insert(
+                        date_index_key(public_key, post.timestamp, &post.key).as_bytes(),
+                        post_key.as_bytes(),
+                    )?;
+                    post_tree.insert(post_key.as_bytes(), post.to_bincode())?;
+                }
 
-        self.post_tree
-            .scan_prefix(public_key.as_bytes())
-            .map(|post| post.unwrap())
-            .for_each(|post| {
-                debug!(
-                    "Deserializing post data for {} from bincode",
-                    String::from_utf8_lossy(&post.0).into_owned()
-                );
-                posts.push(bincode::deserialize(&post.1).unwrap())
+                Ok(counts)
             });
 
-        posts.sort_by(|a: &Post, b: &Post| b.timestamp.cmp(&a.timestamp));
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
 
-        Ok(posts)
+    /// Get a list of all peers in the peer tree which have not been archived.
+    pub fn get_active_peers(&self) -> Vec<Peer> {
+        self.get_peers()
+            .into_iter()
+            .filter(|peer| !peer.archived)
+            .collect()
     }
 
-    /// Get a single post from the post tree, authored by the given public key
-    /// and defined by the given message ID. The byte value for the matching
-    /// entry, if found, is deserialized from bincode into an instance of the
-    /// Post struct.
-    pub fn get_post(&self, public_key: &str, msg_id: &str) -> Result<Option<Post>> {
-        let post_key = format!("{}_{}", public_key, msg_id);
-        debug!(
-            "Retrieving post data for {} from 'posts' database tree",
-            &post_key
-        );
+    /// Get a list of all peers in the peer tree which have been archived.
+    pub fn get_archived_peers(&self) -> Vec<Peer> {
+        self.get_peers()
+            .into_iter()
+            .filter(|peer| peer.archived)
+            .collect()
+    }
 
-        let post = self
+    /// Get a list of the active (non-archived) peers, each paired with the
+    /// number of their unread posts, as shown in the peer list.
+    pub fn get_peers_with_unread(&self) -> Vec<(Peer, usize)> {
+        self.with_unread_counts(self.get_active_peers())
+    }
+
+    /// Pair each of the given peers with the number of their unread posts,
+    /// keeping the order of the peers.
+    pub fn with_unread_counts(&self, peers: Vec<Peer>) -> Vec<(Peer, usize)> {
+        peers
+            .into_iter()
+            .map(|peer| {
+                let unread_count = self.get_unread_post_count(&peer.public_key) as usize;
+                (peer, unread_count)
+            })
+            .collect()
+    }
+
+    /// Get a list of the peers with at least one unread post, sorted by the
+    /// timestamp of their most recent unread post in descending order.
+    pub fn peers_by_unread_recency(&self) -> Vec<Peer> {
+        debug!("Sorting peers by the timestamp of their latest unread post");
+        let mut peers: Vec<(i64, Peer)> = self
+            .get_peers()
+            .into_iter()
+            .filter_map(|peer| {
+                let latest_unread = self
+                    .get_post_summaries(&peer.public_key)
+                    .ok()?
+                    .into_iter()
+                    .filter(|post| !post.read && !post.filtered)
+                    .map(|post| post.timestamp)
+                    .max()?;
+                Some((latest_unread, peer))
+            })
+            .collect();
+
+        peers.sort_by(|a, b| b.0.cmp(&a.0));
+
+        peers.into_iter().map(|(_, peer)| peer).collect()
+    }
+
+    /// Get a list of all peers paired with the number of their stored posts,
+    /// sorted by the number of posts in descending order. Peers with the same
+    /// number of posts are sorted by name.
+    ///
+    /// Only the keys of the posts are counted; no post is deserialized.
+    pub fn peers_by_post_count(&self) -> Vec<(Peer, usize)> {
+        debug!("Sorting peers by the number of their stored posts");
+        let mut peers: Vec<(Peer, usize)> = self
+            .get_peers()
+            .into_iter()
+            .map(|peer| {
+                let post_count = self.get_post_count(&peer.public_key);
+                (peer, post_count)
+            })
+            .collect();
+
+        peers.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.name.cmp(&b.0.name)));
+
+        peers
+    }
+
+    /// Search the peer tree for peers whose name or public key contains the
+    /// given query. Matching is case-insensitive and independent of Unicode
+    /// composition. An empty query matches no peers.
+    pub fn search_peers(&self, query: &str) -> Vec<Peer> {
+        let query = utils::normalize_for_search(query.trim());
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        debug!("Searching 'peers' database tree for {}", &query);
+        self.get_peers()
+            .into_iter()
+            .filter(|peer| {
+                utils::normalize_for_search(&peer.name).contains(&query)
+                    || utils::normalize_for_search(&peer.public_key).contains(&query)
+            })
+            .collect()
+    }
+
+    /// Remove a peer from the database, as represented by the given public
+    /// key.
+    pub fn remove_peer(&self, public_key: &str) -> Result<()> {
+        debug!("Removing peer {} from 'peers' database tree", &public_key);
+        self.peer_tree.remove(&public_key).map(|_| ())
+    }
+
+    /// Remove all posts and replies authored by the given public key from the
+    /// post and reply trees. The posts are not marked as deleted, so they are
+    /// fetched again if the peer is subscribed to again. Returns the number
+    /// of posts and replies which were removed.
+    pub fn remove_peer_posts(&self, public_key: &str) -> Result<usize> {
+        debug!("Removing all posts of peer {}", &public_key);
+        let post_prefix = format!("{}_", public_key);
+        let mut removed = 0;
+
+        for tree in [&self.post_tree, &self.reply_tree] {
+            let mut post_batch = Batch::default();
+            for post in tree.scan_prefix(post_prefix.as_bytes()) {
+                let (post_key, _) = post?;
+                post_batch.remove(post_key);
+                removed += 1;
+            }
+            tree.apply_batch(post_batch)?;
+        }
+        self.reindex_post_dates(public_key)?;
+
+        Ok(removed)
+    }
+
+    /// Merge the duplicate peer into the canonical peer, as represented by
+    /// their public keys. The posts, replies and tombstones of deleted posts
+    /// of the duplicate are moved to the canonical peer, comments authored by
+    /// the duplicate are attributed to the canonical peer, the peer metadata
+    /// is merged and the duplicate is removed. Posts which are already stored
+    /// for the canonical peer keep their local state, and posts which were
+    /// deleted for either peer are not moved.
+    ///
+    /// The merge is applied in a single transaction. Since sled transactions
+    /// cannot scan a tree, the keys to be moved are collected beforehand and
+    /// their values are read again within the transaction. Returns the number
+    /// of posts which were moved.
+    pub fn merge_peers(&self, canonical: &str, duplicate: &str) -> Result<usize> {
+        if canonical == duplicate {
+            return Ok(0);
+        }
+        info!("Merging peer {} into {}", &duplicate, &canonical);
+
+        let duplicate_prefix = format!("{}_", duplicate);
+        let canonical_key = |key: &[u8]| {
+            let mut canonical_key = format!("{}_", canonical).into_bytes();
+            canonical_key.extend_from_slice(&key[duplicate_prefix.len()..]);
+            canonical_key
+        };
+        let keys_of = |tree: &Tree| -> Result<Vec<IVec>> {
+            tree.scan_prefix(duplicate_prefix.as_bytes())
+                .keys()
+                .collect()
+        };
+        let post_keys = keys_of(&self.post_tree)?;
+        let reply_keys = keys_of(&self.reply_tree)?;
+        let tombstone_keys = keys_of(&self.deleted_tree)?;
+        let mut comment_keys = Vec::new();
+        for comment in self.comment_tree.iter() {
+            let (comment_key, comment_bytes) = comment?;
+            let stored_comment: StoredPost<&str> = bincode::deserialize(&comment_bytes).unwrap();
+            if stored_comment.author == duplicate {
+                comment_keys.push(comment_key);
+            }
+        }
+
+        let result: TransactionResult<usize, sled::Error> = (
+            &self.peer_tree,
+            &self.post_tree,
+            &self.reply_tree,
+            &self.comment_tree,
+            &self.deleted_tree,
+        )
+            .transaction(
+                |(peer_tree, post_tree, reply_tree, comment_tree, deleted_tree)| {
+                    // Tombstones are moved first, so that posts deleted for
+                    // the duplicate are not moved below.
+                    for tombstone_key in &tombstone_keys {
+                        if deleted_tree.remove(tombstone_key)?.is_some() {
+                            deleted_tree.insert(canonical_key(tombstone_key), &[])?;
+                        }
+                    }
+
+                    let mut moved_posts = 0;
+                    for post_key in &post_keys {
+                        let post_bytes = match post_tree.remove(post_key)? {
+                            Some(post_bytes) => post_bytes,
+                            // The post was removed since the keys were collected.
+                            None => continue,
+                        };
+                        let canonical_post_key = canonical_key(post_key);
+                        if post_tree.get(&canonical_post_key)?.is_none()
+                            && deleted_tree.get(&canonical_post_key)?.is_none()
+                        {
+                            let mut post = Post::from_bincode(&post_bytes);
+                            post.author = canonical.to_string();
+                            post_tree.insert(canonical_post_key, post.to_bincode())?;
+                            moved_posts += 1;
+                        }
+                    }
+
+                    for reply_key in &reply_keys {
+                        if let Some(reply_bytes) = reply_tree.remove(reply_key)? {
+                            let mut reply = Post::from_bincode(&reply_bytes);
+                            reply.author = canonical.to_string();
+                            reply_tree.insert(canonical_key(reply_key), reply.to_bincode())?;
+                        }
+                    }
+
+                    // Comments are keyed by their root post alone, so only
+                    // their author changes.
+                    for comment_key in &comment_keys {
+                        if let Some(comment_bytes) = comment_tree.get(comment_key)? {
+                            let mut comment = Post::from_bincode(&comment_bytes);
+                            if comment.author == duplicate {
+                                comment.author = canonical.to_string();
+                                comment_tree.insert(comment_key, comment.to_bincode())?;
+                            }
+                        }
+                    }
+
+                    let canonical_peer = peer_tree
+                        .get(canonical)?
+                        .map(|peer| Peer::from_bincode(&peer));
+                    let duplicate_peer = peer_tree
+                        .get(duplicate)?
+                        .map(|peer| Peer::from_bincode(&peer));
+                    let merged_peer = match (canonical_peer, duplicate_peer) {
+                        (Some(canonical_peer), Some(duplicate_peer)) => {
+                            Some(canonical_peer.merge(duplicate_peer))
+                        }
+                        (Some(canonical_peer), None) => Some(canonical_peer),
+                        (None, Some(duplicate_peer)) => Some(Peer {
+                            public_key: canonical.to_string(),
+                            ..duplicate_peer
+                        }),
+                        (None, None) => None,
+                    };
+                    if let Some(peer) = merged_peer {
+                        peer_tree.insert(canonical, bincode::serialize(&peer).unwrap())?;
+                    }
+                    peer_tree.remove(duplicate)?;
+
+                    Ok(moved_posts)
+                },
+            );
+
+        let moved_posts = result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })?;
+
+        // The post date index is derived from the post tree, so it is updated
+        // once the merge has been applied.
+        self.reindex_post_dates(duplicate)?;
+        self.reindex_post_dates(canonical)?;
+
+        Ok(moved_posts)
+    }
+
+    /// Add a post to the database by inserting an instance of the Post struct
+    /// into the post tree. If the post is already stored, its local state is
+    /// preserved (see `preserve_post_state`). A post which has been deleted is
+    /// skipped, unless its tombstone is first removed (see `undelete_post`).
+    pub fn add_post(&self, public_key: &str, mut post: Post) -> Result<Option<IVec>> {
+        post.author = public_key.to_string();
+        let post_key = format!("{}_{}", public_key, post.key);
+        if self.deleted_tree.contains_key(post_key.as_bytes())? {
+            debug!("Skipping deleted post {}", &post_key);
+            return Ok(None);
+        }
+        self.preserve_post_state(&post_key, &mut post)?;
+        debug!("Serializing post data for {} to bincode", &post_key);
+        let post_bytes = post.to_bincode();
+
+        self.post_date_index.insert(
+            date_index_key(public_key, post.timestamp, &post.key).as_bytes(),
+            post_key.as_bytes(),
+        )?;
+        debug!("Inserting post {} into 'posts' database tree", &post_key);
+        self.post_tree.insert(post_key.as_bytes(), post_bytes)
+    }
+
+    /// Add a batch of posts to the database by inserting a vector of instances
+    /// of the Post struct into the post tree. Posts which have been deleted
+    /// are skipped, so that fetching them again does not restore them, and
+    /// posts which are already stored keep their local state (see
+    /// `preserve_post_state`).
+    pub fn add_post_batch(&self, public_key: &str, posts: Vec<Post>) -> Result<()> {
+        let mut post_batch = Batch::default();
+        let mut index_batch = Batch::default();
+
+        for mut post in posts {
+            post.author = public_key.to_string();
+            let post_key = format!("{}_{}", public_key, post.key);
+            if self.deleted_tree.contains_key(post_key.as_bytes())? {
+                debug!("Skipping deleted post {}", &post_key);
+                continue;
+            }
+            self.preserve_post_state(&post_key, &mut post)?;
+            debug!("Serializing post data for {} to bincode", &post_key);
+            let post_bytes = post.to_bincode();
+
+            index_batch.insert(
+                date_index_key(public_key, post.timestamp, &post.key).as_bytes(),
+                post_key.as_bytes(),
+            );
+            debug!("Inserting post {} into 'posts' database tree", &post_key);
+            post_batch.insert(post_key.as_bytes(), post_bytes)
+        }
+
+        self.post_date_index.apply_batch(index_batch)?;
+        debug!("Applying batch insertion into 'posts' database tree");
+        self.post_tree.apply_batch(post_batch)
+    }
+
+    /// Copy the state which is kept locally rather than fetched from the sbot
+    /// (the read state, like count and starred state) from the stored post
+    /// with the given key, if any, to the given post. This prevents a post
+    /// which is fetched again (e.g. by overlapping fetches) from being reset,
+    /// while its text and metadata are updated.
+    ///
+    /// To change the local state of a stored post, use the dedicated update
+    /// methods (e.g. `update_post_read`).
+    fn preserve_post_state(&self, post_key: &str, post: &mut Post) -> Result<()> {
+        if let Some(stored_bytes) = self.post_tree.get(post_key.as_bytes())? {
+            debug!("Preserving the state of stored post {}", post_key);
+            let stored_post = Post::from_bincode(&stored_bytes);
+            post.read = stored_post.read;
+            post.read_at = stored_post.read_at;
+            post.like_count = stored_post.like_count;
+            post.starred = stored_post.starred;
+        }
+
+        Ok(())
+    }
+
+    /// Add a batch of replies to the database by inserting instances of the
+    /// Post struct into the reply tree, each paired with the key of the root
+    /// post to which it replies.
+    pub fn add_reply_batch(&self, public_key: &str, replies: Vec<(String, Post)>) -> Result<()> {
+        let mut reply_batch = Batch::default();
+
+        for (root_key, mut reply) in replies {
+            reply.author = public_key.to_string();
+            let reply_key = format!("{}_{}_{}", public_key, root_key, reply.key);
+            debug!(
+                "Inserting reply {} into 'replies' database tree",
+                &reply_key
+            );
+            reply_batch.insert(reply_key.as_bytes(), reply.to_bincode())
+        }
+
+        self.reply_tree.apply_batch(reply_batch)
+    }
+
+    /// Add a batch of comments (replies by any peer) to the database by
+    /// inserting instances of the Post struct into the comment tree, each
+    /// paired with the key of the root post to which it replies. The root
+    /// post need not be stored.
+    pub fn add_comment_batch(&self, comments: &[(String, Post)]) -> Result<()> {
+        let mut comment_batch = Batch::default();
+
+        for (root_key, comment) in comments {
+            let comment_key = format!("{}_{}", root_key, comment.key);
+            debug!(
+                "Inserting comment {} into 'comments' database tree",
+                &comment_key
+            );
+            comment_batch.insert(comment_key.as_bytes(), comment.to_bincode())
+        }
+
+        self.comment_tree.apply_batch(comment_batch)
+    }
+
+    /// Add a batch of decrypted private messages to the database by inserting
+    /// instances of the Post struct into the private tree. Messages which are
+    /// already stored are left untouched, so that their read state is kept.
+    /// Returns the number of messages which were inserted.
+    pub fn add_private_messages(&self, messages: Vec<Post>) -> Result<usize> {
+        let mut private_batch = Batch::default();
+        let mut inserted = 0;
+
+        for message in messages {
+            let message_key = format!("{}_{}", message.author, message.key);
+            if self.private_tree.contains_key(message_key.as_bytes())? {
+                continue;
+            }
+            debug!(
+                "Inserting private message {} into 'private' database tree",
+                &message_key
+            );
+            private_batch.insert(message_key.as_bytes(), message.to_bincode());
+            inserted += 1;
+        }
+
+        self.private_tree.apply_batch(private_batch)?;
+
+        Ok(inserted)
+    }
+
+    /// Get the sequence number of the latest message of the feed of the given
+    /// public key which has been searched for private messages, or zero if
+    /// the feed has never been searched.
+    pub fn get_private_sequence(&self, public_key: &str) -> Result<u64> {
+        let sequence = self
+            .private_sequence_tree
+            .get(public_key.as_bytes())?
+            .and_then(|sequence| sequence.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+
+        Ok(sequence)
+    }
+
+    /// Record the sequence number of the latest message of the feed of the
+    /// given public key which has been searched for private messages.
+    pub fn set_private_sequence(&self, public_key: &str, sequence: u64) -> Result<()> {
+        debug!(
+            "Setting latest private message sequence number for feed {} to {}",
+            public_key, sequence
+        );
+        self.private_sequence_tree
+            .insert(public_key.as_bytes(), &sequence.to_be_bytes())?;
+
+        Ok(())
+    }
+
+    /// Get all decrypted private messages, most recent first.
+    pub fn get_private_messages(&self) -> Result<Vec<Post>> {
+        debug!("Retrieving private messages");
+
+        let mut messages = Vec::new();
+        for message in self.private_tree.iter() {
+            let (_, message_bytes) = message?;
+            messages.push(Post::from_bincode(&message_bytes));
+        }
+        messages.sort_by(|a: &Post, b: &Post| b.timestamp.cmp(&a.timestamp));
+
+        Ok(messages)
+    }
+
+    /// Get the comments (replies by any peer) to the root post with the given
+    /// key, oldest first.
+    pub fn get_comments(&self, root_key: &str) -> Result<Vec<Post>> {
+        debug!("Retrieving comments to post {}", &root_key);
+        let comment_prefix = format!("{}_", root_key);
+
+        let mut comments = Vec::new();
+        for comment in self.comment_tree.scan_prefix(comment_prefix.as_bytes()) {
+            let (_, comment_bytes) = comment?;
+            comments.push(Post::from_bincode(&comment_bytes));
+        }
+        comments.sort_by(|a: &Post, b: &Post| a.timestamp.cmp(&b.timestamp));
+
+        Ok(comments)
+    }
+
+    /// Get the root posts authored by the given public key, most recent
+    /// first, each paired with the replies of the author to the post, oldest
+    /// first.
+    pub fn get_threads(&self, public_key: &str) -> Result<Vec<(Post, Vec<Post>)>> {
+        debug!("Retrieving threads for peer {}", &public_key);
+        let mut threads = Vec::new();
+
+        for root in self.get_posts(public_key)? {
+            let reply_prefix = format!("{}_{}_", public_key, root.key);
+            let mut replies = Vec::new();
+            for reply in self.reply_tree.scan_prefix(reply_prefix.as_bytes()) {
+                let (_, reply_bytes) = reply?;
+                replies.push(Post::from_bincode(&reply_bytes));
+            }
+            replies.sort_by(|a: &Post, b: &Post| a.timestamp.cmp(&b.timestamp));
+
+            threads.push((root, replies));
+        }
+
+        Ok(threads)
+    }
+
+    /// Get a list of all posts in the post tree authored by the given public
+    /// key and sort them by timestamp in descending order. The byte value for
+    /// each matching entry is deserialized from bincode into an instance of
+    /// the Post struct.
+    pub fn get_posts(&self, public_key: &str) -> Result<Vec<Post>> {
+        self.get_posts_sorted(public_key, SortOrder::Descending)
+    }
+
+    /// Iterate over the posts in the post tree authored by the given public
+    /// key, in the order of their keys (not by timestamp). Each post is only
+    /// read and deserialized when the iterator is advanced, so the history of
+    /// the peer is never held in memory at once.
+    ///
+    /// The iterator owns its own handle to the tree (sled iterators are not
+    /// tied to the lifetime of the tree), so it does not borrow the database.
+    /// It does not read from a snapshot: posts written while iterating may or
+    /// may not be yielded.
+    pub fn posts_iter_for_peer(&self, public_key: &str) -> impl Iterator<Item = Result<Post>> {
+        let post_prefix = format!("{}_", public_key);
+
+        self.post_tree
+            .scan_prefix(post_prefix.as_bytes())
+            .map(|post| {
+                let (post_key, post_bytes) = post?;
+                debug!(
+                    "Deserializing post data for {} from bincode",
+                    String::from_utf8_lossy(&post_key)
+                );
+                Ok(Post::from_bincode(&post_bytes))
+            })
+    }
+
+    /// Get a page of the posts in the post tree authored by the given public
+    /// key, sorted by sequence number in the given order: up to `limit`
+    /// posts, starting after the first `offset` posts. The sequence numbers
+    /// of a feed are chronological, unlike the keys under which the posts are
+    /// stored and the timestamps claimed by their authors.
+    ///
+    /// Only the sequence number of each post is read to sort them; the posts
+    /// of the requested page are the only ones which are fully deserialized.
+    pub fn get_posts_paged(
+        &self,
+        public_key: &str,
+        order: SortOrder,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<Post>> {
+        debug!(
+            "Retrieving {} posts of {} from offset {}",
+            limit, &public_key, offset
+        );
+        let post_prefix = format!("{}_", public_key);
+
+        let mut sequences = Vec::new();
+        for post in self.post_tree.scan_prefix(post_prefix.as_bytes()) {
+            let (post_key, post_bytes) = post?;
+            let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+            sequences.push((stored_post.sequence, post_key));
+        }
+        match order {
+            SortOrder::Ascending => sequences.sort_by(|a, b| a.0.cmp(&b.0)),
+            SortOrder::Descending => sequences.sort_by(|a, b| b.0.cmp(&a.0)),
+        }
+
+        let mut posts = Vec::new();
+        for (_, post_key) in sequences.into_iter().skip(offset).take(limit) {
+            // The post may have been removed since the keys were read.
+            if let Some(post_bytes) = self.post_tree.get(&post_key)? {
+                posts.push(Post::from_bincode(&post_bytes));
+            }
+        }
+
+        Ok(posts)
+    }
+
+    /// Get a list of all posts in the post tree authored by the given public
+    /// key and sort them by timestamp in the given order.
+    pub fn get_posts_sorted(&self, public_key: &str, order: SortOrder) -> Result<Vec<Post>> {
+        debug!("Retrieving data for all posts in the 'posts' database tree");
+        let mut posts = self
+            .posts_iter_for_peer(public_key)
+            .collect::<Result<Vec<Post>>>()?;
+
+        match order {
+            SortOrder::Ascending => {
+                posts.sort_by(|a: &Post, b: &Post| a.timestamp.cmp(&b.timestamp))
+            }
+            SortOrder::Descending => {
+                posts.sort_by(|a: &Post, b: &Post| b.timestamp.cmp(&a.timestamp))
+            }
+        }
+
+        Ok(posts)
+    }
+
+    /// Get a summary of each post in the post tree authored by the given
+    /// public key and sort them by timestamp in descending order. The text of
+    /// posts with a subject is not loaded.
+    pub fn get_post_summaries(&self, public_key: &str) -> Result<Vec<PostSummary>> {
+        debug!("Retrieving post summaries for {}", &public_key);
+        let mut summaries = Vec::new();
+
+        for post in self.post_tree.scan_prefix(public_key.as_bytes()) {
+            let (_, post_info) = post?;
+            let stored_post: StoredPost<&str> = bincode::deserialize(&post_info).unwrap();
+            summaries.push(PostSummary::from(stored_post));
+        }
+
+        summaries.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(summaries)
+    }
+
+    /// Search the post tree for posts whose text contains the given query and
+    /// sort them by timestamp in descending order. Matching is
+    /// case-insensitive and independent of Unicode composition. An empty query
+    /// matches no posts.
+    pub fn search_posts(&self, query: &str) -> Vec<Post> {
+        let query = utils::normalize_for_search(query.trim());
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        debug!("Searching 'posts' database tree for {}", &query);
+        let mut posts = Vec::new();
+
+        self.post_tree
+            .iter()
+            .map(|post| post.unwrap())
+            .for_each(|post| {
+                let deserialized_post = Post::from_bincode(&post.1);
+                if utils::normalize_for_search(&deserialized_post.text).contains(&query) {
+                    posts.push(deserialized_post)
+                }
+            });
+
+        posts.sort_by(|a: &Post, b: &Post| b.timestamp.cmp(&a.timestamp));
+
+        posts
+    }
+
+    /// Get the posts of all peers which mention the given channel, sorted by
+    /// timestamp in descending order. The channel name is matched without
+    /// regard to case or a leading `#`. Filtered posts are not included.
+    ///
+    /// Only the posts which are returned are fully deserialized.
+    pub fn get_posts_by_channel(&self, channel: &str) -> Vec<Post> {
+        let channel = channel.trim().trim_start_matches('#').to_lowercase();
+        debug!("Retrieving posts in channel {}", &channel);
+
+        let mut posts: Vec<Post> = self
+            .post_tree
+            .iter()
+            .values()
+            .map(|post| post.unwrap())
+            .filter(|post_bytes| {
+                let stored_post: StoredPost<&str> = bincode::deserialize(post_bytes).unwrap();
+                !stored_post.filtered && stored_post.channels.contains(&channel.as_str())
+            })
+            .map(|post_bytes| Post::from_bincode(&post_bytes))
+            .collect();
+
+        posts.sort_by(|a: &Post, b: &Post| b.timestamp.cmp(&a.timestamp));
+
+        posts
+    }
+
+    /// Get the `limit` most recently read posts of all peers, sorted by the
+    /// time at which they were marked as read in descending order. Posts
+    /// without a recorded time (e.g. those read before it was recorded, or
+    /// filtered posts, which are read when fetched) are not included.
+    ///
+    /// Only the posts which are returned are fully deserialized.
+    pub fn get_recently_read(&self, limit: usize) -> Vec<Post> {
+        debug!("Retrieving {} most recently read posts", limit);
+        let mut read_posts: Vec<(i64, IVec)> = self
+            .post_tree
+            .iter()
+            .values()
+            .map(|post| post.unwrap())
+            .filter_map(|post_bytes| {
+                let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+                match stored_post.read {
+                    true => stored_post
+                        .read_at
+                        .map(|read_at| (read_at, post_bytes.clone())),
+                    false => None,
+                }
+            })
+            .collect();
+
+        read_posts.sort_by(|a, b| b.0.cmp(&a.0));
+        read_posts.truncate(limit);
+
+        read_posts
+            .into_iter()
+            .map(|(_, post_bytes)| Post::from_bincode(&post_bytes))
+            .collect()
+    }
+
+    /// Get a single post from the post tree, authored by the given public key
+    /// and defined by the given message ID. The byte value for the matching
+    /// entry, if found, is deserialized from bincode into an instance of the
+    /// Post struct.
+    pub fn get_post(&self, public_key: &str, msg_id: &str) -> Result<Option<Post>> {
+        let post_key = format!("{}_{}", public_key, msg_id);
+        debug!(
+            "Retrieving post data for {} from 'posts' database tree",
+            &post_key
+        );
+
+        let post = self
             .post_tree
             .get(post_key.as_bytes())
             .unwrap()
             .map(|post| {
                 debug!("Deserializing post data for {} from bincode", &post_key);
-                bincode::deserialize(&post).unwrap()
+                Post::from_bincode(&post)
             });
 
         Ok(post)
     }
 
-    /// Remove a single post from the post tree, authored by the given public
-    /// key and defined by the given message ID.
-    pub fn remove_post(&self, public_key: &str, msg_id: &str) -> Result<()> {
+    /// Get a single post or reply defined by the given message ID (also known
+    /// as a message reference), regardless of its author.
+    ///
+    /// Posts are stored under the public key of their author, so the keys of
+    /// the post, reply and comment trees are scanned for the message ID. No
+    /// post is deserialized until a match is found.
+    pub fn get_post_by_ref(&self, msg_id: &str) -> Result<Option<Post>> {
+        debug!(
+            "Searching for post {} in the post, reply and comment trees",
+            msg_id
+        );
+        let key_suffix = format!("_{}", msg_id);
+
+        for tree in [&self.post_tree, &self.reply_tree, &self.comment_tree] {
+            for post in tree.iter() {
+                let (post_key, post_bytes) = post?;
+                if post_key.ends_with(key_suffix.as_bytes()) {
+                    return Ok(Some(Post::from_bincode(&post_bytes)));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Set the read state of a single post in the post tree, authored by the
+    /// given public key and defined by the given message ID. The update is
+    /// applied atomically. Returns `true` if the post was found and updated.
+    pub fn update_post_read(&self, public_key: &str, msg_id: &str, read: bool) -> Result<bool> {
         let post_key = format!("{}_{}", public_key, msg_id);
-        debug!("Removing post {} from 'posts' database tree", &post_key);
+        debug!("Setting read state of post {} to {}", &post_key, read);
+
+        let updated_post = self
+            .post_tree
+            .update_and_fetch(post_key.as_bytes(), |post_bytes| {
+                post_bytes.map(|bytes| {
+                    let mut post = Post::from_bincode(bytes);
+                    post.set_read(read);
+                    post.to_bincode()
+                })
+            })?;
 
-        // .remove() would ordinarily return the value of the deleted entry
-        // as an Option, returning None if the post_key was not found.
-        // We don't care about the value of the deleted entry so we simply
-        // map the Option to ().
-        self.post_tree.remove(post_key.as_bytes()).map(|_| ())
+        Ok(updated_post.is_some())
     }
 
-    /// Sum the total number of unread posts for the peer represented by the
-    /// given public key.
-    pub fn get_unread_post_count(&self, public_key: &str) -> u16 {
+    /// Mark all posts authored by the given public key as read. All changes
+    /// are applied in a single batch. Returns the number of posts which were
+    /// previously unread.
+    pub fn mark_all_read(&self, public_key: &str) -> Result<usize> {
+        debug!("Marking all posts of peer {} as read", &public_key);
+
+        let post_prefix = format!("{}_", public_key);
+        let mut updated = 0;
+        let mut post_batch = Batch::default();
+
+        for post in self.post_tree.scan_prefix(post_prefix.as_bytes()) {
+            let (post_key, post_bytes) = post?;
+            let mut post = Post::from_bincode(&post_bytes);
+            if !post.read {
+                post.set_read(true);
+                post_batch.insert(post_key, post.to_bincode());
+                updated += 1;
+            }
+        }
+
+        debug!("Applying batch update to 'posts' database tree");
+        self.post_tree.apply_batch(post_batch)?;
+
+        Ok(updated)
+    }
+
+    /// Apply the given action to the posts authored by the given public key
+    /// and defined by the given message IDs. All changes, including the
+    /// tombstones of deleted posts, are applied in a single transaction, so
+    /// that either every post is updated or none are. Returns the number of
+    /// posts which were found and updated.
+    pub fn bulk_update_posts(
+        &self,
+        public_key: &str,
+        msg_ids: &[String],
+        action: BulkAction,
+    ) -> Result<usize> {
         debug!(
-            "Counting total number of unread posts for peer {}",
+            "Applying {:?} to {} posts of peer {}",
+            action,
+            msg_ids.len(),
             &public_key
         );
 
-        let mut unread_post_counter = 0;
+        let result: TransactionResult<usize, sled::Error> = (&self.post_tree, &self.deleted_tree)
+            .transaction(|(post_tree, deleted_tree)| {
+                let mut updated = 0;
+                for msg_id in msg_ids {
+                    let post_key = format!("{}_{}", public_key, msg_id);
+                    let post_bytes = match post_tree.get(post_key.as_bytes())? {
+                        Some(post_bytes) => post_bytes,
+                        None => continue,
+                    };
 
-        self.post_tree
-            .scan_prefix(public_key.as_bytes())
-            .map(|post| post.unwrap())
-            .for_each(|post| {
-                debug!(
-                    "Deserializing post data for {} from bincode",
-                    String::from_utf8_lossy(&post.0).into_owned()
-                );
-                let deserialized_post: Post = bincode::deserialize(&post.1).unwrap();
-                if !deserialized_post.read {
-                    unread_post_counter += 1
+                    if action == BulkAction::Delete {
+                        post_tree.remove(post_key.as_bytes())?;
+                        deleted_tree.insert(post_key.as_bytes(), &[])?;
+                    } else {
+                        let mut post = Post::from_bincode(&post_bytes);
+                        match action {
+                            BulkAction::Read => post.set_read(true),
+                            BulkAction::Unread => post.set_read(false),
+                            BulkAction::Star => post.starred = true,
+                            BulkAction::Unstar => post.starred = false,
+                            BulkAction::Delete => unreachable!(),
+                        }
+                        post_tree.insert(post_key.as_bytes(), post.to_bincode())?;
+                    }
+                    updated += 1;
                 }
+
+                Ok(updated)
             });
 
-        unread_post_counter
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
+
+    /// Remove all but the `keep_latest` most recent posts authored by the
+    /// given public key. Older posts which are unread or starred are kept.
+    ///
+    /// Removed posts are marked as deleted, so that they are not inserted
+    /// again if the feed of the peer is fetched from the start. The latest
+    /// sequence number of the peer is left unchanged. Returns the number of
+    /// posts which were removed.
+    pub fn prune_peer_posts(&self, public_key: &str, keep_latest: usize) -> Result<usize> {
+        debug!(
+            "Pruning posts of peer {}, keeping the latest {}",
+            &public_key, keep_latest
+        );
+
+        // Post summaries are sorted with the most recent post first.
+        let post_keys: Vec<String> = self
+            .get_post_summaries(public_key)?
+            .into_iter()
+            .skip(keep_latest)
+            .filter(|post| post.read && !post.starred)
+            .map(|post| format!("{}_{}", public_key, post.key))
+            .collect();
+
+        self.remove_posts_with_tombstones(&post_keys)?;
+
+        Ok(post_keys.len())
+    }
+
+    /// Remove the posts with the given keys from the post tree and record a
+    /// tombstone for each of them, in a single transaction, so that no post
+    /// is removed without its tombstone.
+    fn remove_posts_with_tombstones<K: AsRef<[u8]>>(&self, post_keys: &[K]) -> Result<()> {
+        let result: TransactionResult<(), sled::Error> = (&self.post_tree, &self.deleted_tree)
+            .transaction(|(post_tree, deleted_tree)| {
+                for post_key in post_keys {
+                    post_tree.remove(post_key.as_ref())?;
+                    deleted_tree.insert(post_key.as_ref(), &[])?;
+                }
+                Ok(())
+            });
+
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
+
+    /// Remove all read posts authored by the given public key, regardless of
+    /// their age. Unread and starred posts are kept.
+    ///
+    /// Removed posts are marked as deleted, so that they are not inserted
+    /// again if the feed of the peer is fetched from the start. Returns the
+    /// number of posts which were removed.
+    pub fn delete_read_posts(&self, public_key: &str) -> Result<usize> {
+        debug!("Deleting read posts of peer {}", &public_key);
+
+        let post_prefix = format!("{}_", public_key);
+        let mut post_keys = Vec::new();
+
+        for post in self.post_tree.scan_prefix(post_prefix.as_bytes()) {
+            let (post_key, post_bytes) = post?;
+            // Only the flags are needed, so the text is borrowed.
+            let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+            if stored_post.read && !stored_post.starred {
+                post_keys.push(post_key);
+            }
+        }
+
+        debug!("Removing read posts from 'posts' database tree");
+        self.remove_posts_with_tombstones(&post_keys)?;
+
+        Ok(post_keys.len())
+    }
+
+    /// Get the author, key and like count of every stored post, without
+    /// copying the text of the posts.
+    pub fn get_like_counts(&self) -> Result<Vec<(String, String, u32)>> {
+        debug!("Retrieving the like counts of all posts");
+        let mut like_counts = Vec::new();
+
+        for post in self.post_tree.iter().values() {
+            let post_bytes = post?;
+            let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+            like_counts.push((
+                stored_post.author.to_string(),
+                stored_post.key.to_string(),
+                stored_post.like_count,
+            ));
+        }
+
+        Ok(like_counts)
+    }
+
+    /// Get the sequence number of the latest message of the feed of the given
+    /// public key which has been searched for votes, or zero if the feed has
+    /// never been searched.
+    pub fn get_vote_sequence(&self, public_key: &str) -> Result<u64> {
+        let sequence = self
+            .vote_sequence_tree
+            .get(public_key.as_bytes())?
+            .and_then(|sequence| sequence.as_ref().try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0);
+
+        Ok(sequence)
+    }
+
+    /// Apply the given votes, published by the given public key in the given
+    /// order, to the like tree and record the sequence number of the latest
+    /// message of the feed which has been searched for votes. Both are
+    /// written in a single transaction, so that votes are neither skipped nor
+    /// applied twice.
+    pub fn add_votes(
+        &self,
+        public_key: &str,
+        votes: &[(String, bool)],
+        latest_sequence: u64,
+    ) -> Result<()> {
+        debug!(
+            "Adding {} votes of {} into 'likes' database tree",
+            votes.len(),
+            public_key
+        );
+        let result: TransactionResult<(), sled::Error> = (
+            &self.like_tree,
+            &self.vote_sequence_tree,
+        )
+            .transaction(|(like_tree, vote_sequence_tree)| {
+                for (msg_id, liked) in votes {
+                    let like_key = like_key(msg_id, public_key);
+                    if *liked {
+                        like_tree.insert(like_key.as_bytes(), &[])?;
+                    } else {
+                        like_tree.remove(like_key.as_bytes())?;
+                    }
+                }
+                vote_sequence_tree.insert(public_key.as_bytes(), &latest_sequence.to_be_bytes())?;
+                Ok(())
+            });
+
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
+
+    /// Record a like of the message with the given ID by the given public
+    /// key, as soon as it is published and before its vote is tallied.
+    pub fn add_like(&self, msg_id: &str, public_key: &str) -> Result<()> {
+        debug!(
+            "Adding like of {} by {} into 'likes' database tree",
+            msg_id, public_key
+        );
+        self.like_tree
+            .insert(like_key(msg_id, public_key).as_bytes(), &[])?;
+
+        Ok(())
+    }
+
+    /// Return `true` if the message with the given ID is liked by the given
+    /// public key.
+    pub fn is_liked_by(&self, msg_id: &str, public_key: &str) -> Result<bool> {
+        self.like_tree
+            .contains_key(like_key(msg_id, public_key).as_bytes())
+    }
+
+    /// Get the number of peers (ourselves included) who like the message
+    /// with the given ID.
+    pub fn get_like_count(&self, msg_id: &str) -> usize {
+        // The public key of each liker starts with `@`, which keeps the likes
+        // of a message apart from those of any message whose ID starts with
+        // the given one.
+        self.like_tree
+            .scan_prefix(format!("{}@", msg_id).as_bytes())
+            .keys()
+            .count()
+    }
+
+    /// Set the like count of a single post in the post tree, authored by the
+    /// given public key and defined by the given message ID. The update is
+    /// applied atomically. Returns `true` if the post was found and updated.
+    pub fn update_post_like_count(
+        &self,
+        public_key: &str,
+        msg_id: &str,
+        like_count: u32,
+    ) -> Result<bool> {
+        let post_key = format!("{}_{}", public_key, msg_id);
+        debug!("Setting like count of post {} to {}", &post_key, like_count);
+
+        let updated_post = self
+            .post_tree
+            .update_and_fetch(post_key.as_bytes(), |post_bytes| {
+                post_bytes.map(|bytes| {
+                    let mut post = Post::from_bincode(bytes);
+                    post.like_count = like_count;
+                    post.to_bincode()
+                })
+            })?;
+
+        Ok(updated_post.is_some())
+    }
+
+    /// Get the first unread post authored by the given public key, following
+    /// the order in which posts are listed (most recent first). Filtered
+    /// posts are skipped.
+    pub fn first_unread_post(&self, public_key: &str) -> Result<Option<Post>> {
+        debug!("Retrieving first unread post for peer {}", &public_key);
+        let post = self
+            .get_posts(public_key)?
+            .into_iter()
+            .find(|post| !post.read && !post.filtered);
+
+        Ok(post)
+    }
+
+    /// Get the posts authored by the given public key which were published
+    /// between the given timestamps (inclusive), sorted by date in ascending
+    /// order.
+    ///
+    /// The posts are looked up in the post date index, so only the posts in
+    /// the range are read.
+    #[allow(dead_code)]
+    pub fn get_posts_by_date_range(
+        &self,
+        public_key: &str,
+        from_ts: i64,
+        to_ts: i64,
+    ) -> Result<Vec<Post>> {
+        debug!(
+            "Retrieving posts of peer {} published between {} and {}",
+            &public_key, from_ts, to_ts
+        );
+        let mut posts = Vec::new();
+        if from_ts > to_ts {
+            return Ok(posts);
+        }
+
+        // The index keys of a peer and timestamp are followed by `_`, which
+        // sorts before `~`.
+        let start = format!("{}_{:020}_", public_key, from_ts.max(0));
+        let end = format!("{}_{:020}~", public_key, to_ts.max(0));
+        for entry in self.post_date_index.range(start.as_bytes()..end.as_bytes()) {
+            let (index_key, post_key) = entry?;
+            if let Some(post_bytes) = self.post_tree.get(post_key)? {
+                let post = Post::from_bincode(&post_bytes);
+                // Skip the stale entries of posts which have been redated.
+                let current_key = date_index_key(public_key, post.timestamp, &post.key);
+                if (from_ts..=to_ts).contains(&post.timestamp)
+                    && index_key == current_key.as_bytes()
+                {
+                    posts.push(post);
+                }
+            }
+        }
+
+        Ok(posts)
+    }
+
+    /// Get the timestamp of the most recent post authored by the given public
+    /// key, if any.
+    ///
+    /// The post date index is read from its end, so only the latest post is
+    /// read, along with the stale entries of posts which have since been
+    /// removed or redated.
+    pub fn get_latest_post_timestamp(&self, public_key: &str) -> Result<Option<i64>> {
+        debug!(
+            "Retrieving the timestamp of the latest post of {}",
+            &public_key
+        );
+        let prefix = format!("{}_", public_key);
+
+        for entry in self.post_date_index.scan_prefix(prefix.as_bytes()).rev() {
+            let (index_key, post_key) = entry?;
+            if let Some(post_bytes) = self.post_tree.get(post_key)? {
+                let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+                let current_key =
+                    date_index_key(public_key, stored_post.timestamp, stored_post.key);
+                if index_key == current_key.as_bytes() {
+                    return Ok(Some(stored_post.timestamp));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Remove a single post from the post tree, authored by the given public
+    /// key and defined by the given message ID. A tombstone is recorded for
+    /// the post, so that it is not inserted again by a later fetch.
+    pub fn remove_post(&self, public_key: &str, msg_id: &str) -> Result<()> {
+        let post_key = format!("{}_{}", public_key, msg_id);
+        debug!("Removing post {} from 'posts' database tree", &post_key);
+
+        let result: TransactionResult<(), sled::Error> = (&self.post_tree, &self.deleted_tree)
+            .transaction(|(post_tree, deleted_tree)| {
+                // We don't care about the value of the deleted entry, which is
+                // None if the post_key was not found.
+                post_tree.remove(post_key.as_bytes())?;
+                deleted_tree.insert(post_key.as_bytes(), &[])?;
+                Ok(())
+            });
+
+        result.map_err(|e| match e {
+            TransactionError::Abort(e) => e,
+            TransactionError::Storage(e) => e,
+        })
+    }
+
+    /// Remove the tombstone of a deleted post, authored by the given public
+    /// key and defined by the given message ID, allowing it to be inserted
+    /// again. Returns `true` if the post had been deleted.
+    pub fn undelete_post(&self, public_key: &str, msg_id: &str) -> Result<bool> {
+        let post_key = format!("{}_{}", public_key, msg_id);
+        debug!("Removing tombstone of post {}", &post_key);
+
+        self.deleted_tree
+            .remove(post_key.as_bytes())
+            .map(|tombstone| tombstone.is_some())
+    }
+
+    /// Sum the total number of unread posts for the peer represented by the
+    /// given public key. Filtered posts are not counted, even if they have
+    /// been marked as unread.
+    ///
+    /// This is called for every peer whenever the peer list is rendered, so
+    /// posts are not fully deserialized: the stored text is borrowed rather
+    /// than copied and no date is formatted. The cost is still linear in the
+    /// number of posts of the peer.
+    pub fn get_unread_post_count(&self, public_key: &str) -> u16 {
+        debug!(
+            "Counting total number of unread posts for peer {}",
+            &public_key
+        );
+
+        let mut unread_post_counter = 0;
+
+        for post in self.post_tree.scan_prefix(public_key.as_bytes()) {
+            let (_, post_bytes) = post.unwrap();
+            if is_unread(&post_bytes) {
+                unread_post_counter += 1
+            }
+        }
+
+        unread_post_counter
+    }
+
+    /// Count the stored posts of the peer represented by the given public key.
+    ///
+    /// Only the keys of the posts are counted; no post is deserialized. The
+    /// prefix includes the separator so that the posts of a peer whose key
+    /// begins with the given key are not counted.
+    pub fn get_post_count(&self, public_key: &str) -> usize {
+        let post_prefix = format!("{}_", public_key);
+        self.post_tree
+            .scan_prefix(post_prefix.as_bytes())
+            .keys()
+            .count()
+    }
+
+    /// Count the posts of all peers. Replies are stored in a separate tree,
+    /// so only root posts are counted.
+    ///
+    /// sled counts the entries of the post tree by scanning its keys, so the
+    /// cost is linear in the number of posts, but no post is deserialized.
+    pub fn get_all_posts_count(&self) -> usize {
+        self.post_tree.len()
+    }
+
+    /// Sum the total number of unread posts for all peers, excluding filtered
+    /// posts.
+    pub fn get_total_unread_count(&self) -> u64 {
+        debug!("Counting total number of unread posts for all peers");
+        let mut unread_post_counter = 0;
+
+        for post in self.post_tree.iter() {
+            let (_, post_bytes) = post.unwrap();
+            if is_unread(&post_bytes) {
+                unread_post_counter += 1
+            }
+        }
+
+        unread_post_counter
+    }
+
+    /// Record a sample of the total number of unread posts, taken at the
+    /// given timestamp, in the unread history tree. The oldest samples are
+    /// removed once the tree holds more than the retained number of samples.
+    pub fn add_unread_sample(&self, timestamp: i64, unread: u64) -> Result<()> {
+        debug!(
+            "Inserting unread count sample {} at {} into 'unread_history' database tree",
+            unread, timestamp
+        );
+        // Big-endian keys are ordered chronologically by sled.
+        self.unread_history_tree
+            .insert(timestamp.to_be_bytes(), &unread.to_be_bytes())?;
+
+        while self.unread_history_tree.len() > UNREAD_HISTORY_LIMIT {
+            self.unread_history_tree.pop_min()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the samples of the total number of unread posts as a list of
+    /// (timestamp, unread count) pairs, ordered from oldest to newest.
+    pub fn get_unread_history(&self) -> Result<Vec<(i64, u64)>> {
+        debug!("Retrieving samples from 'unread_history' database tree");
+        let mut samples = Vec::new();
+
+        for sample in self.unread_history_tree.iter() {
+            let (timestamp, unread) = sample?;
+            let timestamp = i64::from_be_bytes(timestamp.as_ref().try_into().unwrap());
+            let unread = u64::from_be_bytes(unread.as_ref().try_into().unwrap());
+            samples.push((timestamp, unread))
+        }
+
+        Ok(samples)
+    }
+
+    /// Count the channels (hashtags) mentioned in all posts published at or
+    /// after the given timestamp. The channels are returned with their
+    /// respective counts, sorted by count in descending order.
+    pub fn trending_channels(&self, since_timestamp: i64) -> Vec<(String, usize)> {
+        debug!(
+            "Counting channels mentioned in posts published since {}",
+            since_timestamp
+        );
+        let mut channel_counts: HashMap<String, usize> = HashMap::new();
+
+        self.post_tree
+            .iter()
+            .values()
+            .map(|post| post.unwrap())
+            .for_each(|post_bytes| {
+                let stored_post: StoredPost<&str> = bincode::deserialize(&post_bytes).unwrap();
+                if !stored_post.filtered && stored_post.timestamp >= since_timestamp {
+                    for channel in stored_post.channels {
+                        *channel_counts.entry(channel.to_string()).or_insert(0) += 1
+                    }
+                }
+            });
+
+        let mut channels: Vec<(String, usize)> = channel_counts.into_iter().collect();
+        // Sort by count (descending) and then alphabetically for a stable
+        // ordering of channels with equal counts.
+        channels.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        channels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER_A: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+    const PEER_B: &str = "@qK93G/R9R5J2fiqK+kxV72HqqPUcss+rth8rACcYr4s=.ed25519";
+
+    fn post(key: &str, sequence: u64, timestamp: i64) -> Post {
+        Post::builder(key)
+            .text(format!("text of {}", key))
+            .sequence(sequence)
+            .timestamp(timestamp)
+            .build()
+    }
+
+    #[test]
+    fn post_builder_sets_fields_and_defaults() {
+        let post = Post::builder("%post1")
+            .text("hello")
+            .sequence(3)
+            .subject(Some("hel".to_string()))
+            .build();
+        assert_eq!(post.key, "%post1");
+        assert_eq!(post.text, "hello");
+        assert_eq!(post.sequence, 3);
+        assert_eq!(post.subject.as_deref(), Some("hel"));
+        assert_eq!(post.timestamp, 0);
+        assert_eq!(post.date, format_post_date(0));
+        assert!(!post.read);
+    }
+
+    #[test]
+    fn add_get_and_remove_peer() {
+        let db = Database::init_temp();
+
+        assert!(db.get_peer(PEER_A).unwrap().is_none());
+
+        db.add_peer(Peer::new(PEER_A).set_name("glyph")).unwrap();
+        let peer = db.get_peer(PEER_A).unwrap().unwrap();
+        assert_eq!(peer.public_key, PEER_A);
+        assert_eq!(peer.name, "glyph");
+        assert_eq!(peer.latest_sequence, 0);
+
+        // Reinserting a peer overwrites the existing entry.
+        db.add_peer(peer.set_latest_sequence(42)).unwrap();
+        assert_eq!(db.get_peer(PEER_A).unwrap().unwrap().latest_sequence, 42);
+
+        db.remove_peer(PEER_A).unwrap();
+        assert!(db.get_peer(PEER_A).unwrap().is_none());
+    }
+
+    #[test]
+    fn legacy_peer_data_is_deserialized() {
+        let db = Database::init_temp();
+        // Peer data serialized with the legacy layout of the Peer struct.
+        let legacy_bytes = bincode::serialize(&(PEER_A, "glyph", 7u64)).unwrap();
+        db.peer_tree.insert(PEER_A, legacy_bytes).unwrap();
+
+        let peer = db.get_peer(PEER_A).unwrap().unwrap();
+        assert_eq!(peer.name, "glyph");
+        assert_eq!(peer.latest_sequence, 7);
+        assert!(!peer.archived);
+        assert_eq!(db.get_peers().len(), 1);
+    }
+
+    #[test]
+    fn set_peer_notes_keeps_other_fields() {
+        let db = Database::init_temp();
+        assert!(!db.set_peer_notes(PEER_A, "met at a meetup").unwrap());
+
+        db.add_peer(Peer::new(PEER_A).set_name("glyph").set_archived(true))
+            .unwrap();
+        assert!(db.set_peer_notes(PEER_A, "met at a meetup").unwrap());
+
+        let peer = db.get_peer(PEER_A).unwrap().unwrap();
+        assert_eq!(peer.notes, "met at a meetup");
+        assert_eq!(peer.name, "glyph");
+        assert!(peer.archived);
+    }
+
+    #[test]
+    fn update_peer_applies_the_update_to_the_stored_peer() {
+        let db = Database::init_temp();
+        assert!(db
+            .update_peer(PEER_A, |peer| peer.set_archived(true))
+            .unwrap()
+            .is_none());
+        assert!(db.get_peer(PEER_A).unwrap().is_none());
+
+        db.add_peer(Peer::new(PEER_A).set_name("glyph")).unwrap();
+        db.set_peer_notes(PEER_A, "met at a meetup").unwrap();
+        let peer = db
+            .update_peer(PEER_A, |peer| peer.set_latest_sequence(42))
+            .unwrap()
+            .unwrap();
+        assert_eq!(peer.latest_sequence, 42);
+        assert_eq!(peer.name, "glyph");
+        assert_eq!(peer.notes, "met at a meetup");
+        assert_eq!(db.get_peer(PEER_A).unwrap().unwrap().latest_sequence, 42);
+    }
+
+    #[test]
+    fn archived_peers_are_listed_separately() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A)).unwrap();
+        db.add_peer(Peer::new(PEER_B).set_archived(true)).unwrap();
+
+        let active = db.get_active_peers();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].public_key, PEER_A);
+
+        let archived = db.get_archived_peers();
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].public_key, PEER_B);
+    }
+
+    #[test]
+    fn merge_peers_moves_posts_and_merges_metadata() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A).set_latest_sequence(2))
+            .unwrap();
+        db.add_peer(Peer::new(PEER_B).set_name("glyph").set_latest_sequence(5))
+            .unwrap();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.update_post_read(PEER_A, "%post1", true).unwrap();
+        db.add_post_batch(PEER_B, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+
+        assert_eq!(db.merge_peers(PEER_A, PEER_B).unwrap(), 1);
+
+        let peer = db.get_peer(PEER_A).unwrap().unwrap();
+        assert_eq!(peer.name, "glyph");
+        assert_eq!(peer.latest_sequence, 5);
+        assert!(db.get_peer(PEER_B).unwrap().is_none());
+        assert!(db.get_posts(PEER_B).unwrap().is_empty());
+
+        // The post stored for both peers keeps the state of the canonical peer.
+        assert!(db.get_post(PEER_A, "%post1").unwrap().unwrap().read);
+        assert_eq!(
+            db.get_post(PEER_A, "%post2").unwrap().unwrap().author,
+            PEER_A
+        );
+    }
+
+    #[test]
+    fn merge_peers_moves_replies_comments_and_tombstones() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_B)).unwrap();
+        db.add_post_batch(PEER_B, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        db.add_reply_batch(PEER_B, vec![("%post1".to_string(), post("%reply", 3, 300))])
+            .unwrap();
+        let mut comment = post("%comment", 4, 400);
+        comment.author = PEER_B.to_string();
+        db.add_comment_batch(&[("%root".to_string(), comment)])
+            .unwrap();
+        db.remove_post(PEER_B, "%post2").unwrap();
+
+        assert_eq!(db.merge_peers(PEER_A, PEER_B).unwrap(), 1);
+
+        // The post deleted for the duplicate stays deleted for the canonical
+        // peer when it is fetched again.
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_some());
+        db.add_post_batch(PEER_A, vec![post("%post2", 2, 200)])
+            .unwrap();
+        assert!(db.get_post(PEER_A, "%post2").unwrap().is_none());
+        assert!(db.undelete_post(PEER_A, "%post2").unwrap());
+        assert!(!db.undelete_post(PEER_B, "%post2").unwrap());
+
+        let threads = db.get_threads(PEER_A).unwrap();
+        assert_eq!(threads[0].1[0].key, "%reply");
+        assert_eq!(threads[0].1[0].author, PEER_A);
+        assert!(db.get_threads(PEER_B).unwrap().is_empty());
+        assert_eq!(db.get_comments("%root").unwrap()[0].author, PEER_A);
+    }
+
+    #[test]
+    fn get_peers_returns_all_peers() {
+        let db = Database::init_temp();
+        assert!(db.get_peers().is_empty());
+
+        db.add_peer(Peer::new(PEER_A)).unwrap();
+        db.add_peer(Peer::new(PEER_B)).unwrap();
+
+        let mut keys: Vec<String> = db.get_peers().into_iter().map(|p| p.public_key).collect();
+        keys.sort();
+        let mut expected = vec![PEER_A.to_string(), PEER_B.to_string()];
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn peers_by_unread_recency_orders_by_latest_unread_post() {
+        let db = Database::init_temp();
+        let peer_c = "@6ilZq3kN0F+dXFHAPjAwMm87JEb/VdB+LC9eIMW3sa0=.ed25519";
+        for peer in [PEER_A, PEER_B, peer_c] {
+            db.add_peer(Peer::new(peer)).unwrap();
+        }
+
+        // The most recent post of PEER_A is read, so their latest unread
+        // post is older than that of PEER_B.
+        db.add_post(PEER_A, post("%a1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%a2", 2, 400)).unwrap();
+        db.update_post_read(PEER_A, "%a2", true).unwrap();
+        db.add_post(PEER_B, post("%b1", 1, 300)).unwrap();
+        // All posts of peer_c are read.
+        db.add_post(peer_c, post("%c1", 1, 500)).unwrap();
+        db.update_post_read(peer_c, "%c1", true).unwrap();
+
+        let keys: Vec<String> = db
+            .peers_by_unread_recency()
+            .into_iter()
+            .map(|peer| peer.public_key)
+            .collect();
+        assert_eq!(keys, vec![PEER_B, PEER_A]);
+    }
+
+    #[test]
+    fn search_peers_matches_name_regardless_of_composition() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A).set_name("Ren\u{e9}e"))
+            .unwrap();
+        db.add_peer(Peer::new(PEER_B).set_name("glyph")).unwrap();
+
+        let found = db.search_peers("rene\u{301}e");
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].public_key, PEER_A);
+
+        assert_eq!(db.search_peers("qK93G")[0].public_key, PEER_B);
+        assert!(db.search_peers("  ").is_empty());
+    }
+
+    #[test]
+    fn export_peer_includes_peer_and_posts() {
+        let db = Database::init_temp();
+        assert!(db.export_peer(PEER_A).unwrap().is_none());
+
+        db.add_peer(Peer::new(PEER_A).set_name("glyph")).unwrap();
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        db.add_post(PEER_B, post("%other", 1, 300)).unwrap();
+
+        let export = db.export_peer(PEER_A).unwrap().unwrap();
+        assert_eq!(export["peer"]["public_key"], PEER_A);
+        assert_eq!(export["peer"]["name"], "glyph");
+        let keys: Vec<&str> = export["posts"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|post| post["key"].as_str().unwrap())
+            .collect();
+        assert_eq!(keys, vec!["%post2", "%post1"]);
+    }
+
+    #[test]
+    fn stored_posts_omit_date_and_format_it_on_read() {
+        let db = Database::init_temp();
+        let timestamp = 1621252800;
+        db.add_post(PEER_A, post("%post1", 1, timestamp)).unwrap();
+
+        let post_bytes = db
+            .post_tree
+            .get(format!("{}_%post1", PEER_A))
+            .unwrap()
+            .unwrap();
+        let stored_post: StoredPost<String> = bincode::deserialize(&post_bytes).unwrap();
+        assert_eq!(
+            bincode::serialized_size(&stored_post).unwrap(),
+            post_bytes.len() as u64
+        );
+
+        let post = db.get_post(PEER_A, "%post1").unwrap().unwrap();
+        assert_eq!(post.date, sbot::format_date(timestamp, sbot::date_format()));
+    }
+
+    #[test]
+    fn migrate_posts_drops_stored_date() {
+        let db = Database::init_temp();
+        // Post data serialized with the legacy layout of the Post struct.
+        let legacy_bytes = bincode::serialize(&(
+            "%post1",
+            "\"learning #Rust\"",
+            "17 May 2021",
+            1u64,
+            true,
+            1621252800i64,
+            None::<String>,
+        ))
+        .unwrap();
+        db.post_tree
+            .insert(format!("{}_%post1", PEER_A), legacy_bytes)
+            .unwrap();
+        db.db.remove(POST_FORMAT_KEY).unwrap();
+
+        db.migrate_posts().unwrap();
+        let post = db.get_post(PEER_A, "%post1").unwrap().unwrap();
+        assert_eq!(post.text, "\"learning #Rust\"");
+        assert_eq!(post.sequence, 1);
+        assert!(post.read);
+        assert_eq!(post.timestamp, 1621252800);
+        assert_eq!(post.author, PEER_A);
+        assert_eq!(post.channels, vec!["rust"]);
+        assert!(!post.duplicate);
+        assert_eq!(post.like_count, 0);
+        assert!(!post.starred);
+        assert_eq!(post.read_at, None);
+
+        // Migrating again leaves the posts untouched.
+        db.migrate_posts().unwrap();
+        assert_eq!(
+            db.get_post(PEER_A, "%post1").unwrap().unwrap().author,
+            PEER_A
+        );
+    }
+
+    #[test]
+    fn posts_by_channel_are_matched_across_peers() {
+        let db = Database::init_temp();
+        let channel_post = |key: &str, timestamp: i64, channels: &[&str]| {
+            Post::builder(key)
+                .timestamp(timestamp)
+                .channels(channels.iter().map(|c| c.to_string()).collect())
+                .build()
+        };
+        db.add_post(PEER_A, channel_post("%a1", 100, &["rust"]))
+            .unwrap();
+        db.add_post(PEER_A, channel_post("%a2", 300, &["garlic"]))
+            .unwrap();
+        db.add_post(PEER_B, channel_post("%b1", 200, &["garlic", "rust"]))
+            .unwrap();
+        let mut filtered = channel_post("%b2", 400, &["rust"]);
+        filtered.filtered = true;
+        db.add_post(PEER_B, filtered).unwrap();
+
+        let keys: Vec<String> = db
+            .get_posts_by_channel("#Rust")
+            .into_iter()
+            .map(|post| post.key)
+            .collect();
+        assert_eq!(keys, vec!["%b1", "%a1"]);
+        assert!(db.get_posts_by_channel("solarpunk").is_empty());
+    }
+
+    #[test]
+    fn recently_read_posts_are_sorted_by_read_time() {
+        let db = Database::init_temp();
+        let mut posts = Vec::new();
+        for (i, read_at) in [(1, Some(300)), (2, None), (3, Some(100)), (4, Some(200))] {
+            let mut post = post(&format!("%post{}", i), i, i as i64);
+            post.read = true;
+            post.read_at = read_at;
+            posts.push(post);
+        }
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(PEER_B, post("%unread", 1, 1)).unwrap();
+
+        let keys: Vec<String> = db.get_recently_read(2).into_iter().map(|p| p.key).collect();
+        assert_eq!(keys, vec!["%post1", "%post4"]);
+        assert_eq!(db.get_recently_read(10).len(), 3);
+
+        // Marking a post as unread clears the time at which it was read.
+        db.update_post_read(PEER_A, "%post1", false).unwrap();
+        assert_eq!(
+            db.get_post(PEER_A, "%post1").unwrap().unwrap().read_at,
+            None
+        );
+
+        let before = Utc::now().timestamp();
+        db.update_post_read(PEER_B, "%unread", true).unwrap();
+        let read_at = db.get_post(PEER_B, "%unread").unwrap().unwrap().read_at;
+        assert!(read_at.unwrap() >= before);
+        assert_eq!(db.get_recently_read(1)[0].key, "%unread");
+    }
+
+    #[test]
+    fn get_post_by_ref_finds_posts_and_replies_of_any_author() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.add_post(PEER_B, post("%post2", 1, 200)).unwrap();
+        db.add_reply_batch(
+            PEER_B,
+            vec![("%post2".to_string(), post("%reply1", 2, 300))],
+        )
+        .unwrap();
+
+        let found = db.get_post_by_ref("%post2").unwrap().unwrap();
+        assert_eq!(found.author, PEER_B);
+        let found = db.get_post_by_ref("%reply1").unwrap().unwrap();
+        assert_eq!(found.key, "%reply1");
+        assert!(db.get_post_by_ref("%post").unwrap().is_none());
+    }
+
+    #[test]
+    fn import_peer_keeps_local_read_state() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A).set_name("glyph")).unwrap();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.update_post_read(PEER_A, "%post1", true).unwrap();
+        db.add_post(PEER_A, post("%post3", 3, 300)).unwrap();
+        db.remove_post(PEER_A, "%post3").unwrap();
+
+        let import: PeerImport = serde_json::from_value(json!({
+            "peer": { "public_key": PEER_A, "name": "other", "latest_sequence": 2 },
+            "posts": [
+                { "key": "%post1", "text": "edited", "date": "", "sequence": 1,
+                  "read": false, "timestamp": 100, "subject": null },
+                { "key": "%post2", "text": "new", "date": "", "sequence": 2,
+                  "read": true, "timestamp": 200, "subject": null },
+                { "key": "%post3", "text": "deleted", "date": "", "sequence": 3,
+                  "read": false, "timestamp": 300, "subject": null }
+            ]
+        }))
+        .unwrap();
+
+        let counts = db.import_peer(import).unwrap();
+        assert_eq!(
+            counts,
+            ImportCounts {
+                added: 1,
+                skipped: 1,
+                deleted: 1
+            }
+        );
+        assert!(db.get_post(PEER_A, "%post3").unwrap().is_none());
+        assert_eq!(db.get_peer(PEER_A).unwrap().unwrap().name, "glyph");
+
+        let existing = db.get_post(PEER_A, "%post1").unwrap().unwrap();
+        assert!(existing.read);
+        assert_eq!(existing.text, "edited");
+        assert!(db.get_post(PEER_A, "%post2").unwrap().unwrap().read);
+    }
+
+    #[test]
+    fn import_peer_replaces_the_index_entry_of_redated_posts() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A)).unwrap();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+
+        let import: PeerImport = serde_json::from_value(json!({
+            "peer": { "public_key": PEER_A, "name": "", "latest_sequence": 1 },
+            "posts": [
+                { "key": "%post1", "text": "redated", "sequence": 1,
+                  "read": false, "timestamp": 500, "subject": null }
+            ]
+        }))
+        .unwrap();
+        db.import_peer(import).unwrap();
+
+        let index_keys: Vec<IVec> = db
+            .post_date_index
+            .scan_prefix(PEER_A.as_bytes())
+            .keys()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            index_keys,
+            vec![IVec::from(date_index_key(PEER_A, 500, "%post1").as_bytes())]
+        );
+        assert_eq!(
+            db.get_posts_by_date_range(PEER_A, 0, 1000).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn exported_peer_can_be_imported() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A).set_name("glyph")).unwrap();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        let export = db.export_peer(PEER_A).unwrap().unwrap();
+
+        let other_db = Database::init_temp();
+        let counts = other_db
+            .import_peer(serde_json::from_value(export).unwrap())
+            .unwrap();
+        assert_eq!(counts.added, 1);
+        assert_eq!(other_db.get_peer(PEER_A).unwrap().unwrap().name, "glyph");
+        assert!(other_db.get_post(PEER_A, "%post1").unwrap().is_some());
+    }
+
+    #[test]
+    fn add_get_and_remove_post() {
+        let db = Database::init_temp();
+
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_none());
+
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        let stored = db.get_post(PEER_A, "%post1").unwrap().unwrap();
+        assert_eq!(stored.key, "%post1");
+        assert_eq!(stored.text, "text of %post1");
+        assert_eq!(stored.sequence, 1);
+        assert_eq!(stored.author, PEER_A);
+        assert!(!stored.read);
+
+        // The post is only retrievable using the public key of the author.
+        assert!(db.get_post(PEER_B, "%post1").unwrap().is_none());
+
+        db.remove_post(PEER_A, "%post1").unwrap();
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_none());
+    }
+
+    #[test]
+    fn deleted_post_is_not_restored_until_undeleted() {
+        let db = Database::init_temp();
+
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.remove_post(PEER_A, "%post1").unwrap();
+
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_none());
+        assert!(db.get_post(PEER_A, "%post2").unwrap().is_some());
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_none());
+
+        assert!(db.undelete_post(PEER_A, "%post1").unwrap());
+        assert!(!db.undelete_post(PEER_A, "%post1").unwrap());
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100)])
+            .unwrap();
+        assert!(db.get_post(PEER_A, "%post1").unwrap().is_some());
+        assert!(!db.undelete_post(PEER_B, "%post1").unwrap());
+    }
+
+    #[test]
+    fn get_posts_sorts_by_timestamp_descending() {
+        let db = Database::init_temp();
+
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post1", 1, 100),
+                post("%post3", 3, 300),
+                post("%post2", 2, 200),
+            ],
+        )
+        .unwrap();
+        db.add_post(PEER_B, post("%other", 1, 400)).unwrap();
+
+        let keys: Vec<String> = db
+            .get_posts(PEER_A)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.key)
+            .collect();
+        assert_eq!(keys, vec!["%post3", "%post2", "%post1"]);
+    }
+
+    #[test]
+    fn get_post_summaries_omits_text_of_long_posts() {
+        let db = Database::init_temp();
+        let long_text = "lorem ipsum ".repeat(8192);
+        let mut posts = Vec::new();
+        for i in 0..200 {
+            let post = Post::builder(format!("%post{}", i))
+                .text(long_text.as_str())
+                .sequence(i)
+                .timestamp(i as i64)
+                .subject(Some(long_text[..52].to_string()))
+                .build();
+            posts.push(post);
+        }
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(
+            PEER_A,
+            Post::builder("%short").text("hi").timestamp(-1).build(),
+        )
+        .unwrap();
+
+        let full = db.get_posts(PEER_A).unwrap();
+        let summaries = db.get_post_summaries(PEER_A).unwrap();
+
+        let full_keys: Vec<&str> = full.iter().map(|post| post.key.as_str()).collect();
+        let summary_keys: Vec<&str> = summaries.iter().map(|post| post.key.as_str()).collect();
+        assert_eq!(full_keys, summary_keys);
+        assert!(summaries[0].text.is_empty());
+        assert_eq!(summaries[0].subject, full[0].subject);
+        assert_eq!(summaries.last().unwrap().text, "hi");
+    }
+
+    #[test]
+    fn posts_iter_for_peer_yields_the_posts_of_the_peer() {
+        let db = Database::init_temp();
+        assert_eq!(db.posts_iter_for_peer(PEER_A).count(), 0);
+
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post2", 2, 200),
+                post("%post1", 1, 100),
+                post("%post3", 3, 300),
+            ],
+        )
+        .unwrap();
+        db.add_post(PEER_B, post("%other", 1, 400)).unwrap();
+
+        let mut posts: Vec<Post> = db
+            .posts_iter_for_peer(PEER_A)
+            .map(|post| post.unwrap())
+            .collect();
+        posts.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        let keys =
+            |posts: &[Post]| -> Vec<String> { posts.iter().map(|post| post.key.clone()).collect() };
+        assert_eq!(keys(&posts), keys(&db.get_posts(PEER_A).unwrap()));
+    }
+
+    #[test]
+    fn get_posts_sorted_ascending_lists_oldest_first() {
+        let db = Database::init_temp();
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post2", 2, 200),
+                post("%post1", 1, 100),
+                post("%post3", 3, 300),
+            ],
+        )
+        .unwrap();
+
+        let keys: Vec<String> = db
+            .get_posts_sorted(PEER_A, SortOrder::Ascending)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.key)
+            .collect();
+        assert_eq!(keys, vec!["%post1", "%post2", "%post3"]);
+    }
+
+    #[test]
+    fn update_post_read_sets_read_state() {
+        let db = Database::init_temp();
+        assert!(!db.update_post_read(PEER_A, "%post1", true).unwrap());
+
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert!(db.update_post_read(PEER_A, "%post1", true).unwrap());
+        assert!(db.get_post(PEER_A, "%post1").unwrap().unwrap().read);
+
+        assert!(db.update_post_read(PEER_A, "%post1", false).unwrap());
+        assert!(!db.get_post(PEER_A, "%post1").unwrap().unwrap().read);
+    }
+
+    #[test]
+    fn mark_all_read_updates_only_the_posts_of_the_peer() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%post2", 2, 200)).unwrap();
+        db.add_post(PEER_A, post("%post3", 3, 300)).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 100)).unwrap();
+        db.update_post_read(PEER_A, "%post2", true).unwrap();
+
+        assert_eq!(db.mark_all_read(PEER_A).unwrap(), 2);
+        assert_eq!(db.get_unread_post_count(PEER_A), 0);
+        assert_eq!(db.get_unread_post_count(PEER_B), 1);
+        assert_eq!(db.mark_all_read(PEER_A).unwrap(), 0);
+    }
+
+    #[test]
+    fn bulk_update_posts_applies_action_to_existing_posts() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%post2", 2, 200)).unwrap();
+        db.add_post(PEER_A, post("%post3", 3, 300)).unwrap();
+        let msg_ids = vec![
+            "%post1".to_string(),
+            "%post2".to_string(),
+            "%missing".to_string(),
+        ];
+
+        assert_eq!(
+            db.bulk_update_posts(PEER_A, &msg_ids, BulkAction::Read)
+                .unwrap(),
+            2
+        );
+        assert_eq!(db.get_unread_post_count(PEER_A), 1);
+
+        assert_eq!(
+            db.bulk_update_posts(PEER_A, &msg_ids, BulkAction::Star)
+                .unwrap(),
+            2
+        );
+        assert!(db.get_post(PEER_A, "%post1").unwrap().unwrap().starred);
+        assert!(!db.get_post(PEER_A, "%post3").unwrap().unwrap().starred);
+
+        assert_eq!(
+            db.bulk_update_posts(PEER_A, &msg_ids, BulkAction::Delete)
+                .unwrap(),
+            2
+        );
+        let remaining: Vec<String> = db
+            .get_posts(PEER_A)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.key)
+            .collect();
+        assert_eq!(remaining, vec!["%post3"]);
+    }
+
+    #[test]
+    fn rebuild_indexes_recomputes_derived_data_idempotently() {
+        let db = Database::init_temp();
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post1", 1, 100),
+                post("%post2", 2, 200),
+                Post::builder("%post3")
+                    .text("text of %post1")
+                    .sequence(3)
+                    .build(),
+            ],
+        )
+        .unwrap();
+        let mut read_post = db.get_post(PEER_A, "%post2").unwrap().unwrap();
+        read_post.read = true;
+        read_post.author = PEER_B.to_string();
+        db.post_tree
+            .insert(format!("{}_%post2", PEER_A), read_post.to_bincode())
+            .unwrap();
+        db.add_reply_batch(
+            PEER_A,
+            vec![
+                ("%post1".to_string(), post("%reply1", 4, 400)),
+                ("%gone".to_string(), post("%reply2", 5, 500)),
+            ],
+        )
+        .unwrap();
+
+        let filter_words = vec!["post2".to_string()];
+        let counts = db.rebuild_indexes(&filter_words, 1).unwrap();
+        assert_eq!(
+            counts,
+            ReindexCounts {
+                posts: 3,
+                updated: 1,
+                removed_replies: 1,
+            }
+        );
+
+        let post2 = db.get_post(PEER_A, "%post2").unwrap().unwrap();
+        assert_eq!(post2.author, PEER_A);
+        assert!(post2.filtered);
+        assert!(post2.read);
+        // The repeated text is outside a window of one post.
+        assert!(!db.get_post(PEER_A, "%post3").unwrap().unwrap().duplicate);
+        let threads = db.get_threads(PEER_A).unwrap();
+        let replies: Vec<&str> = threads
+            .iter()
+            .flat_map(|(_, replies)| replies.iter().map(|reply| reply.key.as_str()))
+            .collect();
+        assert_eq!(replies, vec!["%reply1"]);
+
+        // A wider window flags the repeated text as a duplicate.
+        let counts = db.rebuild_indexes(&filter_words, 2).unwrap();
+        assert_eq!(counts.updated, 1);
+        assert!(db.get_post(PEER_A, "%post3").unwrap().unwrap().duplicate);
+
+        let counts = db.rebuild_indexes(&filter_words, 2).unwrap();
+        assert_eq!(
+            counts,
+            ReindexCounts {
+                posts: 3,
+                updated: 0,
+                removed_replies: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn comments_are_kept_by_root_post_even_if_it_is_not_stored() {
+        let db = Database::init_temp();
+        let mut comment = post("%comment1", 4, 300);
+        comment.author = PEER_B.to_string();
+        db.add_comment_batch(&[
+            ("%post1".to_string(), comment),
+            ("%post1".to_string(), post("%comment2", 2, 200)),
+            ("%post2".to_string(), post("%comment3", 3, 100)),
+        ])
+        .unwrap();
+
+        let comments = db.get_comments("%post1").unwrap();
+        let keys: Vec<&str> = comments.iter().map(|c| c.key.as_str()).collect();
+        assert_eq!(keys, vec!["%comment2", "%comment1"]);
+        assert_eq!(comments[1].author, PEER_B);
+
+        // Comments are found by reference once the root post is downloaded.
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert_eq!(db.get_comments("%post1").unwrap().len(), 2);
+        assert!(db.get_post_by_ref("%comment3").unwrap().is_some());
+    }
+
+    #[test]
+    fn get_threads_attaches_replies_to_root_posts() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%root1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%root2", 2, 200)).unwrap();
+        db.add_reply_batch(
+            PEER_A,
+            vec![
+                ("%root1".to_string(), post("%reply2", 4, 400)),
+                ("%root1".to_string(), post("%reply1", 3, 300)),
+            ],
+        )
+        .unwrap();
+
+        let threads = db.get_threads(PEER_A).unwrap();
+        assert_eq!(threads.len(), 2);
+        assert_eq!(threads[0].0.key, "%root2");
+        assert!(threads[0].1.is_empty());
+        assert_eq!(threads[1].0.key, "%root1");
+        let reply_keys: Vec<&str> = threads[1].1.iter().map(|post| post.key.as_str()).collect();
+        assert_eq!(reply_keys, vec!["%reply1", "%reply2"]);
+        assert_eq!(threads[1].1[0].author, PEER_A);
+    }
+
+    #[test]
+    fn update_post_like_count_sets_like_count() {
+        let db = Database::init_temp();
+        assert!(!db.update_post_like_count(PEER_A, "%post1", 3).unwrap());
+
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert!(db.update_post_like_count(PEER_A, "%post1", 3).unwrap());
+        assert_eq!(
+            db.get_post(PEER_A, "%post1").unwrap().unwrap().like_count,
+            3
+        );
+    }
+
+    #[test]
+    fn add_votes_tracks_likes_and_vote_sequence() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_vote_sequence(PEER_A).unwrap(), 0);
+
+        let like = |msg_id: &str, liked: bool| (msg_id.to_string(), liked);
+        db.add_votes(PEER_A, &[like("%post1", true), like("%post2", true)], 5)
+            .unwrap();
+        db.add_votes(PEER_B, &[like("%post1", true)], 3).unwrap();
+        assert_eq!(db.get_like_count("%post1"), 2);
+        assert_eq!(db.get_like_count("%post2"), 1);
+        assert_eq!(db.get_like_count("%post"), 0);
+
+        // An unvote cancels the earlier like of the same peer only.
+        db.add_votes(PEER_A, &[like("%post1", false)], 7).unwrap();
+        assert_eq!(db.get_like_count("%post1"), 1);
+        assert!(!db.is_liked_by("%post1", PEER_A).unwrap());
+        assert!(db.is_liked_by("%post1", PEER_B).unwrap());
+        assert_eq!(db.get_vote_sequence(PEER_A).unwrap(), 7);
+        assert_eq!(db.get_vote_sequence(PEER_B).unwrap(), 3);
+    }
+
+    #[test]
+    fn add_like_is_kept_until_an_unvote_is_tallied() {
+        let db = Database::init_temp();
+        db.add_like("%post1", PEER_A).unwrap();
+        assert!(db.is_liked_by("%post1", PEER_A).unwrap());
+        assert_eq!(db.get_like_count("%post1"), 1);
+        // The tally of the published vote leaves the like in place.
+        db.add_votes(PEER_A, &[("%post1".to_string(), true)], 1)
+            .unwrap();
+        assert_eq!(db.get_like_count("%post1"), 1);
+
+        db.add_votes(PEER_A, &[("%post1".to_string(), false)], 2)
+            .unwrap();
+        assert!(!db.is_liked_by("%post1", PEER_A).unwrap());
+    }
+
+    #[test]
+    fn add_post_batch_preserves_the_state_of_stored_posts() {
+        let db = Database::init_temp();
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100)])
+            .unwrap();
+        assert!(db.update_post_read(PEER_A, "%post1", true).unwrap());
+        assert!(db.update_post_like_count(PEER_A, "%post1", 2).unwrap());
+        let read_at = db.get_post(PEER_A, "%post1").unwrap().unwrap().read_at;
+        assert!(read_at.is_some());
+
+        let mut refetched = post("%post1", 1, 100);
+        refetched.text = "edited text".to_string();
+        db.add_post_batch(PEER_A, vec![refetched, post("%post2", 2, 200)])
+            .unwrap();
+
+        let post1 = db.get_post(PEER_A, "%post1").unwrap().unwrap();
+        assert!(post1.read);
+        assert_eq!(post1.read_at, read_at);
+        assert_eq!(post1.like_count, 2);
+        assert_eq!(post1.text, "edited text");
+        assert!(!db.get_post(PEER_A, "%post2").unwrap().unwrap().read);
+
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert!(db.get_post(PEER_A, "%post1").unwrap().unwrap().read);
+    }
+
+    #[test]
+    fn first_unread_post_skips_read_posts() {
+        let db = Database::init_temp();
+        assert!(db.first_unread_post(PEER_A).unwrap().is_none());
+
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        assert_eq!(db.first_unread_post(PEER_A).unwrap().unwrap().key, "%post2");
+
+        db.update_post_read(PEER_A, "%post2", true).unwrap();
+        assert_eq!(db.first_unread_post(PEER_A).unwrap().unwrap().key, "%post1");
+    }
+
+    #[test]
+    fn search_posts_matches_text_regardless_of_composition_and_case() {
+        let db = Database::init_temp();
+        let mut precomposed = post("%post1", 1, 100);
+        precomposed.text = "\"Meet me at the Caf\u{e9}\"".to_string();
+        let mut decomposed = post("%post2", 2, 200);
+        decomposed.text = "\"Another cafe\u{301} post\"".to_string();
+        let unrelated = post("%post3", 3, 300);
+        db.add_post_batch(PEER_A, vec![precomposed, decomposed, unrelated])
+            .unwrap();
+
+        for query in ["caf\u{e9}", "CAFE\u{301}", " Caf\u{e9} "] {
+            let keys: Vec<String> = db.search_posts(query).into_iter().map(|p| p.key).collect();
+            assert_eq!(keys, vec!["%post2", "%post1"]);
+        }
+        assert!(db.search_posts("").is_empty());
+        assert!(db.search_posts("  ").is_empty());
+        assert!(db.search_posts("tea").is_empty());
+    }
+
+    #[test]
+    fn get_posts_paged_returns_windows_by_descending_sequence() {
+        let db = Database::init_temp();
+        // Timestamps are out of order; pages follow the sequence numbers.
+        let posts = (1..=5)
+            .map(|i| post(&format!("%post{}", i), i, 1000 - i as i64))
+            .collect();
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(PEER_B, post("%other", 9, 100)).unwrap();
+
+        let page = |offset, limit| -> Vec<u64> {
+            db.get_posts_paged(PEER_A, SortOrder::Descending, offset, limit)
+                .unwrap()
+                .into_iter()
+                .map(|post| post.sequence)
+                .collect()
+        };
+        assert_eq!(page(0, 2), vec![5, 4]);
+        assert_eq!(page(2, 2), vec![3, 2]);
+        assert_eq!(page(4, 2), vec![1]);
+        assert!(page(6, 2).is_empty());
+
+        let oldest_first: Vec<u64> = db
+            .get_posts_paged(PEER_A, SortOrder::Ascending, 1, 3)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.sequence)
+            .collect();
+        assert_eq!(oldest_first, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn sort_order_defaults_to_newest_first() {
+        assert_eq!(SortOrder::from_query(Some("oldest")), SortOrder::Ascending);
+        assert_eq!(SortOrder::from_query(Some("newest")), SortOrder::Descending);
+        assert_eq!(
+            SortOrder::from_query(Some("sideways")),
+            SortOrder::Descending
+        );
+        assert_eq!(SortOrder::from_query(None), SortOrder::Descending);
+        assert_eq!(SortOrder::Ascending.as_query(), "oldest");
+    }
+
+    #[test]
+    fn peers_are_ranked_by_post_count_then_name() {
+        let db = Database::init_temp();
+        let peer_c = "@CCCC940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        db.add_peer(Peer::new(PEER_A).set_name("zed")).unwrap();
+        db.add_peer(Peer::new(PEER_B).set_name("amy")).unwrap();
+        db.add_peer(Peer::new(peer_c).set_name("bob")).unwrap();
+
+        db.add_post_batch(PEER_A, vec![post("%a1", 1, 100), post("%a2", 2, 200)])
+            .unwrap();
+        db.add_post_batch(peer_c, vec![post("%c1", 1, 100), post("%c2", 2, 200)])
+            .unwrap();
+        db.add_post_batch(PEER_B, vec![post("%b1", 1, 100)])
+            .unwrap();
+
+        let ranking: Vec<(String, usize)> = db
+            .peers_by_post_count()
+            .into_iter()
+            .map(|(peer, post_count)| (peer.name, post_count))
+            .collect();
+        assert_eq!(
+            ranking,
+            vec![
+                ("bob".to_string(), 2),
+                ("zed".to_string(), 2),
+                ("amy".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_peer_posts_removes_only_the_posts_of_the_peer() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%post2", 2, 200)).unwrap();
+        db.add_reply_batch(
+            PEER_A,
+            vec![("%post1".to_string(), post("%reply1", 3, 300))],
+        )
+        .unwrap();
+        db.add_post(PEER_B, post("%other", 1, 100)).unwrap();
+
+        assert_eq!(db.remove_peer_posts(PEER_A).unwrap(), 3);
+        assert!(db.get_posts(PEER_A).unwrap().is_empty());
+        assert!(db.get_post_by_ref("%reply1").unwrap().is_none());
+        assert_eq!(db.get_posts(PEER_B).unwrap().len(), 1);
+
+        // The posts are not marked as deleted.
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        assert_eq!(db.get_posts(PEER_A).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn private_messages_are_kept_apart_from_posts() {
+        let db = Database::init_temp();
+        let message = |key: &str, author: &str, timestamp: i64| {
+            Post::builder(key)
+                .author(author)
+                .text("a private message")
+                .timestamp(timestamp)
+                .build()
+        };
+
+        let messages = vec![message("%dm1", PEER_A, 100), message("%dm2", PEER_B, 200)];
+        assert_eq!(db.add_private_messages(messages).unwrap(), 2);
+        assert!(db.get_posts(PEER_A).unwrap().is_empty());
+
+        // Messages which are fetched again keep their read state.
+        let mut fetched_again = message("%dm1", PEER_A, 100);
+        fetched_again.read = true;
+        assert_eq!(db.add_private_messages(vec![fetched_again]).unwrap(), 0);
+        let messages = db.get_private_messages().unwrap();
+        let keys: Vec<&str> = messages.iter().map(|msg| msg.key.as_str()).collect();
+        assert_eq!(keys, vec!["%dm2", "%dm1"]);
+        assert!(!messages[1].read);
+    }
+
+    #[test]
+    fn private_sequences_are_kept_per_feed() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_private_sequence(PEER_A).unwrap(), 0);
+
+        db.set_private_sequence(PEER_A, 42).unwrap();
+        db.set_private_sequence(PEER_B, 7).unwrap();
+        assert_eq!(db.get_private_sequence(PEER_A).unwrap(), 42);
+        assert_eq!(db.get_private_sequence(PEER_B).unwrap(), 7);
+    }
+
+    #[test]
+    fn delete_read_posts_keeps_unread_and_starred_posts() {
+        let db = Database::init_temp();
+        let posts = (1..=4)
+            .map(|i| post(&format!("%post{}", i), i, i as i64 * 100))
+            .collect();
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 100)).unwrap();
+        db.update_post_read(PEER_B, "%other", true).unwrap();
+
+        // The oldest post is left unread, while newer ones are read.
+        let read: Vec<String> = (2..=4).map(|i| format!("%post{}", i)).collect();
+        db.bulk_update_posts(PEER_A, &read, BulkAction::Read)
+            .unwrap();
+        db.bulk_update_posts(PEER_A, &["%post4".to_string()], BulkAction::Star)
+            .unwrap();
+
+        assert_eq!(db.delete_read_posts(PEER_A).unwrap(), 2);
+        let mut keys: Vec<String> = db
+            .get_posts(PEER_A)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, vec!["%post1", "%post4"]);
+
+        // Deleted posts are not restored by a refetch, and the posts of other
+        // peers are untouched.
+        db.add_post_batch(PEER_A, vec![post("%post2", 2, 200)])
+            .unwrap();
+        assert!(db.get_post(PEER_A, "%post2").unwrap().is_none());
+        assert_eq!(db.get_posts(PEER_B).unwrap().len(), 1);
+        assert_eq!(db.delete_read_posts(PEER_A).unwrap(), 0);
+    }
+
+    #[test]
+    fn prune_peer_posts_keeps_latest_unread_and_starred_posts() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A).set_latest_sequence(5))
+            .unwrap();
+        let posts = (1..=5)
+            .map(|i| post(&format!("%post{}", i), i, i as i64 * 100))
+            .collect();
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 100)).unwrap();
+
+        let read: Vec<String> = (1..=5).map(|i| format!("%post{}", i)).collect();
+        db.bulk_update_posts(PEER_A, &read, BulkAction::Read)
+            .unwrap();
+        db.update_post_read(PEER_A, "%post1", false).unwrap();
+        db.bulk_update_posts(PEER_A, &["%post2".to_string()], BulkAction::Star)
+            .unwrap();
+
+        // The two latest posts are kept, as are the unread and starred posts.
+        assert_eq!(db.prune_peer_posts(PEER_A, 2).unwrap(), 1);
+        let mut remaining: Vec<String> = db
+            .get_posts(PEER_A)
+            .unwrap()
+            .into_iter()
+            .map(|post| post.key)
+            .collect();
+        remaining.sort();
+        assert_eq!(remaining, vec!["%post1", "%post2", "%post4", "%post5"]);
+
+        // Pruned posts are not restored by a refetch, other peers are left
+        // alone and the latest sequence number is unchanged.
+        db.add_post_batch(PEER_A, vec![post("%post3", 3, 300)])
+            .unwrap();
+        assert!(db.get_post(PEER_A, "%post3").unwrap().is_none());
+        assert_eq!(db.get_posts(PEER_B).unwrap().len(), 1);
+        assert_eq!(db.get_peer(PEER_A).unwrap().unwrap().latest_sequence, 5);
+    }
+
+    #[test]
+    fn all_posts_count_tracks_added_and_removed_posts() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_all_posts_count(), 0);
+
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        db.add_post(PEER_B, post("%post1", 1, 300)).unwrap();
+        db.add_reply_batch(
+            PEER_A,
+            vec![("%post1".to_string(), post("%reply1", 3, 400))],
+        )
+        .unwrap();
+        assert_eq!(db.get_all_posts_count(), 3);
+
+        db.remove_post(PEER_A, "%post2").unwrap();
+        assert_eq!(db.get_all_posts_count(), 2);
+    }
+
+    #[test]
+    fn total_unread_count_spans_all_peers() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        db.add_post(PEER_A, post("%post2", 2, 200)).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 300)).unwrap();
+        db.update_post_read(PEER_A, "%post1", true).unwrap();
+
+        assert_eq!(db.get_total_unread_count(), 2);
+    }
+
+    #[test]
+    fn unread_counts_exclude_filtered_posts() {
+        let db = Database::init_temp();
+        db.add_post(PEER_A, post("%post1", 1, 100)).unwrap();
+        // A filtered post which has since been marked as unread.
+        let mut filtered = post("%post2", 2, 200);
+        filtered.filtered = true;
+        db.add_post(PEER_A, filtered).unwrap();
+
+        assert_eq!(db.get_unread_post_count(PEER_A), 1);
+        assert_eq!(db.get_total_unread_count(), 1);
+        assert_eq!(db.first_unread_post(PEER_A).unwrap().unwrap().key, "%post1");
+    }
+
+    #[test]
+    fn unread_history_is_ordered_and_bounded() {
+        let db = Database::init_temp();
+        for i in 0..(UNREAD_HISTORY_LIMIT as i64 + 5) {
+            db.add_unread_sample(1_000 + i, i as u64).unwrap();
+        }
+
+        let samples = db.get_unread_history().unwrap();
+        assert_eq!(samples.len(), UNREAD_HISTORY_LIMIT);
+        assert_eq!(samples[0], (1_005, 5));
+        assert_eq!(
+            samples.last(),
+            Some(&(
+                1_000 + UNREAD_HISTORY_LIMIT as i64 + 4,
+                UNREAD_HISTORY_LIMIT as u64 + 4
+            ))
+        );
+    }
+
+    #[test]
+    fn trending_channels_counts_recent_posts() {
+        let db = Database::init_temp();
+        assert!(db.trending_channels(0).is_empty());
+
+        let mut old = post("%post1", 1, 100);
+        old.text = "\"#rust is old news\"".to_string();
+        let mut recent = post("%post2", 2, 200);
+        recent.text = "\"#Garlic and #rust\"".to_string();
+        let mut latest = post("%post3", 3, 300);
+        latest.text = "\"more #garlic\"".to_string();
+        // Filtered posts are not counted.
+        let mut filtered = post("%post4", 4, 400);
+        filtered.text = "\"#rust\"".to_string();
+        filtered.filtered = true;
+        // The channels are extracted when posts are fetched from the sbot.
+        let posts = vec![old, recent, latest, filtered]
+            .into_iter()
+            .map(|mut post| {
+                post.channels = utils::extract_channels(&post.text);
+                post
+            })
+            .collect();
+        db.add_post_batch(PEER_A, posts).unwrap();
+
+        assert_eq!(
+            db.trending_channels(200),
+            vec![("garlic".to_string(), 2), ("rust".to_string(), 1)]
+        );
+        // An empty window yields no channels.
+        assert!(db.trending_channels(301).is_empty());
+    }
+
+    #[test]
+    fn latest_post_timestamp_skips_removed_posts() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_latest_post_timestamp(PEER_A).unwrap(), None);
+
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post1", 1, 100),
+                post("%post2", 2, 300),
+                post("%post3", 3, 200),
+            ],
+        )
+        .unwrap();
+        db.add_post(PEER_B, post("%other", 1, 400)).unwrap();
+        assert_eq!(db.get_latest_post_timestamp(PEER_A).unwrap(), Some(300));
+
+        db.remove_post(PEER_A, "%post2").unwrap();
+        assert_eq!(db.get_latest_post_timestamp(PEER_A).unwrap(), Some(200));
+    }
+
+    #[test]
+    fn post_count_only_includes_posts_of_the_given_peer() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_post_count(PEER_A), 0);
+
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        // A key which extends the key of the peer is a different peer.
+        let longer_key = format!("{}x", PEER_A);
+        db.add_post(&longer_key, post("%post3", 1, 300)).unwrap();
+
+        assert_eq!(db.get_post_count(PEER_A), 2);
+        assert_eq!(db.get_post_count(&longer_key), 1);
+    }
+
+    #[test]
+    fn trees_of_identities_are_kept_apart() {
+        let sled_db = sled::Config::new().temporary(true).open().unwrap();
+        let shared = Database::open_trees(sled_db.clone(), None);
+        let identity_a = Database::open_trees(sled_db.clone(), Some(PEER_A));
+        let identity_b = Database::open_trees(sled_db, Some(PEER_B));
+
+        identity_a.add_peer(Peer::new(PEER_B)).unwrap();
+        identity_a.add_post(PEER_B, post("%post1", 1, 100)).unwrap();
+
+        assert_eq!(identity_a.get_peers().len(), 1);
+        assert!(identity_b.get_peers().is_empty());
+        assert!(shared.get_peers().is_empty());
+        assert!(identity_b.get_post(PEER_B, "%post1").unwrap().is_none());
+        assert_ne!(identity_a.post_format_key, identity_b.post_format_key);
+        assert_eq!(shared.post_format_key, POST_FORMAT_KEY);
+    }
+
+    #[test]
+    fn posts_are_retrieved_by_date_range() {
+        let db = Database::init_temp();
+        db.add_post_batch(
+            PEER_A,
+            vec![
+                post("%post1", 1, 100),
+                post("%post2", 2, 200),
+                post("%post3", 3, 300),
+            ],
+        )
+        .unwrap();
+        db.add_post(PEER_A, post("%post4", 4, 400)).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 250)).unwrap();
+
+        let keys = |posts: Vec<Post>| posts.into_iter().map(|post| post.key).collect::<Vec<_>>();
+        assert_eq!(
+            keys(db.get_posts_by_date_range(PEER_A, 150, 400).unwrap()),
+            vec!["%post2", "%post3", "%post4"]
+        );
+        assert!(db
+            .get_posts_by_date_range(PEER_A, 400, 100)
+            .unwrap()
+            .is_empty());
+
+        // Removed and redated posts are not returned for their old date.
+        db.remove_post(PEER_A, "%post2").unwrap();
+        db.add_post(PEER_A, post("%post3", 3, 500)).unwrap();
+        assert_eq!(
+            keys(db.get_posts_by_date_range(PEER_A, 0, 450).unwrap()),
+            vec!["%post1", "%post4"]
+        );
+        assert_eq!(
+            keys(db.get_posts_by_date_range(PEER_A, 450, 500).unwrap()),
+            vec!["%post3"]
+        );
+
+        // The index is rebuilt from the post tree.
+        db.post_date_index.clear().unwrap();
+        db.rebuild_post_date_index().unwrap();
+        assert_eq!(
+            keys(db.get_posts_by_date_range(PEER_A, 0, 500).unwrap()),
+            vec!["%post1", "%post4", "%post3"]
+        );
+        db.merge_peers(PEER_B, PEER_A).unwrap();
+        assert!(db
+            .get_posts_by_date_range(PEER_A, 0, 500)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            keys(db.get_posts_by_date_range(PEER_B, 0, 500).unwrap()),
+            vec!["%post1", "%other", "%post4", "%post3"]
+        );
+    }
+
+    #[test]
+    fn peers_with_unread_lists_active_peers_and_their_unread_counts() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER_A)).unwrap();
+        db.add_peer(Peer::new(PEER_B).set_archived(true)).unwrap();
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        db.update_post_read(PEER_A, "%post1", true).unwrap();
+        db.add_post(PEER_B, post("%other", 1, 300)).unwrap();
+
+        let peers = db.get_peers_with_unread();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0.public_key, PEER_A);
+        assert_eq!(peers[0].1, 1);
+
+        let archived = db.with_unread_counts(db.get_archived_peers());
+        assert_eq!(archived[0].0.public_key, PEER_B);
+        assert_eq!(archived[0].1, 1);
+    }
+
+    #[test]
+    fn unread_post_count_tracks_read_state() {
+        let db = Database::init_temp();
+        assert_eq!(db.get_unread_post_count(PEER_A), 0);
+
+        db.add_post_batch(PEER_A, vec![post("%post1", 1, 100), post("%post2", 2, 200)])
+            .unwrap();
+        assert_eq!(db.get_unread_post_count(PEER_A), 2);
+
+        db.update_post_read(PEER_A, "%post1", true).unwrap();
+        assert_eq!(db.get_unread_post_count(PEER_A), 1);
+
+        db.remove_post(PEER_A, "%post2").unwrap();
+        assert_eq!(db.get_unread_post_count(PEER_A), 0);
+    }
+
+    #[test]
+    fn unread_post_count_is_cheap_for_peers_with_many_posts() {
+        let db = Database::init_temp();
+
+        // Every third post is read and every fifth post is filtered.
+        let posts = (0..10_000u64)
+            .map(|sequence| {
+                let mut post = post(&format!("%post{}", sequence), sequence, sequence as i64);
+                post.read = sequence % 3 == 0;
+                post.filtered = sequence % 5 == 0;
+                post
+            })
+            .collect();
+        db.add_post_batch(PEER_A, posts).unwrap();
+        db.add_post(PEER_B, post("%post1", 1, 100)).unwrap();
+        let expected = (0..10_000u64)
+            .filter(|sequence| sequence % 3 != 0 && sequence % 5 != 0)
+            .count();
+
+        let start = std::time::Instant::now();
+        for _ in 0..10 {
+            assert_eq!(db.get_unread_post_count(PEER_A) as usize, expected);
+        }
+        let elapsed = start.elapsed();
+
+        // The bound is generous, so that the test only fails if counting
+        // regresses to something far more expensive than a borrowed
+        // deserialization of each post.
+        assert!(
+            elapsed < std::time::Duration::from_secs(5),
+            "counting unread posts 10 times took {:?}",
+            elapsed
+        );
+        assert_eq!(db.get_unread_post_count(PEER_B), 1);
     }
 }
@@ -0,0 +1,124 @@
+//! Background health checking of the connection to the sbot.
+
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std::task;
+use log::{info, warn};
+
+use crate::sbot;
+
+/// The default interval between sbot health checks, in seconds.
+const DEFAULT_CHECK_INTERVAL: u64 = 30;
+
+const STATUS_UNKNOWN: u8 = 0;
+const STATUS_UP: u8 = 1;
+const STATUS_DOWN: u8 = 2;
+
+/// The cached reachability status of the sbot, as determined by the most
+/// recent health check.
+#[derive(Clone)]
+pub struct SbotStatus(Arc<AtomicU8>);
+
+impl Default for SbotStatus {
+    fn default() -> Self {
+        SbotStatus(Arc::new(AtomicU8::new(STATUS_UNKNOWN)))
+    }
+}
+
+impl SbotStatus {
+    /// Return `true` if the most recent health check failed to reach the
+    /// sbot. Returns `false` if the sbot is reachable or if no check has
+    /// completed yet.
+    pub fn is_down(&self) -> bool {
+        self.0.load(Ordering::Relaxed) == STATUS_DOWN
+    }
+
+    /// Return a label describing the status: `up`, `down` or `unknown` (if no
+    /// check has completed yet).
+    pub fn label(&self) -> &'static str {
+        match self.0.load(Ordering::Relaxed) {
+            STATUS_UP => "up",
+            STATUS_DOWN => "down",
+            _ => "unknown",
+        }
+    }
+
+    /// Record the outcome of a health check, returning `true` if the status
+    /// changed as a result.
+    fn update(&self, reachable: bool) -> bool {
+        let status = if reachable { STATUS_UP } else { STATUS_DOWN };
+        self.0.swap(status, Ordering::Relaxed) != status
+    }
+}
+
+/// Return the interval between sbot health checks.
+///
+/// The interval (in seconds) is read from the `LYKIN_HEALTH_CHECK_INTERVAL`
+/// environment variable, falling back to the default if the variable is unset
+/// or invalid.
+pub fn check_interval() -> Duration {
+    let seconds = match env::var("LYKIN_HEALTH_CHECK_INTERVAL") {
+        Ok(interval) => interval.parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid LYKIN_HEALTH_CHECK_INTERVAL {}; using default of {} seconds",
+                interval, DEFAULT_CHECK_INTERVAL
+            );
+            DEFAULT_CHECK_INTERVAL
+        }),
+        Err(_) => DEFAULT_CHECK_INTERVAL,
+    };
+
+    Duration::from_secs(seconds.max(1))
+}
+
+/// Spawn an asynchronous loop which periodically checks whether the sbot is
+/// reachable and records the outcome in the given status, logging any
+/// transitions between reachable and unreachable.
+pub async fn spawn(status: SbotStatus, interval: Duration) {
+    task::spawn(async move {
+        loop {
+            let reachable = sbot::is_reachable().await;
+            if status.update(reachable) {
+                if reachable {
+                    info!("Connection to sbot is up");
+                } else {
+                    warn!("Connection to sbot is down");
+                }
+            }
+
+            task::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_transitions() {
+        let status = SbotStatus::default();
+        assert!(!status.is_down());
+        assert_eq!(status.label(), "unknown");
+
+        // The first check is always a transition from the unknown status.
+        assert!(status.update(true));
+        assert!(!status.update(true));
+        assert!(!status.is_down());
+
+        assert!(status.update(false));
+        assert!(status.is_down());
+        assert_eq!(status.label(), "down");
+        assert!(!status.update(false));
+
+        assert!(status.update(true));
+        assert!(!status.is_down());
+    }
+}
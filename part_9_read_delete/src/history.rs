@@ -0,0 +1,47 @@
+//! Periodic sampling of the total number of unread posts.
+
+use std::{env, time::Duration};
+
+use async_std::task;
+use chrono::Utc;
+use log::warn;
+
+use crate::db::Database;
+
+/// The default interval between samples of the unread count, in seconds.
+const DEFAULT_SAMPLE_INTERVAL: u64 = 3600;
+
+/// Return the interval between samples of the unread count.
+///
+/// The interval (in seconds) is read from the `LYKIN_UNREAD_SAMPLE_INTERVAL`
+/// environment variable, falling back to the default if the variable is unset
+/// or invalid.
+pub fn sample_interval() -> Duration {
+    let seconds = match env::var("LYKIN_UNREAD_SAMPLE_INTERVAL") {
+        Ok(interval) => interval.parse().unwrap_or_else(|_| {
+            warn!(
+                "Invalid LYKIN_UNREAD_SAMPLE_INTERVAL {}; using default of {} seconds",
+                interval, DEFAULT_SAMPLE_INTERVAL
+            );
+            DEFAULT_SAMPLE_INTERVAL
+        }),
+        Err(_) => DEFAULT_SAMPLE_INTERVAL,
+    };
+
+    Duration::from_secs(seconds.max(1))
+}
+
+/// Spawn an asynchronous loop which periodically records the total number of
+/// unread posts in the unread history of the database.
+pub async fn spawn(db: Database, interval: Duration) {
+    task::spawn(async move {
+        loop {
+            let unread = db.get_total_unread_count();
+            if let Err(e) = db.add_unread_sample(Utc::now().timestamp(), unread) {
+                warn!("Failed to record unread count sample: {}", e);
+            }
+
+            task::sleep(interval).await;
+        }
+    });
+}
@@ -1,10 +1,18 @@
 #![doc = include_str!("../README.md")]
 
+mod activity;
+mod config;
 mod db;
+mod health;
+mod history;
+mod private_box;
 mod routes;
 mod sbot;
 mod task_loop;
 mod utils;
+mod webhook;
+
+use std::{env, fs, path::PathBuf};
 
 use async_std::channel;
 use log::info;
@@ -16,42 +24,210 @@ use rocket::{
 use rocket_dyn_templates::Template;
 use xdg::BaseDirectories;
 
-use crate::{db::Database, routes::*, task_loop::Task};
+use crate::{
+    activity::ActivityLog,
+    config::ResolvedConfig,
+    db::Database,
+    health::SbotStatus,
+    routes::*,
+    sbot::{FollowCache, LikedPosts},
+    task_loop::{FetchProgressChannel, PendingFetches, Task, TaskLoopStatus},
+};
 
 #[launch]
 async fn rocket() -> _ {
-    // Create the key-value database.
-    let xdg_dirs = BaseDirectories::with_prefix("lykin").unwrap();
-    let db_path = xdg_dirs
-        .place_config_file("database")
-        .expect("cannot create database directory");
-    let db = Database::init(&db_path);
+    // Ensure the configured date format is valid before any posts are
+    // fetched.
+    if let Ok(date_format) = env::var("LYKIN_DATE_FORMAT") {
+        if let Err(e) = sbot::validate_date_format(&date_format) {
+            panic!("LYKIN_DATE_FORMAT is invalid: {}", e);
+        }
+        info!("Using date format {}", date_format);
+    }
+
+    // Ensure the configured subscribe behaviour is valid before any peers are
+    // subscribed to.
+    if let Ok(setting) = env::var("LYKIN_SUBSCRIBE_FETCH") {
+        if let Err(e) = sbot::parse_subscribe_fetch(&setting) {
+            panic!("LYKIN_SUBSCRIBE_FETCH is invalid: {}", e);
+        }
+        info!("Using subscribe fetch setting {}", setting);
+    }
+
+    // Ensure the configured webhook URL is valid before any posts are
+    // fetched.
+    if let Some(url) = webhook::webhook_url() {
+        if let Err(e) = webhook::validate_url(&url) {
+            panic!("LYKIN_WEBHOOK_URL is invalid: {}", e);
+        }
+        info!(
+            "Delivering notifications of new posts to {}",
+            webhook::redact_url(&url)
+        );
+    }
+
+    // Create the key-value database, at the path set by LYKIN_DB_PATH (e.g.
+    // for testing or for one database per account) or otherwise in the XDG
+    // config directory.
+    let db_path = match env::var("LYKIN_DB_PATH") {
+        Ok(path) if !path.trim().is_empty() => {
+            let db_path = PathBuf::from(path.trim());
+            if let Some(parent) = db_path.parent() {
+                fs::create_dir_all(parent).expect("cannot create database directory");
+            }
+            db_path
+        }
+        _ => {
+            let xdg_dirs = BaseDirectories::with_prefix("lykin").unwrap();
+            xdg_dirs
+                .place_config_file("database")
+                .expect("cannot create database directory")
+        }
+    };
+    info!("Using database at {}", db_path.display());
+
+    // The data of identities other than that of the local go-sbot is kept in
+    // database trees of their own, so that it does not leak between them.
+    // The identity is read from the key file, so that lykin starts even if
+    // the sbot cannot be reached.
+    let identity = match sbot::key_path() {
+        Some(key_path) => match sbot::key_file_identity(&key_path) {
+            Ok(identity) => {
+                info!("Using the database trees of identity {}", identity);
+                Some(identity)
+            }
+            Err(e) => panic!(
+                "Failed to determine the identity of GO_SBOT_KEY_PATH {}: {}",
+                key_path, e
+            ),
+        },
+        None => None,
+    };
+    let db = Database::init(&db_path, identity.as_deref());
+
+    // Report the resolved configuration, so that users can verify which
+    // settings took effect.
+    let config = ResolvedConfig::resolve(&db_path);
+    config.log();
+
     let db_clone = db.clone();
+    let db_history = db.clone();
 
     // Create a message passing channel.
     let (tx, rx) = channel::unbounded();
     let tx_clone = tx.clone();
 
-    // Spawn the task loop, passing in the receiver half of the channel.
+    // Create the activity log, the set of pending fetches and the fetch
+    // progress channel, which are shared by the task loop and the route
+    // handlers.
+    let activity = ActivityLog::default();
+    let pending_fetches = PendingFetches::default();
+    let fetch_progress = FetchProgressChannel::default();
+
+    // Spawn the task loop, passing in the receiver half of the channel. The
+    // status of the loop is reported by the readiness probe.
     info!("Spawning task loop");
-    task_loop::spawn(db_clone, rx).await;
+    let task_loop_status = TaskLoopStatus::default();
+    task_loop::spawn(
+        db_clone,
+        rx,
+        activity.clone(),
+        pending_fetches.clone(),
+        fetch_progress.clone(),
+        task_loop_status.clone(),
+    )
+    .await;
+
+    // Spawn the sbot health checker, which caches the connection status for
+    // the route handlers.
+    let sbot_status = SbotStatus::default();
+    info!("Spawning sbot health checker");
+    health::spawn(sbot_status.clone(), health::check_interval()).await;
+
+    // Spawn the unread count sampler, which records the unread history.
+    info!("Spawning unread count sampler");
+    history::spawn(db_history, history::sample_interval()).await;
 
     rocket::build()
         .manage(db)
         .manage(tx)
+        .manage(sbot_status)
+        .manage(FollowCache::default())
+        .manage(LikedPosts::default())
+        .manage(activity)
+        .manage(config)
+        .manage(pending_fetches)
+        .manage(fetch_progress)
+        .manage(task_loop_status)
         .attach(Template::fairing())
         .mount(
             "/",
             routes![
                 home,
                 subscribe_form,
+                subscribe_invite_form,
                 unsubscribe_form,
+                archive_form,
+                block_form,
+                unblock_form,
+                archived,
+                merge_form,
+                reindex,
                 download_latest_posts,
+                refresh_peer,
+                refresh_peer_posts,
+                cancel_peer_fetch,
+                refresh_names,
+                refresh_private_messages,
+                peer_notes,
+                prune_peer,
                 post,
+                timeline,
+                threads,
+                recent_activity,
+                activity_stream,
+                progress_stream,
+                raw_post,
                 posts,
+                peek,
+                fetch_message,
+                trending,
+                channel_posts,
+                prolific,
+                compare,
+                api_unread,
+                api_unread_history,
+                api_recently_read,
+                api_private_messages,
+                api_peers,
+                api_posts,
+                status,
+                configuration,
+                liveness,
+                sbot_health,
+                readiness,
+                stats,
+                version,
+                whoami,
+                search,
+                peer_search,
+                export_opml,
+                import_opml,
+                export_peer,
+                import,
+                feed,
+                mark_all_posts_read,
+                delete_read_posts,
+                blob,
+                like_post,
+                publish,
                 mark_post_read,
+                mark_post_read_beacon,
+                mark_post_read_and_next,
                 mark_post_unread,
-                delete_post
+                delete_post,
+                undelete_post,
+                bulk_update_posts
             ],
         )
         .mount("/", FileServer::from(relative!("static")))
@@ -0,0 +1,215 @@
+//! Decryption of private messages, which are encrypted with the private-box
+//! format of Scuttlebutt.
+//!
+//! A boxed message is made up of a nonce, a one-time public key, a header
+//! slot for each recipient (holding the key of the body, encrypted for that
+//! recipient) and the encrypted body. Every part is encrypted with
+//! `crypto_secretbox` (XSalsa20-Poly1305), whose authentication tag precedes
+//! the ciphertext.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use crypto_secretbox::{AeadInPlace, Key, KeyInit, Nonce, Tag, XSalsa20Poly1305};
+use serde_json::Value;
+use sha2::{Digest, Sha512};
+
+use crate::sbot;
+
+/// The suffix of the content of a private message.
+const BOX_SUFFIX: &str = ".box";
+
+/// The maximum number of recipients of a private message.
+const MAX_RECIPIENTS: usize = 7;
+
+const NONCE_LENGTH: usize = 24;
+const PUBLIC_KEY_LENGTH: usize = 32;
+const TAG_LENGTH: usize = 16;
+
+/// The length of a header slot: the tag, the number of recipients and the
+/// key of the body.
+const SLOT_LENGTH: usize = TAG_LENGTH + 1 + 32;
+
+/// Read the secret key file (as written by go-sbot or patchwork) at the given
+/// path and return the Curve25519 secret key derived from its Ed25519 key.
+pub fn read_secret_key(path: &str) -> Result<[u8; 32], String> {
+    let secret = sbot::read_key_file(path)?;
+    let private_key = secret
+        .get("private")
+        .and_then(Value::as_str)
+        .ok_or_else(|| format!("Secret key file {} holds no private key", path))?;
+
+    curve_secret_key(private_key)
+}
+
+/// Derive the Curve25519 secret key from the given Ed25519 private key
+/// (e.g. `base64.ed25519`), as `crypto_sign_ed25519_sk_to_curve25519` does:
+/// the scalar is the first half of the SHA-512 hash of the seed. Clamping is
+/// left to the scalar multiplication.
+fn curve_secret_key(private_key: &str) -> Result<[u8; 32], String> {
+    let key_bytes = STANDARD
+        .decode(private_key.trim_end_matches(".ed25519"))
+        .map_err(|e| format!("Failed to decode private key: {}", e))?;
+    // The private key is the seed followed by the public key.
+    if key_bytes.len() != 64 {
+        return Err(format!(
+            "Private key has {} bytes, expected 64",
+            key_bytes.len()
+        ));
+    }
+
+    let hash = Sha512::digest(&key_bytes[..32]);
+    let mut secret_key = [0; 32];
+    secret_key.copy_from_slice(&hash[..32]);
+
+    Ok(secret_key)
+}
+
+/// Decrypt the given private message content with the given Curve25519
+/// secret key, returning the decrypted content.
+///
+/// `None` is returned if the content is malformed or was not encrypted for
+/// the given key.
+pub fn unbox(content: &str, secret_key: &[u8; 32]) -> Option<Value> {
+    let boxed = STANDARD.decode(content.strip_suffix(BOX_SUFFIX)?).ok()?;
+    if boxed.len() < NONCE_LENGTH + PUBLIC_KEY_LENGTH {
+        return None;
+    }
+    let (nonce, rest) = boxed.split_at(NONCE_LENGTH);
+    let (one_time_key, slots) = rest.split_at(PUBLIC_KEY_LENGTH);
+
+    let mut one_time_key_bytes = [0; 32];
+    one_time_key_bytes.copy_from_slice(one_time_key);
+    let header_key = x25519_dalek::x25519(*secret_key, one_time_key_bytes);
+
+    // Try each header slot in turn, since the position of our slot is not
+    // known.
+    let slot = slots
+        .chunks_exact(SLOT_LENGTH)
+        .take(MAX_RECIPIENTS)
+        .find_map(|slot| open(slot, nonce, &header_key))?;
+    let (&recipients, body_key) = slot.split_first()?;
+    let body_key: [u8; 32] = body_key.try_into().ok()?;
+
+    let body = slots.get(recipients as usize * SLOT_LENGTH..)?;
+    let plaintext = open(body, nonce, &body_key)?;
+
+    serde_json::from_slice(&plaintext).ok()
+}
+
+/// Open the given secret box, made up of the tag followed by the ciphertext,
+/// with the given nonce and key.
+fn open(sealed: &[u8], nonce: &[u8], key: &[u8; 32]) -> Option<Vec<u8>> {
+    if sealed.len() < TAG_LENGTH {
+        return None;
+    }
+    let (tag, ciphertext) = sealed.split_at(TAG_LENGTH);
+    let mut buffer = ciphertext.to_vec();
+
+    XSalsa20Poly1305::new(Key::from_slice(key))
+        .decrypt_in_place_detached(
+            Nonce::from_slice(nonce),
+            b"",
+            &mut buffer,
+            Tag::from_slice(tag),
+        )
+        .ok()?;
+
+    Some(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    const PRIVATE_KEY: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8AAQIDBAUGBwgJCgsMDQ4PEBESExQVFhcYGRobHB0eHw==.ed25519";
+
+    /// The Ed25519 key pair of RFC 8032 (test 1), as written to a secret key
+    /// file.
+    const RFC_8032_PRIVATE_KEY: &str = "nWGxne/9WmC6hEr0kuwsxERJxWl7MmkZcDusAxyuf2DXWpgBgrEKt9VL/tPJZAc6DuFy89qmIyWvAhpo9wdRGg==.ed25519";
+
+    /// The Curve25519 secret key which libsodium derives from the key pair
+    /// above (`crypto_sign_ed25519_sk_to_curve25519`).
+    const RFC_8032_CURVE_SECRET_KEY: [u8; 32] = [
+        48, 124, 131, 134, 79, 40, 51, 203, 66, 122, 46, 241, 192, 10, 1, 60, 253, 255, 39, 104,
+        217, 128, 192, 163, 165, 32, 240, 6, 144, 77, 233, 79,
+    ];
+
+    /// A message boxed with libsodium as private-box's `multibox` does, for
+    /// a random recipient and then for the key pair above.
+    const LIBSODIUM_BOX: &str = "BVJquk4rBUWo8Er0/LajHjTHhB5VyT6br1zUjU5OXYd8ufzDel3kJ8sH26HJP708NlV13psEYVdunvJ8QmMsuwwsu883r+N0qgheVQoZxrXp8gT7x+S8LI3YFrQp3tJo+bo/x7M0y0KVBQgtzx23ndm3eNyKf6nwXFywrG9ZhVpp7V04EBL3pPD/bu1vU3WXLgtG8KpYb3rJn9QY4gvJQVBAbs/9avSITKO8/RTEBZbJ6fSh5/PTewlSLHqH6d6f1SbM1NUg1v/tv//ARL6xoYfeFi0y4ws=.box";
+
+    fn seal(plaintext: &[u8], nonce: &[u8; 24], key: &[u8; 32]) -> Vec<u8> {
+        let mut buffer = plaintext.to_vec();
+        let tag = XSalsa20Poly1305::new(Key::from_slice(key))
+            .encrypt_in_place_detached(Nonce::from_slice(nonce), b"", &mut buffer)
+            .unwrap();
+
+        [tag.as_slice(), &buffer].concat()
+    }
+
+    /// Box the given content for the recipients with the given Curve25519
+    /// public keys, as private-box does.
+    fn boxed(content: &Value, recipients: &[[u8; 32]]) -> String {
+        let nonce = [7; 24];
+        let body_key = [9; 32];
+        let one_time_secret = [5; 32];
+        let one_time_key =
+            x25519_dalek::x25519(one_time_secret, x25519_dalek::X25519_BASEPOINT_BYTES);
+
+        let mut slot = vec![recipients.len() as u8];
+        slot.extend_from_slice(&body_key);
+        let mut boxed = [nonce.as_slice(), &one_time_key].concat();
+        for recipient in recipients {
+            let header_key = x25519_dalek::x25519(one_time_secret, *recipient);
+            boxed.extend(seal(&slot, &nonce, &header_key));
+        }
+        boxed.extend(seal(content.to_string().as_bytes(), &nonce, &body_key));
+
+        format!("{}{}", STANDARD.encode(boxed), BOX_SUFFIX)
+    }
+
+    fn public_key(secret_key: &[u8; 32]) -> [u8; 32] {
+        x25519_dalek::x25519(*secret_key, x25519_dalek::X25519_BASEPOINT_BYTES)
+    }
+
+    #[test]
+    fn unbox_decrypts_messages_for_any_recipient_slot() {
+        let secret_key = curve_secret_key(PRIVATE_KEY).unwrap();
+        let other_key = public_key(&[3; 32]);
+        let content = json!({ "type": "post", "text": "hello" });
+
+        let message = boxed(&content, &[other_key, public_key(&secret_key)]);
+        assert_eq!(unbox(&message, &secret_key), Some(content));
+    }
+
+    #[test]
+    fn unbox_decrypts_messages_boxed_by_libsodium() {
+        let secret_key = curve_secret_key(RFC_8032_PRIVATE_KEY).unwrap();
+        // libsodium clamps the scalar, which is otherwise left to the scalar
+        // multiplication.
+        let mut clamped_key = secret_key;
+        clamped_key[0] &= 248;
+        clamped_key[31] &= 127;
+        clamped_key[31] |= 64;
+        assert_eq!(clamped_key, RFC_8032_CURVE_SECRET_KEY);
+
+        let content = json!({ "type": "post", "text": "hello from libsodium" });
+        assert_eq!(unbox(LIBSODIUM_BOX, &secret_key), Some(content.clone()));
+        assert_eq!(
+            unbox(LIBSODIUM_BOX, &RFC_8032_CURVE_SECRET_KEY),
+            Some(content)
+        );
+        assert_eq!(unbox(LIBSODIUM_BOX, &[3; 32]), None);
+    }
+
+    #[test]
+    fn unbox_skips_messages_for_other_recipients() {
+        let secret_key = curve_secret_key(PRIVATE_KEY).unwrap();
+        let content = json!({ "type": "post", "text": "hello" });
+
+        let message = boxed(&content, &[public_key(&[3; 32])]);
+        assert_eq!(unbox(&message, &secret_key), None);
+        assert_eq!(unbox("bm90IGEgYm94.box", &secret_key), None);
+    }
+}
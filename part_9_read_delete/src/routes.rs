@@ -1,242 +1,2579 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, BTreeSet},
+    env,
+    hash::{Hash, Hasher},
+    io::Cursor,
+};
+
 use async_std::channel::Sender;
-use log::{info, warn};
+use chrono::Utc;
+use log::{debug, info, warn};
 use rocket::{
+    data::{Data, Limits},
     form::Form,
-    get, post,
-    request::FlashMessage,
-    response::{Flash, Redirect},
-    uri, FromForm, State,
+    fs::TempFile,
+    get,
+    http::{ContentType, Status},
+    post,
+    request::{self, FlashMessage, FromRequest, Request},
+    response::{
+        self,
+        status::{BadRequest, Custom, NoContent},
+        stream::{Event, EventStream},
+        Flash, Redirect, Responder, Response,
+    },
+    tokio::{select, sync::broadcast::error::RecvError},
+    uri, Config, FromForm, Shutdown, State,
 };
 use rocket_dyn_templates::{context, Template};
+use serde::Serialize;
 
 use crate::{
-    db::{Database, Peer},
-    sbot,
-    task_loop::Task,
+    activity::ActivityLog,
+    config::ResolvedConfig,
+    db::{BulkAction, Database, Peer, PeerImport, Post, PostSummary, SortOrder},
+    health::SbotStatus,
+    sbot::{self, FollowCache, LikedPosts, SubscribeFetch},
+    task_loop::{FetchProgressChannel, PendingFetches, Task, TaskLoopStatus},
     utils,
 };
 
+/// The default number of days considered when computing trending channels.
+const TRENDING_DAYS: i64 = 7;
+/// The maximum number of trending channels to display.
+const TRENDING_LIMIT: usize = 20;
+
+/// The default number of days without a stored post after which a peer is
+/// considered dormant in the ranking of prolific peers.
+const DORMANT_DAYS: i64 = 90;
+
+/// The maximum number of matching posts shown for a post search.
+const SEARCH_LIMIT: usize = 100;
+
+/// The maximum number of candidates returned by a peer search.
+const PEER_SEARCH_LIMIT: usize = 10;
+
+/// The default maximum number of items included in the feed.
+const DEFAULT_FEED_LIMIT: usize = 50;
+/// The RFC 822 date format used for the publication date of feed items.
+const FEED_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S +0000";
+
+/// The number of posts shown on each page of the post list of a peer.
+const POSTS_PAGE_LIMIT: usize = 50;
+
+/// The maximum length of the notes kept about a peer, in characters.
+const PEER_NOTES_MAX_LENGTH: usize = 2000;
+
+/// The default number of posts returned by the recently read API.
+const DEFAULT_RECENTLY_READ_LIMIT: usize = 50;
+
+/// The default number of recent events shown on the activity page.
+const DEFAULT_ACTIVITY_LIMIT: usize = 50;
+/// The format of the time at which each activity event occurred.
+const ACTIVITY_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// Return the maximum number of items included in the feed when no limit is
+/// given in the request.
+///
+/// The limit is read from the `LYKIN_FEED_LIMIT` environment variable,
+/// falling back to the default limit if the variable is unset or invalid.
+pub fn feed_limit() -> usize {
+    env::var("LYKIN_FEED_LIMIT")
+        .ok()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(DEFAULT_FEED_LIMIT)
+}
+
+/// An item of the feed, representing a single post.
+#[derive(Serialize)]
+struct FeedItem {
+    title: String,
+    link: String,
+    description: String,
+    guid: String,
+    pub_date: String,
+}
+
+/// A peer along with the number of their unread posts, as returned by the
+/// peers API.
+#[derive(Serialize)]
+struct PeerSummary {
+    #[serde(flatten)]
+    peer: Peer,
+    unread_count: usize,
+}
+
 #[derive(FromForm)]
 pub struct PeerForm {
     pub public_key: String,
 }
 
-#[get("/")]
-pub async fn home(db: &State<Database>, flash: Option<FlashMessage<'_>>) -> Template {
-    let peers = db.get_peers();
-    let mut peers_unread = Vec::new();
-    for peer in peers {
-        let unread_count = db.get_unread_post_count(&peer.public_key);
-        peers_unread.push((peer, unread_count.to_string()));
+#[derive(FromForm)]
+pub struct NotesForm {
+    pub notes: String,
+}
+
+#[derive(FromForm)]
+pub struct PruneForm {
+    /// The number of most recent posts to keep.
+    pub keep: usize,
+}
+
+#[derive(FromForm)]
+pub struct OpmlForm<'r> {
+    /// The uploaded OPML document.
+    pub opml: TempFile<'r>,
+}
+
+#[derive(FromForm)]
+pub struct InviteForm {
+    pub invite: String,
+}
+
+#[derive(FromForm)]
+pub struct PostForm {
+    pub text: String,
+}
+
+#[derive(FromForm)]
+pub struct MergeForm {
+    /// The public key under which the merged peer is stored.
+    pub canonical: String,
+    /// The public key of the duplicate peer, which is removed.
+    pub duplicate: String,
+}
+
+#[derive(FromForm)]
+pub struct BulkForm {
+    /// The action to apply: read, unread, delete, star or unstar.
+    pub action: String,
+    /// The message IDs of the selected posts.
+    pub msg_ids: Vec<String>,
+}
+
+/// The entity tag sent by the client in the `If-None-Match` header, if any.
+pub struct IfNoneMatch(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfNoneMatch {
+    type Error = ();
+
+    async fn from_request(req: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let etag = req.headers().get_one("If-None-Match").map(String::from);
+        request::Outcome::Success(IfNoneMatch(etag))
+    }
+}
+
+/// A JSON response body tagged with an entity tag derived from its content.
+///
+/// If the entity tag matches the one sent by the client, a `304 Not Modified`
+/// response without a body is returned instead.
+pub struct TaggedJson {
+    body: String,
+    etag: String,
+    not_modified: bool,
+}
+
+impl TaggedJson {
+    /// Tag the given JSON body and compare the tag with the one sent by the
+    /// client.
+    fn new(body: String, if_none_match: IfNoneMatch) -> TaggedJson {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = format!("\"{:x}\"", hasher.finish());
+        let not_modified = if_none_match.0.as_deref() == Some(etag.as_str());
+
+        TaggedJson {
+            body,
+            etag,
+            not_modified,
+        }
+    }
+}
+
+/// A response body which is served as a file attachment with the given
+/// filename, prompting the browser to download it.
+pub struct Download {
+    body: String,
+    content_type: ContentType,
+    filename: String,
+}
+
+impl<'r> Responder<'r, 'static> for Download {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(self.content_type)
+            .raw_header(
+                "Content-Disposition",
+                format!("attachment; filename=\"{}\"", self.filename),
+            )
+            .sized_body(self.body.len(), Cursor::new(self.body))
+            .ok()
+    }
+}
+
+impl<'r> Responder<'r, 'static> for TaggedJson {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let mut response = Response::build();
+        response.raw_header("ETag", self.etag);
+
+        if self.not_modified {
+            response.status(Status::NotModified);
+        } else {
+            response
+                .header(ContentType::JSON)
+                .sized_body(self.body.len(), Cursor::new(self.body));
+        }
+
+        response.ok()
+    }
+}
+
+/// Render the list of peers. With `?sort=inbox`, only peers with unread posts
+/// are listed, ordered by the arrival of their most recent unread post.
+#[get("/?<sort>")]
+pub async fn home(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    flash: Option<FlashMessage<'_>>,
+    sort: Option<&str>,
+) -> Template {
+    let peers = match sort {
+        Some("inbox") => db.with_unread_counts(
+            db.peers_by_unread_recency()
+                .into_iter()
+                .filter(|peer| !peer.archived)
+                .collect(),
+        ),
+        _ => db.get_peers_with_unread(),
+    };
+    // The total number of stored posts is shown along with the unread count.
+    let peers_unread: Vec<_> = peer_list_entries(peers)
+        .into_iter()
+        .map(|(peer, unread_count, color)| {
+            let post_count = db.get_post_count(&peer.public_key);
+            (peer, unread_count, color, post_count)
+        })
+        .collect();
+
+    let context = context! {
+        peers: &peers_unread,
+        total_unread: db.get_total_unread_count(),
+        flash: flash,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+/// Pair each of the given peers and their unread count with the color of the
+/// peer, as listed by the peer list template.
+fn peer_list_entries(peers: Vec<(Peer, usize)>) -> Vec<(Peer, String, String)> {
+    peers
+        .into_iter()
+        .map(|(peer, unread_count)| {
+            let color = utils::color_for_key(&peer.public_key);
+            (peer, unread_count.to_string(), color)
+        })
+        .collect()
+}
+
+/// Subscribe to the peer with the given (valid) public key: follow the peer,
+/// add them to the database and fetch all of their root posts.
+async fn subscribe_to_peer(
+    db: &Database,
+    tx: &Sender<Task>,
+    pending_fetches: &PendingFetches,
+    activity: &ActivityLog,
+    public_key: &str,
+) -> Result<(), String> {
+    // Retrieve the name of the peer to which we are subscribing.
+    let peer_name = match sbot::get_name(public_key).await {
+        Ok(name) => name,
+        Err(e) => {
+            warn!("Failed to fetch name for peer {}: {}", public_key, e);
+            // Return an empty string if an error occurs.
+            String::from("")
+        }
+    };
+    let peer_info = Peer::new(public_key).set_name(&peer_name);
+
+    // In read-only mode the peer is only recorded locally; no follow
+    // message is published.
+    if sbot::read_only() {
+        info!("Read-only mode: not following peer {}", public_key);
+    } else {
+        sbot::follow_if_not_following(public_key).await?;
+        activity.record(format!("Followed peer {}", public_key));
+    }
+
+    // Add the peer to the database.
+    if db.add_peer(peer_info).is_err() {
+        return Err(format!(
+            "Failed to add peer {} to 'peers' database tree",
+            public_key
+        ));
+    }
+    info!("Added {} to 'peers' database tree", public_key);
+    activity.record(format!("Subscribed to peer {}", public_key));
+
+    // Fetch the root posts authored by the peer we're subscribing to, as
+    // configured. Posts will be added to the key-value database.
+    let task = match sbot::subscribe_fetch() {
+        SubscribeFetch::Latest(limit) => Task::FetchRecentPosts(public_key.to_string(), limit),
+        SubscribeFetch::All => Task::FetchAllPosts(public_key.to_string()),
+    };
+    pending_fetches.insert(public_key);
+    if let Err(e) = tx.send(task).await {
+        warn!("Task loop error: {}", e);
+        pending_fetches.remove(public_key);
     }
 
-    Template::render("base", context! { peers: &peers_unread, flash: flash })
+    Ok(())
 }
 
 #[post("/subscribe", data = "<peer>")]
 pub async fn subscribe_form(
     db: &State<Database>,
     tx: &State<Sender<Task>>,
+    pending_fetches: &State<PendingFetches>,
+    activity: &State<ActivityLog>,
+    peer: Form<PeerForm>,
+) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(&peer.public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", &peer.public_key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    info!("Public key {} is valid", &peer.public_key);
+    if let Err(e) = subscribe_to_peer(db, tx, pending_fetches, activity, &peer.public_key).await {
+        warn!("{}", e);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), e));
+    }
+
+    Ok(Redirect::to(uri!(home(_))))
+}
+
+/// Accept an SSB invite to a pub and subscribe to the pub which issued it.
+#[post("/subscribe_invite", data = "<invite>")]
+pub async fn subscribe_invite_form(
+    db: &State<Database>,
+    tx: &State<Sender<Task>>,
+    pending_fetches: &State<PendingFetches>,
+    activity: &State<ActivityLog>,
+    invite: Form<InviteForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let parsed_invite = match utils::parse_invite(&invite.invite) {
+        Ok(parsed_invite) => parsed_invite,
+        Err(e) => {
+            let validation_err_msg = format!("Invite is invalid: {}", e);
+            warn!("{}", validation_err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(home(_))),
+                validation_err_msg,
+            ));
+        }
+    };
+    let public_key = parsed_invite.public_key;
+
+    // In read-only mode the invite is not used, since doing so publishes
+    // messages; the pub is only recorded locally.
+    if !sbot::read_only() {
+        if let Err(e) = sbot::use_invite(invite.invite.trim()).await {
+            let err_msg = format!(
+                "Failed to use invite for pub {} at {}:{}: {}",
+                &public_key, &parsed_invite.host, parsed_invite.port, e
+            );
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+        info!("Used invite for pub {}", &public_key);
+        activity.record(format!("Used invite for pub {}", &public_key));
+    }
+
+    if let Err(e) = subscribe_to_peer(db, tx, pending_fetches, activity, &public_key).await {
+        warn!("{}", e);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), e));
+    }
+
+    Ok(Flash::success(
+        Redirect::to(uri!(home(_))),
+        format!("Subscribed to pub {}", &public_key),
+    ))
+}
+
+/// Publish a post to the feed of the local sbot.
+#[post("/publish", data = "<post>")]
+pub async fn publish(
+    activity: &State<ActivityLog>,
+    post: Form<PostForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = utils::validate_post_text(&post.text) {
+        let validation_err_msg = format!("Post is invalid: {}", e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    match sbot::publish_post(&post.text).await {
+        Ok(msg_key) => {
+            info!("Published post {}", &msg_key);
+            activity.record(format!("Published post {}", &msg_key));
+            Ok(Flash::success(
+                Redirect::to(uri!(home(_))),
+                format!("Published post {}", msg_key),
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to publish post: {}", e);
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+    }
+}
+
+/// Unfollow a peer and remove the peer and their posts from the database.
+#[post("/unsubscribe", data = "<peer>")]
+pub async fn unsubscribe_form(
+    db: &State<Database>,
+    activity: &State<ActivityLog>,
     peer: Form<PeerForm>,
 ) -> Result<Redirect, Flash<Redirect>> {
     if let Err(e) = utils::validate_public_key(&peer.public_key) {
         let validation_err_msg = format!("Public key {} is invalid: {}", &peer.public_key, e);
         warn!("{}", validation_err_msg);
-        return Err(Flash::error(Redirect::to(uri!(home)), validation_err_msg));
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
     } else {
         info!("Public key {} is valid", &peer.public_key);
-        // Retrieve the name of the peer to which we are subscribing.
-        let peer_name = match sbot::get_name(&peer.public_key).await {
-            Ok(name) => name,
-            Err(e) => {
-                warn!("Failed to fetch name for peer {}: {}", &peer.public_key, e);
-                // Return an empty string if an error occurs.
-                String::from("")
-            }
-        };
-        let peer_info = Peer::new(&peer.public_key).set_name(&peer_name);
-
-        match sbot::follow_if_not_following(&peer.public_key).await {
+        match sbot::unfollow_if_following(&peer.public_key).await {
             Ok(_) => {
-                // Add the peer to the database.
-                if db.add_peer(peer_info).is_ok() {
-                    info!("Added {} to 'peers' database tree", &peer.public_key);
-                    let peer_id = peer.public_key.to_string();
-
-                    // Fetch all root posts authored by the peer we're subscribing
-                    // to. Posts will be added to the key-value database.
-                    if let Err(e) = tx.send(Task::FetchAllPosts(peer_id)).await {
-                        warn!("Task loop error: {}", e)
+                // Remove the peer from the database.
+                if db.remove_peer(&peer.public_key).is_ok() {
+                    info!(
+                        "Removed peer {} from 'peers' database tree",
+                        &peer.public_key
+                    );
+                    activity.record(format!("Unsubscribed from peer {}", &peer.public_key));
+
+                    // Purge the posts of the peer; archive the peer instead
+                    // to keep them.
+                    match db.remove_peer_posts(&peer.public_key) {
+                        Ok(removed) => {
+                            info!("Purged {} posts of peer {}", removed, &peer.public_key)
+                        }
+                        Err(e) => {
+                            warn!("Failed to purge posts of peer {}: {}", &peer.public_key, e)
+                        }
                     }
                 } else {
-                    let err_msg = format!(
-                        "Failed to add peer {} to 'peers' database tree",
+                    warn!(
+                        "Failed to remove peer {} from 'peers' database tree",
                         &peer.public_key
                     );
-                    warn!("{}", err_msg);
-                    return Err(Flash::error(Redirect::to(uri!(home)), err_msg));
                 }
             }
             Err(e) => {
                 warn!("{}", e);
-                return Err(Flash::error(Redirect::to(uri!(home)), e));
+                return Err(Flash::error(Redirect::to(uri!(home(_))), e));
             }
         }
     }
 
-    Ok(Redirect::to(uri!(home)))
+    Ok(Redirect::to(uri!(home(_))))
 }
 
-#[post("/unsubscribe", data = "<peer>")]
-pub async fn unsubscribe_form(
+/// Block a peer and remove the peer and their posts from the database.
+/// Blocking, unlike unfollowing, also stops the replication of the feed of
+/// the peer through other peers.
+#[post("/block", data = "<peer>")]
+pub async fn block_form(
+    db: &State<Database>,
+    activity: &State<ActivityLog>,
+    peer: Form<PeerForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(&peer.public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", &peer.public_key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    if let Err(e) = sbot::block_peer(&peer.public_key).await {
+        let err_msg = format!("Failed to block peer {}: {}", &peer.public_key, e);
+        warn!("{}", err_msg);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+    }
+    info!("Blocked peer {}", &peer.public_key);
+    activity.record(format!("Blocked peer {}", &peer.public_key));
+
+    if let Err(e) = db.remove_peer(&peer.public_key) {
+        warn!(
+            "Failed to remove peer {} from 'peers' database tree: {}",
+            &peer.public_key, e
+        );
+    }
+    match db.remove_peer_posts(&peer.public_key) {
+        Ok(removed) => info!("Removed {} posts of peer {}", removed, &peer.public_key),
+        Err(e) => warn!("Failed to remove posts of peer {}: {}", &peer.public_key, e),
+    }
+
+    Ok(Flash::success(
+        Redirect::to(uri!(home(_))),
+        format!("Blocked peer {}", &peer.public_key),
+    ))
+}
+
+/// Unblock a peer. The peer is not subscribed to again.
+#[post("/unblock", data = "<peer>")]
+pub async fn unblock_form(
+    activity: &State<ActivityLog>,
+    peer: Form<PeerForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(&peer.public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", &peer.public_key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    match sbot::unblock_peer(&peer.public_key).await {
+        Ok(_) => {
+            info!("Unblocked peer {}", &peer.public_key);
+            activity.record(format!("Unblocked peer {}", &peer.public_key));
+            Ok(Flash::success(
+                Redirect::to(uri!(home(_))),
+                format!("Unblocked peer {}", &peer.public_key),
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to unblock peer {}: {}", &peer.public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+    }
+}
+
+#[post("/archive", data = "<peer>")]
+pub async fn archive_form(
     db: &State<Database>,
+    activity: &State<ActivityLog>,
     peer: Form<PeerForm>,
 ) -> Result<Redirect, Flash<Redirect>> {
     if let Err(e) = utils::validate_public_key(&peer.public_key) {
         let validation_err_msg = format!("Public key {} is invalid: {}", &peer.public_key, e);
         warn!("{}", validation_err_msg);
-        return Err(Flash::error(Redirect::to(uri!(home)), validation_err_msg));
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
     } else {
         info!("Public key {} is valid", &peer.public_key);
+        if !matches!(db.get_peer(&peer.public_key), Ok(Some(_))) {
+            let err_msg = format!(
+                "Peer {} is not in the 'peers' database tree",
+                &peer.public_key
+            );
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+
         match sbot::unfollow_if_following(&peer.public_key).await {
             Ok(_) => {
-                // Remove the peer from the database.
-                if db.remove_peer(&peer.public_key).is_ok() {
+                // Mark the peer as archived, keeping their posts. The update
+                // is atomic, so that a fetch which completes meanwhile does
+                // not undo it.
+                let archived =
+                    db.update_peer(&peer.public_key, |peer_info| peer_info.set_archived(true));
+                if let Ok(Some(_)) = archived {
                     info!(
-                        "Removed peer {} from 'peers' database tree",
+                        "Archived peer {} in 'peers' database tree",
                         &peer.public_key
                     );
+                    activity.record(format!("Archived peer {}", &peer.public_key));
                 } else {
-                    warn!(
-                        "Failed to remove peer {} from 'peers' database tree",
+                    let err_msg = format!(
+                        "Failed to archive peer {} in 'peers' database tree",
                         &peer.public_key
                     );
+                    warn!("{}", err_msg);
+                    return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
                 }
             }
             Err(e) => {
                 warn!("{}", e);
-                return Err(Flash::error(Redirect::to(uri!(home)), e));
+                return Err(Flash::error(Redirect::to(uri!(home(_))), e));
             }
         }
     }
 
-    Ok(Redirect::to(uri!(home)))
+    Ok(Redirect::to(uri!(home(_))))
+}
+
+/// Merge a peer stored under a duplicate public key into the canonical peer.
+#[post("/admin/merge", data = "<merge>")]
+pub async fn merge_form(
+    db: &State<Database>,
+    merge: Form<MergeForm>,
+) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(&merge.canonical) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", &merge.canonical, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+    if merge.duplicate.is_empty() || merge.duplicate == merge.canonical {
+        let err_msg = format!(
+            "Duplicate public key must differ from the canonical public key {}",
+            &merge.canonical
+        );
+        warn!("{}", err_msg);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+    }
+
+    match db.merge_peers(&merge.canonical, &merge.duplicate) {
+        Ok(moved_posts) => {
+            info!(
+                "Merged peer {} into {}, moving {} posts",
+                &merge.duplicate, &merge.canonical, moved_posts
+            );
+            Ok(Redirect::to(uri!(posts(&merge.canonical, _, _, _))))
+        }
+        Err(e) => {
+            let err_msg = format!(
+                "Failed to merge peer {} into {}: {}",
+                &merge.duplicate, &merge.canonical, e
+            );
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+    }
+}
+
+/// Recompute the data derived from the stored posts, for example after the
+/// filter words or duplicate window have changed. The number of checked and
+/// updated posts and removed replies is returned as JSON.
+#[post("/admin/reindex")]
+pub async fn reindex(db: &State<Database>) -> Result<(ContentType, String), Custom<String>> {
+    match db.rebuild_indexes(&sbot::filter_words(), sbot::duplicate_window()) {
+        Ok(counts) => Ok((ContentType::JSON, serde_json::to_string(&counts).unwrap())),
+        Err(e) => {
+            let err_msg = format!("Failed to rebuild indexes: {}", e);
+            warn!("{}", err_msg);
+            Err(Custom(Status::InternalServerError, err_msg))
+        }
+    }
+}
+
+#[get("/archived")]
+pub async fn archived(db: &State<Database>, sbot_status: &State<SbotStatus>) -> Template {
+    let peers_unread = peer_list_entries(db.with_unread_counts(db.get_archived_peers()));
+
+    let context = context! {
+        peers: &peers_unread,
+        archived_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
 }
 
 #[get("/posts/download_latest")]
 pub async fn download_latest_posts(db: &State<Database>, tx: &State<Sender<Task>>) -> Redirect {
-    for peer in db.get_peers() {
+    // Archived peers are no longer followed and are therefore not refreshed.
+    let peer_ids: Vec<String> = db
+        .get_active_peers()
+        .into_iter()
+        .map(|peer| peer.public_key)
+        .collect();
+
+    for peer_id in &peer_ids {
         // Fetch the latest root posts authored by each peer we're
         // subscribed to. Posts will be added to the key-value database.
-        if let Err(e) = tx
-            .send(Task::FetchLatestPosts(peer.public_key.clone()))
-            .await
-        {
+        if let Err(e) = tx.send(Task::FetchLatestPosts(peer_id.clone())).await {
             warn!("Task loop error: {}", e)
         }
+    }
 
-        // Fetch the latest name for each peer we're subscribed to and update
-        // the database.
-        if let Err(e) = tx.send(Task::FetchLatestName(peer.public_key)).await {
-            warn!("Task loop error: {}", e)
-        }
+    // Fetch the latest names, descriptions and profile images of all peers
+    // we're subscribed to (with the names in a single batch) and update the
+    // database.
+    if let Err(e) = tx.send(Task::FetchLatestNames(peer_ids)).await {
+        warn!("Task loop error: {}", e)
     }
 
-    Redirect::to(uri!(home))
+    Redirect::to(uri!(home(_)))
 }
 
-#[get("/posts/<public_key>")]
-pub async fn posts(db: &State<Database>, public_key: &str) -> Template {
-    let peers = db.get_peers();
-    let mut peers_unread = Vec::new();
-    for peer in peers {
-        let unread_count = db.get_unread_post_count(&peer.public_key);
-        peers_unread.push((peer, unread_count.to_string()));
+/// Fetch the names of all peers in the database, e.g. to fill in the names of
+/// peers imported in bulk.
+#[get("/peers/refresh_names")]
+pub async fn refresh_names(tx: &State<Sender<Task>>) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = tx.send(Task::FetchAllNames).await {
+        let err_msg = format!("Task loop error: {}", e);
+        warn!("{}", err_msg);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
     }
 
-    let posts = db.get_posts(public_key).unwrap();
+    Ok(Flash::success(
+        Redirect::to(uri!(home(_))),
+        "Refreshing the names of all peers",
+    ))
+}
 
-    // Define context data to be rendered in the template.
-    let context = context! {
-        selected_peer: &public_key,
-        peers: &peers_unread,
-        posts: &posts
-    };
+/// Fetch and decrypt the private messages (DMs) which we can read.
+#[get("/private/refresh")]
+pub async fn refresh_private_messages(
+    tx: &State<Sender<Task>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = tx.send(Task::FetchPrivateMessages).await {
+        let err_msg = format!("Task loop error: {}", e);
+        warn!("{}", err_msg);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+    }
 
-    Template::render("base", context)
+    Ok(Flash::success(
+        Redirect::to(uri!(home(_))),
+        "Fetching private messages",
+    ))
 }
 
-#[get("/posts/<public_key>/<msg_id>")]
-pub async fn post(db: &State<Database>, public_key: &str, msg_id: &str) -> Template {
-    let peers = db.get_peers();
-    let mut peers_unread = Vec::new();
-    for peer in peers {
-        let unread_count = db.get_unread_post_count(&peer.public_key);
-        peers_unread.push((peer, unread_count.to_string()));
+/// Fetch the latest posts, name and description of a single peer, as a
+/// focused alternative to downloading the latest posts of all peers.
+#[get("/peers/<public_key>/refresh")]
+pub async fn refresh_peer(
+    db: &State<Database>,
+    tx: &State<Sender<Task>>,
+    public_key: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match db.get_peer(public_key) {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            let err_msg = format!("Peer {} is not in the database", public_key);
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to retrieve peer {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
     }
 
-    let posts = db.get_posts(public_key).unwrap();
-    let post = db.get_post(public_key, msg_id).unwrap();
-
-    let context = context! {
-        peers: &peers_unread,
-        selected_peer: &public_key,
-        selected_post: &msg_id,
-        posts: &posts,
-        post: &post,
-        post_is_selected: &true
-    };
+    for task in [
+        Task::FetchLatestPosts(public_key.to_string()),
+        Task::FetchLatestName(public_key.to_string()),
+    ] {
+        if let Err(e) = tx.send(task).await {
+            let err_msg = format!("Task loop error: {}", e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+    }
 
-    Template::render("base", context)
+    Ok(Flash::success(
+        Redirect::to(uri!(posts(public_key, _, _, _))),
+        "Refreshing posts and name",
+    ))
 }
 
-#[get("/posts/<public_key>/<msg_id>/read")]
-pub async fn mark_post_read(db: &State<Database>, public_key: &str, msg_id: &str) -> Redirect {
-    // Retrieve the post from the database using the public key and msg_id
-    // from the URL.
-    if let Ok(Some(mut post)) = db.get_post(public_key, msg_id) {
-        // Mark the post as read.
-        post.read = true;
-        // Reinsert the modified post into the database.
-        db.add_post(public_key, post).unwrap();
-    } else {
-        warn!(
-            "Failed to find post {} authored by {} in 'posts' database tree",
-            msg_id, public_key
-        )
+/// Refresh a single peer from their posts view. The public key is validated
+/// and the request is then handled by `refresh_peer`.
+#[get("/posts/<public_key>/refresh")]
+pub async fn refresh_peer_posts(public_key: &str) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", public_key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
     }
 
-    Redirect::to(uri!(post(public_key, msg_id)))
+    Ok(Redirect::to(uri!(refresh_peer(public_key))))
 }
 
-#[get("/posts/<public_key>/<msg_id>/unread")]
-pub async fn mark_post_unread(db: &State<Database>, public_key: &str, msg_id: &str) -> Redirect {
-    if let Ok(Some(mut post)) = db.get_post(public_key, msg_id) {
-        post.read = false;
-        db.add_post(public_key, post).unwrap();
-    } else {
-        warn!(
-            "Failed to find post {} authored by {} in 'posts' database tree",
-            msg_id, public_key
-        )
+/// Abort the fetch of all posts of a single peer, e.g. one which was started
+/// by subscribing to a peer with a long feed. The posts fetched so far are
+/// kept.
+#[get("/peers/<public_key>/cancel_fetch")]
+pub async fn cancel_peer_fetch(
+    tx: &State<Sender<Task>>,
+    public_key: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", public_key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    if let Err(e) = tx.send(Task::CancelPeer(public_key.to_string())).await {
+        let err_msg = format!("Task loop error: {}", e);
+        warn!("{}", err_msg);
+        return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
     }
 
-    Redirect::to(uri!(post(public_key, msg_id)))
+    Ok(Flash::success(
+        Redirect::to(uri!(posts(public_key, _, _, _))),
+        "Cancelling the fetch of posts",
+    ))
 }
 
-#[get("/posts/<public_key>/<msg_id>/delete")]
-pub async fn delete_post(db: &State<Database>, public_key: &str, msg_id: &str) -> Redirect {
-    // Delete the post from the database.
-    match db.remove_post(public_key, msg_id) {
-        Ok(_) => info!(
-            "Removed post {} by {} from 'posts' database tree",
-            msg_id, public_key
-        ),
-        Err(e) => warn!(
-            "Failed to remove post {} by {} from 'posts' database tree: {}",
-            msg_id, public_key, e
-        ),
+/// Enqueue a fetch of the latest posts of the given peer if they were last
+/// fetched longer ago than the configured threshold, unless a fetch for the
+/// peer is already pending. Returns `true` if a fetch is pending.
+async fn refresh_if_stale(
+    db: &Database,
+    tx: &Sender<Task>,
+    pending_fetches: &PendingFetches,
+    public_key: &str,
+) -> bool {
+    let peer = match db.get_peer(public_key) {
+        Ok(Some(peer)) => peer,
+        _ => return false,
+    };
+    if Utc::now().timestamp() - peer.last_fetched < sbot::refresh_stale_after() {
+        return false;
+    }
+    if !pending_fetches.insert(public_key) {
+        debug!("Fetch for peer {} is already pending", public_key);
+        return true;
     }
 
-    Redirect::to(uri!(posts(public_key)))
+    info!("Posts of peer {} are stale; refreshing", public_key);
+    if let Err(e) = tx
+        .send(Task::FetchLatestPosts(public_key.to_string()))
+        .await
+    {
+        warn!("Task loop error: {}", e);
+        pending_fetches.remove(public_key);
+        return false;
+    }
+
+    true
+}
+
+/// Remove filtered posts from the given list of posts, unless they are to be
+/// shown.
+fn visible_posts(posts: Vec<PostSummary>, show_filtered: bool) -> Vec<PostSummary> {
+    posts
+        .into_iter()
+        .filter(|post| show_filtered || !post.filtered)
+        .collect()
+}
+
+/// Render a page of the posts of a peer, most recent first unless oldest first
+/// is selected (`sort=oldest`). The first page is shown if no page is given.
+///
+/// Hidden filtered posts are removed from the page after it is retrieved, so
+/// a page may show fewer posts than the page limit.
+#[get("/posts/<public_key>?<show_filtered>&<page>&<sort>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn posts(
+    db: &State<Database>,
+    tx: &State<Sender<Task>>,
+    pending_fetches: &State<PendingFetches>,
+    sbot_status: &State<SbotStatus>,
+    flash: Option<FlashMessage<'_>>,
+    public_key: &str,
+    show_filtered: Option<bool>,
+    page: Option<usize>,
+    sort: Option<&str>,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let show_filtered = show_filtered.unwrap_or(false);
+    let page = page.unwrap_or(0);
+    let limit = POSTS_PAGE_LIMIT;
+    let offset = page.saturating_mul(limit);
+    let order = SortOrder::from_query(sort);
+
+    // Retrieve one more post than is shown to learn whether there is a next
+    // page.
+    let mut posts = db
+        .get_posts_paged(public_key, order, offset, limit + 1)
+        .unwrap_or_else(|e| {
+            warn!("Failed to retrieve posts for {}: {}", public_key, e);
+            Vec::new()
+        });
+    let has_next_page = posts.len() > limit;
+    posts.truncate(limit);
+    posts.retain(|post| show_filtered || !post.filtered);
+
+    let peer = db.get_peer(public_key).ok().flatten();
+    let peer_notes = peer.as_ref().map(|peer| peer.notes.clone());
+    let peer_description = peer.map(|peer| peer.description);
+
+    // The stored posts are rendered without waiting for the refresh.
+    let refreshing =
+        sbot::refresh_on_view() && refresh_if_stale(db, tx, pending_fetches, public_key).await;
+
+    // Define context data to be rendered in the template.
+    let context = context! {
+        selected_peer: &public_key,
+        peer_notes: &peer_notes,
+        peer_description: &peer_description,
+        peers: &peers_unread,
+        posts: &posts,
+        page: &page,
+        offset: &offset,
+        limit: &limit,
+        has_next_page: &has_next_page,
+        sort: order.as_query(),
+        show_filtered: &show_filtered,
+        flash: flash,
+        refreshing: &refreshing,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+/// Save the local notes about a peer. The notes are never published.
+#[post("/peers/<public_key>/notes", data = "<notes>")]
+pub async fn peer_notes(
+    db: &State<Database>,
+    public_key: &str,
+    notes: Form<NotesForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let notes = notes.notes.trim();
+    if notes.chars().count() > PEER_NOTES_MAX_LENGTH {
+        let err_msg = format!(
+            "Notes must not be longer than {} characters",
+            PEER_NOTES_MAX_LENGTH
+        );
+        warn!("{}", err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(posts(public_key, _, _, _))),
+            err_msg,
+        ));
+    }
+
+    match db.set_peer_notes(public_key, notes) {
+        Ok(true) => Ok(Flash::success(
+            Redirect::to(uri!(posts(public_key, _, _, _))),
+            "Saved notes",
+        )),
+        Ok(false) => {
+            let err_msg = format!("Peer {} is not in the 'peers' database tree", public_key);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to save notes for peer {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+/// Remove all but the given number of most recent posts of a peer, without
+/// unsubscribing. Unread and starred posts are kept.
+#[post("/peers/<public_key>/prune", data = "<prune>")]
+pub async fn prune_peer(
+    db: &State<Database>,
+    activity: &State<ActivityLog>,
+    public_key: &str,
+    prune: Form<PruneForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match db.prune_peer_posts(public_key, prune.keep) {
+        Ok(removed) => {
+            info!("Pruned {} posts of peer {}", removed, public_key);
+            activity.record(format!("Pruned {} posts of peer {}", removed, public_key));
+            Ok(Flash::success(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                format!("Removed {} posts", removed),
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to prune posts of peer {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+// Ranked below routes such as `mark_all_read` which share its path shape.
+#[get("/posts/<public_key>/<msg_id>?<show_filtered>", rank = 2)]
+pub async fn post(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    liked_posts: &State<LikedPosts>,
+    flash: Option<FlashMessage<'_>>,
+    public_key: &str,
+    msg_id: &str,
+    show_filtered: Option<bool>,
+) -> Result<Template, Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    // Mark the post as read before the unread counts are computed, so that
+    // they already reflect the opened post.
+    if sbot::auto_read_on_open() {
+        if let Err(e) = db.update_post_read(public_key, msg_id, true) {
+            warn!(
+                "Failed to mark post {} authored by {} as read: {}",
+                msg_id, public_key, e
+            );
+        }
+    }
+
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let show_filtered = show_filtered.unwrap_or(false);
+    let posts = visible_posts(db.get_post_summaries(public_key).unwrap(), show_filtered);
+    let post = db.get_post(public_key, msg_id).unwrap();
+
+    // Fetch the replies to the post from the sbot, if it is reachable, and
+    // show those stored so far.
+    if post.is_some() && !sbot_status.is_down() {
+        match sbot::get_thread_replies(msg_id).await {
+            Ok(replies) => {
+                let comments: Vec<(String, Post)> = replies
+                    .into_iter()
+                    .map(|reply| (msg_id.to_string(), reply))
+                    .collect();
+                if let Err(e) = db.add_comment_batch(&comments) {
+                    warn!("Failed to insert comments to post {}: {}", msg_id, e)
+                }
+            }
+            Err(e) => warn!("Failed to fetch replies to post {}: {}", msg_id, e),
+        }
+    }
+    let comments = db.get_comments(msg_id).unwrap_or_else(|e| {
+        warn!("Failed to retrieve comments to post {}: {}", msg_id, e);
+        Vec::new()
+    });
+
+    // Look up the message quoted by the post, if any. Only stored posts and
+    // replies can be shown, since a message reference does not identify the
+    // feed from which the message could be fetched.
+    let quoted_post = match post.as_ref().and_then(|post| post.quotes.as_deref()) {
+        Some(quoted_key) => db.get_post_by_ref(quoted_key).unwrap_or_else(|e| {
+            warn!("Failed to look up quoted post {}: {}", quoted_key, e);
+            None
+        }),
+        None => None,
+    };
+    // A post which mentions an earlier post of its own author refers back to
+    // it rather than quoting it, so it is not shown inline.
+    let quotes_own_post = matches!(
+        (&post, &quoted_post),
+        (Some(post), Some(quoted_post)) if quoted_post.author == post.author
+    );
+
+    // Our own likes are looked up by our public key, which is only known
+    // while the sbot is reachable.
+    let liked = if liked_posts.contains(msg_id) {
+        true
+    } else if sbot_status.is_down() {
+        false
+    } else {
+        match sbot::whoami().await {
+            Ok(own_key) => db.is_liked_by(msg_id, &own_key).unwrap_or(false),
+            Err(_) => false,
+        }
+    };
+
+    let context = context! {
+        peers: &peers_unread,
+        selected_peer: &public_key,
+        selected_post: &msg_id,
+        posts: &posts,
+        post: &post,
+        quoted_post: &quoted_post,
+        quotes_own_post: &quotes_own_post,
+        comments: &comments,
+        liked: &liked,
+        post_is_selected: &true,
+        show_filtered: &show_filtered,
+        flash: flash,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Ok(Template::render("base", context))
+}
+
+/// Render the root posts of a peer with the replies of the peer to each of
+/// them, as collapsible threads.
+#[get("/threads/<public_key>")]
+pub async fn threads(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    public_key: &str,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let threads = db.get_threads(public_key).unwrap_or_else(|e| {
+        warn!("Failed to retrieve threads for {}: {}", public_key, e);
+        Vec::new()
+    });
+
+    let context = context! {
+        selected_peer: &public_key,
+        peers: &peers_unread,
+        threads: &threads,
+        threads_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+/// Render the most recent activity events, such as fetches and follows.
+#[get("/activity?<limit>")]
+pub async fn recent_activity(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    activity: &State<ActivityLog>,
+    limit: Option<usize>,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let events: Vec<(String, String)> = activity
+        .recent(limit.unwrap_or(DEFAULT_ACTIVITY_LIMIT))
+        .into_iter()
+        .map(|event| {
+            (
+                sbot::format_date(event.timestamp, ACTIVITY_DATE_FORMAT),
+                event.message,
+            )
+        })
+        .collect();
+
+    let context = context! {
+        peers: &peers_unread,
+        events: &events,
+        activity_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+/// Stream new activity events as server-sent events, each carrying the
+/// event as JSON. The stream ends when the server shuts down.
+#[get("/activity/stream")]
+pub async fn activity_stream(
+    activity: &State<ActivityLog>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = activity.subscribe();
+
+    EventStream! {
+        loop {
+            let event = select! {
+                event = receiver.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Closed) => break,
+                    // Events missed by a slow client are skipped.
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            yield Event::data(serde_json::to_string(&event).unwrap());
+        }
+    }
+}
+
+/// Stream the progress of fetches of posts as server-sent events, each
+/// carrying the peer, the number of posts processed so far and the latest
+/// sequence number as JSON. The stream ends when the server shuts down.
+#[get("/progress")]
+pub async fn progress_stream(
+    progress: &State<FetchProgressChannel>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut receiver = progress.subscribe();
+
+    EventStream! {
+        loop {
+            let update = select! {
+                update = receiver.recv() => match update {
+                    Ok(update) => update,
+                    Err(RecvError::Closed) => break,
+                    // Updates missed by a slow client are skipped.
+                    Err(RecvError::Lagged(_)) => continue,
+                },
+                _ = &mut shutdown => break,
+            };
+
+            yield Event::data(serde_json::to_string(&update).unwrap());
+        }
+    }
+}
+
+/// Render the post at the given index of the posting history of a peer,
+/// ordered from oldest to most recent, with links to the previous and next
+/// posts. An out-of-range index renders the end of the history.
+#[get("/posts/<public_key>/timeline/<index>", rank = 2)]
+pub async fn timeline(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    public_key: &str,
+    index: usize,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    // The summaries list every post of the peer, so only the post at the
+    // given index is read in full.
+    let posts = db.get_post_summaries(public_key).unwrap();
+    let history_len = posts.len();
+
+    // Step back to the last post of the history if the index is out of range.
+    let prev_index = if index > 0 && history_len > 0 {
+        Some((index - 1).min(history_len - 1))
+    } else {
+        None
+    };
+    let next_index = index.checked_add(1).filter(|next| *next < history_len);
+    let post = db
+        .get_posts_paged(public_key, SortOrder::Ascending, index, 1)
+        .unwrap()
+        .pop();
+    let selected_post = post.as_ref().map(|post| post.key.clone());
+
+    let context = context! {
+        peers: &peers_unread,
+        selected_peer: &public_key,
+        selected_post: &selected_post,
+        posts: &posts,
+        post: &post,
+        post_is_selected: post.is_some(),
+        timeline_is_selected: &true,
+        timeline_position: index.saturating_add(1),
+        timeline_len: history_len,
+        timeline_prev: prev_index,
+        timeline_next: next_index,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+#[get("/fetch_message?<key>&<author>")]
+pub async fn fetch_message(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    key: &str,
+    author: &str,
+) -> Result<Template, Flash<Redirect>> {
+    if let Err(e) = utils::validate_public_key(author) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", author, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+    if let Err(e) = utils::validate_message_id(key) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", key, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    // Search the feed of the author for the message without storing it or
+    // following the author.
+    let post = match sbot::get_post(author, key).await {
+        Ok(Some(post)) => post,
+        Ok(None) => {
+            let err_msg = format!(
+                "Post {} by {} was not found; the feed of the author may not be replicated",
+                key, author
+            );
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to fetch post {} by {}: {}", key, author, e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+    };
+
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    Ok(Template::render(
+        "base",
+        context! { peers: &peers_unread, post: &post, sbot_down: sbot_status.is_down() },
+    ))
+}
+
+/// Report the status of lykin as JSON: the reachability of the sbot, as
+/// determined by the most recent health check, and whether read-only mode is
+/// enabled.
+#[get("/status")]
+pub async fn status(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+) -> (ContentType, String) {
+    let status = serde_json::json!({
+        "sbot": sbot_status.label(),
+        "read_only": sbot::read_only(),
+        "posts": db.get_all_posts_count(),
+        "version": version_info(),
+    });
+
+    (ContentType::JSON, status.to_string())
+}
+
+/// Liveness probe: respond as long as the process is running.
+#[get("/health")]
+pub async fn liveness() -> &'static str {
+    "ok"
+}
+
+/// Respond with `200 OK` if the sbot responds to a `whoami` call, or
+/// `503 Service Unavailable` and a diagnostic message otherwise. Unlike the
+/// cached status reported by `/status`, the sbot is checked on each request.
+#[get("/health/sbot")]
+pub async fn sbot_health() -> (Status, String) {
+    match sbot::health_check().await {
+        Ok(_) => (Status::Ok, "ok".to_string()),
+        Err(e) => {
+            warn!("{}", e);
+            (Status::ServiceUnavailable, e)
+        }
+    }
+}
+
+/// Readiness probe: respond with `200 OK` once the task loop is running, or
+/// `503 Service Unavailable` otherwise. The database is opened before the
+/// server starts (lykin exits if it cannot be opened), so it is always ready
+/// by the time this is called.
+#[get("/ready")]
+pub async fn readiness(task_loop_status: &State<TaskLoopStatus>) -> (Status, &'static str) {
+    if task_loop_status.is_running() {
+        (Status::Ok, "ready")
+    } else {
+        (Status::ServiceUnavailable, "task loop is not running")
+    }
+}
+
+/// Return the configuration resolved at startup as JSON. Secrets are
+/// redacted.
+#[get("/config")]
+pub async fn configuration(config: &State<ResolvedConfig>) -> (ContentType, String) {
+    let config = serde_json::to_string(config.inner()).unwrap_or_default();
+
+    (ContentType::JSON, config)
+}
+
+/// Return the number of peers, root posts and unread posts as JSON.
+#[get("/stats")]
+pub async fn stats(db: &State<Database>) -> (ContentType, String) {
+    let stats = serde_json::json!({
+        "peers": db.get_peers().len(),
+        "posts": db.get_all_posts_count(),
+        "unread": db.get_total_unread_count(),
+    });
+
+    (ContentType::JSON, stats.to_string())
+}
+
+/// Return the version of the running build, along with the git commit and
+/// the timestamp (in seconds since the Unix epoch) captured by the build
+/// script.
+fn version_info() -> serde_json::Value {
+    serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("LYKIN_GIT_COMMIT"),
+        "build_timestamp": env!("LYKIN_BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0),
+    })
+}
+
+/// Return the public key of the identity with which lykin connects to the
+/// sbot as JSON, or a 503 status if the sbot cannot be reached.
+#[get("/whoami")]
+pub async fn whoami() -> (Status, (ContentType, String)) {
+    match sbot::whoami().await {
+        Ok(public_key) => {
+            let body = serde_json::json!({ "public_key": public_key });
+            (Status::Ok, (ContentType::JSON, body.to_string()))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to determine the identity: {}", e);
+            warn!("{}", err_msg);
+            let body = serde_json::json!({ "error": err_msg });
+            (
+                Status::ServiceUnavailable,
+                (ContentType::JSON, body.to_string()),
+            )
+        }
+    }
+}
+
+/// Return the version and build information of the running build as JSON.
+#[get("/version")]
+pub async fn version() -> (ContentType, String) {
+    (ContentType::JSON, version_info().to_string())
+}
+
+/// Compare the follow lists of two peers, rendering the peers followed by
+/// both and by only one of them. Names are shown for peers we subscribe to.
+#[get("/compare?<a>&<b>")]
+pub async fn compare(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    follow_cache: &State<FollowCache>,
+    a: &str,
+    b: &str,
+) -> Result<Template, Flash<Redirect>> {
+    for public_key in [a, b] {
+        if let Err(e) = utils::validate_public_key(public_key) {
+            let validation_err_msg = format!("Public key {} is invalid: {}", public_key, e);
+            warn!("{}", validation_err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(home(_))),
+                validation_err_msg,
+            ));
+        }
+    }
+
+    let mut follow_sets = Vec::new();
+    for public_key in [a, b] {
+        match follow_cache.get_follows(public_key).await {
+            Ok(follows) => follow_sets.push(follows.into_iter().collect::<BTreeSet<String>>()),
+            Err(e) => {
+                let err_msg = format!("Failed to fetch follows of {}: {}", public_key, e);
+                warn!("{}", err_msg);
+                return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+            }
+        }
+    }
+    let (follows_a, follows_b) = (&follow_sets[0], &follow_sets[1]);
+
+    // Pair each public key with the name of the peer, if known.
+    let with_name = |public_key: &str| -> (String, String) {
+        let name = match db.get_peer(public_key) {
+            Ok(Some(peer)) => peer.name,
+            _ => String::new(),
+        };
+        (public_key.to_string(), name)
+    };
+    let with_names = |keys: Vec<&String>| -> Vec<(String, String)> {
+        keys.into_iter().map(|key| with_name(key)).collect()
+    };
+
+    let name_a = with_name(a);
+    let name_b = with_name(b);
+    let sections = vec![
+        (
+            "Followed by both".to_string(),
+            with_names(follows_a.intersection(follows_b).collect()),
+        ),
+        (
+            format!("Followed only by {}", display_name(&name_a)),
+            with_names(follows_a.difference(follows_b).collect()),
+        ),
+        (
+            format!("Followed only by {}", display_name(&name_b)),
+            with_names(follows_b.difference(follows_a).collect()),
+        ),
+    ];
+
+    // Peers without any known follows are most likely not replicated.
+    let mut unreplicated = Vec::new();
+    for (peer, follows) in [(&name_a, follows_a), (&name_b, follows_b)] {
+        if follows.is_empty() {
+            unreplicated.push(display_name(peer));
+        }
+    }
+
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let context = context! {
+        peers: &peers_unread,
+        sections: &sections,
+        unreplicated: &unreplicated,
+        compare_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Ok(Template::render("base", context))
+}
+
+/// Return the name of the given (public key, name) pair if known, otherwise
+/// the public key.
+fn display_name((public_key, name): &(String, String)) -> String {
+    if name.is_empty() {
+        public_key.to_string()
+    } else {
+        name.to_string()
+    }
+}
+
+#[get("/api/unread")]
+pub async fn api_unread(db: &State<Database>, if_none_match: IfNoneMatch) -> TaggedJson {
+    // Use an ordered map so that the serialized body (and therefore the
+    // entity tag) is stable for unchanged counts.
+    let mut unread_counts = BTreeMap::new();
+    for peer in db.get_peers() {
+        let unread_count = db.get_unread_post_count(&peer.public_key);
+        unread_counts.insert(peer.public_key, unread_count);
+    }
+
+    let body = serde_json::to_string(&unread_counts).unwrap();
+
+    TaggedJson::new(body, if_none_match)
+}
+
+/// Return all peers, including archived peers, and the number of their unread
+/// posts as JSON.
+#[get("/api/peers")]
+pub async fn api_peers(db: &State<Database>) -> (ContentType, String) {
+    let peers: Vec<PeerSummary> = db
+        .with_unread_counts(db.get_peers())
+        .into_iter()
+        .map(|(peer, unread_count)| PeerSummary { peer, unread_count })
+        .collect();
+
+    (ContentType::JSON, serde_json::to_string(&peers).unwrap())
+}
+
+/// Return the stored posts of the peer represented by the given public key as
+/// JSON, optionally only those which are unread. An invalid public key is
+/// rejected with a JSON error body.
+#[get("/api/posts/<public_key>?<unread_only>")]
+pub async fn api_posts(
+    db: &State<Database>,
+    public_key: &str,
+    unread_only: Option<bool>,
+) -> (Status, (ContentType, String)) {
+    if let Err(e) = utils::validate_public_key(public_key) {
+        let validation_err_msg = format!("Public key {} is invalid: {}", public_key, e);
+        warn!("{}", validation_err_msg);
+        let body = serde_json::json!({ "error": validation_err_msg });
+        return (Status::BadRequest, (ContentType::JSON, body.to_string()));
+    }
+
+    match db.get_posts(public_key) {
+        Ok(mut posts) => {
+            if unread_only.unwrap_or(false) {
+                posts.retain(|post| !post.read);
+            }
+            (
+                Status::Ok,
+                (ContentType::JSON, serde_json::to_string(&posts).unwrap()),
+            )
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to retrieve posts for peer {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            let body = serde_json::json!({ "error": err_msg });
+            (
+                Status::InternalServerError,
+                (ContentType::JSON, body.to_string()),
+            )
+        }
+    }
+}
+
+/// Return the most recently read posts of all peers as JSON, most recently
+/// read first.
+#[get("/api/recently_read?<limit>")]
+pub async fn api_recently_read(
+    db: &State<Database>,
+    limit: Option<usize>,
+) -> (ContentType, String) {
+    let posts = db.get_recently_read(limit.unwrap_or(DEFAULT_RECENTLY_READ_LIMIT));
+
+    (ContentType::JSON, serde_json::to_string(&posts).unwrap())
+}
+
+/// Return the decrypted private messages, most recent first, as JSON.
+#[get("/api/private")]
+pub async fn api_private_messages(db: &State<Database>) -> Result<(ContentType, String), Status> {
+    match db.get_private_messages() {
+        Ok(messages) => Ok((ContentType::JSON, serde_json::to_string(&messages).unwrap())),
+        Err(e) => {
+            warn!("Failed to retrieve private messages: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Return the samples of the total number of unread posts, ordered from
+/// oldest to newest, as JSON.
+#[get("/api/unread_history")]
+pub async fn api_unread_history(db: &State<Database>) -> Result<(ContentType, String), Status> {
+    match db.get_unread_history() {
+        Ok(samples) => {
+            let samples: Vec<serde_json::Value> = samples
+                .into_iter()
+                .map(|(timestamp, unread)| {
+                    serde_json::json!({ "timestamp": timestamp, "unread": unread })
+                })
+                .collect();
+
+            Ok((ContentType::JSON, serde_json::to_string(&samples).unwrap()))
+        }
+        Err(e) => {
+            warn!("Failed to retrieve unread history: {}", e);
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Render the stored posts of all peers whose text contains the given query,
+/// most recent first. An empty query matches no posts.
+#[get("/search?<query>")]
+pub async fn search(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    query: Option<&str>,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let query = query.unwrap_or_default().trim();
+    let mut results = db.search_posts(query);
+    let result_count = results.len();
+    results.truncate(SEARCH_LIMIT);
+
+    let context = context! {
+        peers: &peers_unread,
+        search_query: &query,
+        search_results: &results,
+        search_result_count: &result_count,
+        search_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+#[get("/peer_search?<q>")]
+pub async fn peer_search(db: &State<Database>, q: &str) -> (ContentType, String) {
+    // Only known peers are searched; the sbot offers no way to look up
+    // public keys by name without replaying every about message.
+    let candidates: Vec<serde_json::Value> = db
+        .search_peers(q)
+        .into_iter()
+        .take(PEER_SEARCH_LIMIT)
+        .map(|peer| {
+            serde_json::json!({
+                "public_key": peer.public_key,
+                "name": peer.name,
+            })
+        })
+        .collect();
+
+    (
+        ContentType::JSON,
+        serde_json::to_string(&candidates).unwrap(),
+    )
+}
+
+/// Render an RSS feed of the unread posts of all subscribed peers, with the
+/// most recent posts first.
+#[get("/feed.xml?<limit>")]
+pub async fn feed(db: &State<Database>, config: &Config, limit: Option<usize>) -> Template {
+    let base_url = format!("http://{}:{}", config.address, config.port);
+
+    // Only the unread posts of each peer are collected, rather than their
+    // entire history. Posts which cannot be read are left out of the feed.
+    let mut posts = Vec::new();
+    for peer in db.get_active_peers() {
+        posts.extend(
+            db.posts_iter_for_peer(&peer.public_key)
+                .filter_map(|post| match post {
+                    Ok(post) => Some(post),
+                    Err(e) => {
+                        warn!(
+                            "Failed to read a post of {} for the feed: {}",
+                            peer.public_key, e
+                        );
+                        None
+                    }
+                })
+                .filter(|post| !post.read),
+        );
+    }
+
+    // Sort the posts of all peers before keeping the most recent.
+    posts.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    posts.truncate(limit.unwrap_or_else(feed_limit));
+
+    let items: Vec<FeedItem> = posts
+        .into_iter()
+        .map(|post| {
+            let description = utils::decode_text(&post.text);
+            let title = match &post.subject {
+                Some(subject) => format!("{}...", subject.trim_start_matches('"')),
+                None => description.clone(),
+            };
+
+            FeedItem {
+                title,
+                link: format!("{}{}", base_url, uri!(post(&post.author, &post.key, _))),
+                description,
+                guid: post.key,
+                pub_date: sbot::format_date(post.timestamp, FEED_DATE_FORMAT),
+            }
+        })
+        .collect();
+
+    let context = context! {
+        base_url: &base_url,
+        items: &items
+    };
+
+    Template::render("feed", context)
+}
+
+/// Export the list of subscribed peers as an OPML document, with an outline
+/// element for each peer. Archived peers are not included, since they have
+/// been unfollowed.
+#[get("/export/opml")]
+pub async fn export_opml(db: &State<Database>) -> Download {
+    let mut outlines = String::new();
+    for peer in db.get_active_peers() {
+        let public_key = utils::escape_xml(&peer.public_key);
+        let name = if peer.name.is_empty() {
+            public_key.clone()
+        } else {
+            utils::escape_xml(&peer.name)
+        };
+        outlines.push_str(&format!(
+            "    <outline type=\"ssb\" text=\"{}\" title=\"{}\" publicKey=\"{}\"/>\n",
+            name, name, public_key
+        ));
+    }
+
+    let body = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <opml version=\"2.0\">\n  \
+           <head>\n    <title>lykin subscriptions</title>\n  </head>\n  \
+           <body>\n{}  </body>\n\
+         </opml>\n",
+        outlines
+    );
+
+    Download {
+        body,
+        content_type: ContentType::new("text", "x-opml"),
+        filename: "lykin_subscriptions.opml".to_string(),
+    }
+}
+
+/// Import a list of peers from an uploaded OPML document, such as the one
+/// produced by the OPML export route. Each new peer with a valid public key is
+/// followed, added to the database and has all of their root posts fetched.
+/// Peers which are already in the database are skipped.
+#[post("/import/opml", data = "<upload>")]
+pub async fn import_opml(
+    db: &State<Database>,
+    tx: &State<Sender<Task>>,
+    pending_fetches: &State<PendingFetches>,
+    activity: &State<ActivityLog>,
+    upload: Form<OpmlForm<'_>>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let document = match upload.opml.path() {
+        Some(path) => async_std::fs::read_to_string(path).await,
+        None => Err(std::io::ErrorKind::NotFound.into()),
+    };
+    let document = match document {
+        Ok(document) => document,
+        Err(e) => {
+            let err_msg = format!("Failed to read OPML document: {}", e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+    };
+
+    let (mut imported, mut invalid, mut duplicates, mut failed) = (0, 0, 0, 0);
+    let mut seen = BTreeSet::new();
+
+    for public_key in utils::opml_public_keys(&document) {
+        if let Err(e) = utils::validate_public_key(&public_key) {
+            warn!("Public key {} is invalid: {}", &public_key, e);
+            invalid += 1;
+            continue;
+        }
+        if !seen.insert(public_key.clone()) || matches!(db.get_peer(&public_key), Ok(Some(_))) {
+            duplicates += 1;
+            continue;
+        }
+
+        if sbot::read_only() {
+            info!("Read-only mode: not following peer {}", &public_key);
+        } else if let Err(e) = sbot::follow_if_not_following(&public_key).await {
+            warn!("Failed to follow peer {}: {}", &public_key, e);
+            failed += 1;
+            continue;
+        } else {
+            activity.record(format!("Followed peer {}", &public_key));
+        }
+
+        if let Err(e) = db.add_peer(Peer::new(&public_key)) {
+            warn!(
+                "Failed to add peer {} to 'peers' database tree: {}",
+                &public_key, e
+            );
+            failed += 1;
+            continue;
+        }
+        info!("Imported peer {} from OPML", &public_key);
+        activity.record(format!("Subscribed to peer {}", &public_key));
+        imported += 1;
+
+        pending_fetches.insert(&public_key);
+        if let Err(e) = tx.send(Task::FetchAllPosts(public_key.clone())).await {
+            warn!("Task loop error: {}", e);
+            pending_fetches.remove(&public_key);
+        }
+        if let Err(e) = tx.send(Task::FetchLatestName(public_key)).await {
+            warn!("Task loop error: {}", e);
+        }
+    }
+
+    let mut message = format!(
+        "Imported {} peers; skipped {} invalid and {} already subscribed",
+        imported, invalid, duplicates
+    );
+    if failed > 0 {
+        message.push_str(&format!("; failed to import {}", failed));
+    }
+    info!("{}", message);
+
+    Ok(Flash::success(Redirect::to(uri!(home(_))), message))
+}
+
+#[get("/export/peer/<public_key>")]
+pub async fn export_peer(db: &State<Database>, public_key: &str) -> Option<Download> {
+    match db.export_peer(public_key) {
+        Ok(Some(export)) => Some(Download {
+            body: serde_json::to_string_pretty(&export).unwrap(),
+            content_type: ContentType::JSON,
+            filename: "lykin_peer_export.json".to_string(),
+        }),
+        Ok(None) => {
+            warn!(
+                "Failed to find peer {} in 'peers' database tree",
+                public_key
+            );
+            None
+        }
+        Err(e) => {
+            warn!("Failed to export peer {}: {}", public_key, e);
+            None
+        }
+    }
+}
+
+/// Import a peer and their posts from a document produced by the export
+/// route, merging them with any existing data. The number of added, skipped
+/// and deleted (not imported) posts is returned as JSON.
+#[post("/import", data = "<data>")]
+pub async fn import(
+    db: &State<Database>,
+    limits: &Limits,
+    data: Data<'_>,
+) -> Result<(ContentType, String), BadRequest<String>> {
+    let document = match data
+        .open(limits.get("json").unwrap_or(Limits::JSON))
+        .into_string()
+        .await
+    {
+        Ok(document) if document.is_complete() => document.into_inner(),
+        Ok(_) => {
+            let err_msg = "Import document exceeds the size limit".to_string();
+            warn!("{}", err_msg);
+            return Err(BadRequest(Some(err_msg)));
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to read import document: {}", e);
+            warn!("{}", err_msg);
+            return Err(BadRequest(Some(err_msg)));
+        }
+    };
+
+    let import: PeerImport = match serde_json::from_str(&document) {
+        Ok(import) => import,
+        Err(e) => {
+            let err_msg = format!("Import document is malformed: {}", e);
+            warn!("{}", err_msg);
+            return Err(BadRequest(Some(err_msg)));
+        }
+    };
+    if let Err(e) = utils::validate_public_key(&import.peer.public_key) {
+        let err_msg = format!("Public key {} is invalid: {}", &import.peer.public_key, e);
+        warn!("{}", err_msg);
+        return Err(BadRequest(Some(err_msg)));
+    }
+    // The document is rejected as a whole, so that nothing is imported from
+    // a document with an invalid post.
+    if let Some((key, e)) = import.posts.iter().find_map(|post| {
+        utils::validate_message_id(&post.key)
+            .err()
+            .map(|e| (&post.key, e))
+    }) {
+        let err_msg = format!("Message ID {} is invalid: {}", key, e);
+        warn!("{}", err_msg);
+        return Err(BadRequest(Some(err_msg)));
+    }
+
+    match db.import_peer(import) {
+        Ok(counts) => Ok((ContentType::JSON, serde_json::to_string(&counts).unwrap())),
+        Err(e) => {
+            let err_msg = format!("Failed to import peer: {}", e);
+            warn!("{}", err_msg);
+            Err(BadRequest(Some(err_msg)))
+        }
+    }
+}
+
+/// Return the timestamp of the moment the given number of days ago. Large
+/// numbers of days saturate rather than overflow.
+fn days_ago_timestamp(days: i64) -> Result<i64, String> {
+    if days < 0 {
+        return Err(format!("Number of days {} is negative", days));
+    }
+
+    Ok(Utc::now()
+        .timestamp()
+        .saturating_sub(days.saturating_mul(24 * 60 * 60)))
+}
+
+#[get("/trending?<days>")]
+pub async fn trending(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    days: Option<i64>,
+) -> Result<Template, Flash<Redirect>> {
+    let days = days.unwrap_or(TRENDING_DAYS);
+    let since_timestamp = match days_ago_timestamp(days) {
+        Ok(timestamp) => timestamp,
+        Err(e) => {
+            warn!("{}", e);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), e));
+        }
+    };
+
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    // Count the channels mentioned in posts published within the window.
+    let mut channels = db.trending_channels(since_timestamp);
+    channels.truncate(TRENDING_LIMIT);
+
+    let context = context! {
+        peers: &peers_unread,
+        channels: &channels,
+        trending_days: &days,
+        trending_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Ok(Template::render("base", context))
+}
+
+/// Render the stored posts of all peers which mention the given channel
+/// (hashtag), most recent first. The channel name is matched without regard to
+/// case.
+#[get("/channel/<name>")]
+pub async fn channel_posts(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    name: &str,
+) -> Template {
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let channel_name = name.trim().trim_start_matches('#').to_lowercase();
+    let posts = db.get_posts_by_channel(&channel_name);
+
+    let context = context! {
+        peers: &peers_unread,
+        channel_name: &channel_name,
+        channel_posts: &posts,
+        channel_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Template::render("base", context)
+}
+
+/// Render the ranking of all peers by the number of their stored posts. Each
+/// peer is classified as active or dormant, according to whether they have
+/// published a post within the given number of days.
+#[get("/prolific?<dormant_days>")]
+pub async fn prolific(
+    db: &State<Database>,
+    sbot_status: &State<SbotStatus>,
+    dormant_days: Option<i64>,
+) -> Result<Template, Flash<Redirect>> {
+    let dormant_days = dormant_days.unwrap_or(DORMANT_DAYS);
+    let active_since_timestamp = match days_ago_timestamp(dormant_days) {
+        Ok(timestamp) => timestamp,
+        Err(e) => {
+            warn!("{}", e);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), e));
+        }
+    };
+
+    let peers_unread = peer_list_entries(db.get_peers_with_unread());
+
+    let mut ranking = Vec::new();
+    for (peer, post_count) in db.peers_by_post_count() {
+        let latest_timestamp = db
+            .get_latest_post_timestamp(&peer.public_key)
+            .ok()
+            .flatten();
+        let dormant = match latest_timestamp {
+            Some(timestamp) => timestamp < active_since_timestamp,
+            None => true,
+        };
+        ranking.push((peer, post_count, dormant));
+    }
+
+    let context = context! {
+        peers: &peers_unread,
+        ranking: &ranking,
+        dormant_days: &dormant_days,
+        prolific_is_selected: &true,
+        sbot_down: sbot_status.is_down()
+    };
+
+    Ok(Template::render("base", context))
+}
+
+#[get("/peek/<public_key>")]
+pub async fn peek(db: &State<Database>, public_key: &str) -> Result<Template, Status> {
+    // Render only the post content partial so that the client can inject the
+    // fragment into the current page.
+    match db.first_unread_post(public_key) {
+        Ok(Some(post)) => Ok(Template::render("post_content", context! { post: &post })),
+        Ok(None) => Err(Status::NoContent),
+        Err(e) => {
+            warn!(
+                "Failed to retrieve first unread post of peer {}: {}",
+                public_key, e
+            );
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+/// Return the underlying message of a post as pretty-printed JSON, fetched
+/// from the sbot. This aids in troubleshooting the parsing of posts.
+#[get("/posts/<public_key>/<msg_id>/raw")]
+pub async fn raw_post(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<(ContentType, String), Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    // The sequence number of a stored post allows the message to be fetched
+    // without searching the entire feed of the author.
+    let sequence = match db.get_post(public_key, msg_id) {
+        Ok(Some(post)) => Some(post.sequence),
+        _ => None,
+    };
+
+    match sbot::get_message(public_key, msg_id, sequence).await {
+        Ok(Some(msg)) => Ok((
+            ContentType::JSON,
+            serde_json::to_string_pretty(&msg).unwrap(),
+        )),
+        Ok(None) => {
+            let err_msg = format!(
+                "Message {} by {} was not found; the feed of the author may not be replicated",
+                msg_id, public_key
+            );
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+        Err(e) => {
+            let err_msg = format!(
+                "Failed to fetch message {} by {}: {}",
+                msg_id, public_key, e
+            );
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+    }
+}
+
+/// Mark all posts of a peer as read.
+#[get("/posts/<public_key>/mark_all_read")]
+pub async fn mark_all_posts_read(
+    db: &State<Database>,
+    activity: &State<ActivityLog>,
+    public_key: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match db.mark_all_read(public_key) {
+        Ok(updated) => {
+            info!("Marked {} posts of peer {} as read", updated, public_key);
+            activity.record(format!(
+                "Marked {} posts of peer {} as read",
+                updated, public_key
+            ));
+            Ok(Flash::success(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                format!("Marked {} posts as read", updated),
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to mark posts of peer {} as read: {}", public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+/// Delete all read posts of a peer, keeping unread and starred posts.
+#[get("/posts/<public_key>/delete_read")]
+pub async fn delete_read_posts(
+    db: &State<Database>,
+    activity: &State<ActivityLog>,
+    public_key: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match db.delete_read_posts(public_key) {
+        Ok(removed) => {
+            info!("Deleted {} read posts of peer {}", removed, public_key);
+            activity.record(format!(
+                "Deleted {} read posts of peer {}",
+                removed, public_key
+            ));
+            Ok(Flash::success(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                format!("Deleted {} read posts", removed),
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to delete read posts of peer {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+/// Serve the blob with the given ID (e.g. the profile image of a peer) from
+/// the blob store of the local sbot. Only images are served, with the content
+/// type of their format; other blobs, and blobs which are not stored locally,
+/// are not found.
+#[get("/blob/<blob_id>")]
+pub async fn blob(blob_id: &str) -> Result<(ContentType, Vec<u8>), Status> {
+    if let Err(e) = utils::validate_blob_id(blob_id) {
+        warn!("Blob ID {} is invalid: {}", blob_id, e);
+        return Err(Status::NotFound);
+    }
+
+    let bytes = sbot::fetch_blob(blob_id).await.map_err(|e| {
+        warn!("Failed to read blob {}: {}", blob_id, e);
+        Status::NotFound
+    })?;
+
+    match utils::image_extension(&bytes).and_then(ContentType::from_extension) {
+        Some(content_type) => Ok((content_type, bytes)),
+        None => {
+            warn!("Blob {} is not an image", blob_id);
+            Err(Status::NotFound)
+        }
+    }
+}
+
+/// Like a post, unless we have already liked it.
+///
+/// Our likes are stored in the database, both those published here and
+/// those tallied from our own feed (which include likes published with
+/// other clients).
+#[get("/posts/<public_key>/<msg_id>/like")]
+pub async fn like_post(
+    db: &State<Database>,
+    liked_posts: &State<LikedPosts>,
+    activity: &State<ActivityLog>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    let own_key = match sbot::whoami().await {
+        Ok(own_key) => own_key,
+        Err(e) => {
+            let err_msg = format!("Failed to like post {}: {}", msg_id, e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(post(public_key, msg_id, _))),
+                err_msg,
+            ));
+        }
+    };
+    match db.is_liked_by(msg_id, &own_key) {
+        Ok(false) => (),
+        Ok(true) => {
+            return Err(Flash::error(
+                Redirect::to(uri!(post(public_key, msg_id, _))),
+                "Post is already liked",
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to like post {}: {}", msg_id, e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(post(public_key, msg_id, _))),
+                err_msg,
+            ));
+        }
+    }
+
+    // The like is recorded before it is published so that concurrent
+    // requests do not both publish one.
+    if !liked_posts.insert(msg_id) {
+        return Err(Flash::error(
+            Redirect::to(uri!(post(public_key, msg_id, _))),
+            "Post is already liked",
+        ));
+    }
+
+    match sbot::publish_vote(msg_id, 1).await {
+        Ok(vote_key) => {
+            info!("Liked post {} with vote {}", msg_id, vote_key);
+            if let Err(e) = db.add_like(msg_id, &own_key) {
+                warn!("Failed to record like of post {}: {}", msg_id, e);
+            }
+            activity.record(format!("Liked post {}", msg_id));
+            Ok(Flash::success(
+                Redirect::to(uri!(post(public_key, msg_id, _))),
+                "Liked post",
+            ))
+        }
+        Err(e) => {
+            liked_posts.remove(msg_id);
+            let err_msg = format!("Failed to like post {}: {}", msg_id, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(post(public_key, msg_id, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+#[get("/posts/<public_key>/<msg_id>/read")]
+pub async fn mark_post_read(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    // Mark the post defined by the public key and msg_id from the URL as
+    // read.
+    if let Ok(false) | Err(_) = db.update_post_read(public_key, msg_id, true) {
+        warn!(
+            "Failed to find post {} authored by {} in 'posts' database tree",
+            msg_id, public_key
+        )
+    }
+
+    Ok(Redirect::to(uri!(post(public_key, msg_id, _))))
+}
+
+/// Mark a post as read without redirecting. This is intended to be called by
+/// client-side scripts (e.g. via `navigator.sendBeacon()`) as posts are
+/// scrolled into view.
+#[post("/posts/<public_key>/<msg_id>/read")]
+pub async fn mark_post_read_beacon(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<NoContent, Status> {
+    match db.update_post_read(public_key, msg_id, true) {
+        Ok(true) => Ok(NoContent),
+        Ok(false) => {
+            warn!(
+                "Failed to find post {} authored by {} in 'posts' database tree",
+                msg_id, public_key
+            );
+            Err(Status::NotFound)
+        }
+        Err(e) => {
+            warn!(
+                "Failed to mark post {} authored by {} as read: {}",
+                msg_id, public_key, e
+            );
+            Err(Status::InternalServerError)
+        }
+    }
+}
+
+#[get("/posts/<public_key>/<msg_id>/read_next")]
+pub async fn mark_post_read_and_next(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Redirect, Flash<Redirect>> {
+    match db.update_post_read(public_key, msg_id, true) {
+        Ok(true) => (),
+        Ok(false) => {
+            let err_msg = format!(
+                "Failed to find post {} authored by {} in 'posts' database tree",
+                msg_id, public_key
+            );
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+        Err(e) => {
+            let err_msg = format!(
+                "Failed to mark post {} authored by {} as read: {}",
+                msg_id, public_key, e
+            );
+            warn!("{}", err_msg);
+            return Err(Flash::error(Redirect::to(uri!(home(_))), err_msg));
+        }
+    }
+
+    match db.first_unread_post(public_key) {
+        Ok(Some(next_post)) => Ok(Redirect::to(uri!(post(public_key, next_post.key, _)))),
+        // The last unread post has been read; return to the peer list.
+        Ok(None) => Ok(Redirect::to(uri!(home(_)))),
+        Err(e) => {
+            let err_msg = format!(
+                "Failed to find next unread post authored by {}: {}",
+                public_key, e
+            );
+            warn!("{}", err_msg);
+            Err(Flash::error(Redirect::to(uri!(home(_))), err_msg))
+        }
+    }
+}
+
+#[get("/posts/<public_key>/<msg_id>/unread")]
+pub async fn mark_post_unread(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    if let Ok(false) | Err(_) = db.update_post_read(public_key, msg_id, false) {
+        warn!(
+            "Failed to find post {} authored by {} in 'posts' database tree",
+            msg_id, public_key
+        )
+    }
+
+    Ok(Redirect::to(uri!(post(public_key, msg_id, _))))
+}
+
+#[get("/posts/<public_key>/<msg_id>/delete")]
+pub async fn delete_post(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Redirect, Flash<Redirect>> {
+    if let Err(e) = utils::validate_message_id(msg_id) {
+        let validation_err_msg = format!("Message ID {} is invalid: {}", msg_id, e);
+        warn!("{}", validation_err_msg);
+        return Err(Flash::error(
+            Redirect::to(uri!(home(_))),
+            validation_err_msg,
+        ));
+    }
+
+    // Delete the post from the database.
+    match db.remove_post(public_key, msg_id) {
+        Ok(_) => info!(
+            "Removed post {} by {} from 'posts' database tree",
+            msg_id, public_key
+        ),
+        Err(e) => warn!(
+            "Failed to remove post {} by {} from 'posts' database tree: {}",
+            msg_id, public_key, e
+        ),
+    }
+
+    Ok(Redirect::to(uri!(posts(public_key, _, _, _))))
+}
+
+/// Restore a deleted post by removing its tombstone and fetching it again
+/// from the sbot.
+#[get("/posts/<public_key>/<msg_id>/undelete")]
+pub async fn undelete_post(
+    db: &State<Database>,
+    public_key: &str,
+    msg_id: &str,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    match db.undelete_post(public_key, msg_id) {
+        Ok(true) => (),
+        Ok(false) => {
+            let err_msg = format!("Post {} has not been deleted", msg_id);
+            warn!("{}", err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ));
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to undelete post {}: {}", msg_id, e);
+            warn!("{}", err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ));
+        }
+    }
+
+    match sbot::get_post(public_key, msg_id).await {
+        Ok(Some(post)) => {
+            if let Err(e) = db.add_post(public_key, post) {
+                let err_msg = format!("Failed to restore post {}: {}", msg_id, e);
+                warn!("{}", err_msg);
+                return Err(Flash::error(
+                    Redirect::to(uri!(posts(public_key, _, _, _))),
+                    err_msg,
+                ));
+            }
+            info!("Restored post {} by {}", msg_id, public_key);
+            Ok(Flash::success(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                format!("Restored post {}", msg_id),
+            ))
+        }
+        Ok(None) => {
+            let err_msg = format!("Post {} was not found in the feed of the peer", msg_id);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to fetch post {}: {}", msg_id, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
+}
+
+/// Apply an action to several posts of a peer at once, reporting how many of
+/// the selected posts were updated.
+#[post("/posts/<public_key>/bulk", data = "<bulk>")]
+pub async fn bulk_update_posts(
+    db: &State<Database>,
+    public_key: &str,
+    bulk: Form<BulkForm>,
+) -> Result<Flash<Redirect>, Flash<Redirect>> {
+    let action = match bulk.action.as_str() {
+        "read" => BulkAction::Read,
+        "unread" => BulkAction::Unread,
+        "delete" => BulkAction::Delete,
+        "star" => BulkAction::Star,
+        "unstar" => BulkAction::Unstar,
+        _ => {
+            let err_msg = format!("Unknown bulk action: {}", &bulk.action);
+            warn!("{}", err_msg);
+            return Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ));
+        }
+    };
+
+    // Invalid message IDs are skipped and reported as not updated.
+    let msg_ids: Vec<String> = bulk
+        .msg_ids
+        .iter()
+        .filter(|msg_id| match utils::validate_message_id(msg_id) {
+            Ok(_) => true,
+            Err(e) => {
+                warn!("Message ID {} is invalid: {}", msg_id, e);
+                false
+            }
+        })
+        .cloned()
+        .collect();
+
+    match db.bulk_update_posts(public_key, &msg_ids, action) {
+        Ok(updated) => {
+            let msg = format!("Updated {} of {} posts", updated, bulk.msg_ids.len());
+            info!("{} by {}", msg, public_key);
+            Ok(Flash::success(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                msg,
+            ))
+        }
+        Err(e) => {
+            let err_msg = format!("Failed to update posts by {}: {}", public_key, e);
+            warn!("{}", err_msg);
+            Err(Flash::error(
+                Redirect::to(uri!(posts(public_key, _, _, _))),
+                err_msg,
+            ))
+        }
+    }
 }
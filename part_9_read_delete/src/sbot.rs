@@ -1,31 +1,410 @@
-use std::env;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    env, fs,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
 
 use async_std::stream::StreamExt;
-use chrono::NaiveDateTime;
+use chrono::{
+    format::{Item, StrftimeItems},
+    NaiveDateTime,
+};
 use golgi::{
-    api::{friends::RelationshipQuery, history_stream::CreateHistoryStream},
-    messages::{SsbMessageContentType, SsbMessageKVT},
+    api::{
+        friends::{FriendsHops, RelationshipQuery},
+        history_stream::CreateHistoryStream,
+        tangles::TanglesThread,
+    },
+    messages::{SsbMessageContent, SsbMessageContentType, SsbMessageKVT, SsbMessageValue},
     sbot::Keystore,
     GolgiError, Sbot,
 };
-use log::{info, warn};
-use serde_json::value::Value;
+use log::{debug, info, warn};
+use serde_json::{value::Value, Map};
+
+use crate::{db::Post, private_box, utils};
+
+/// The default format used to display the date a post was published
+/// (e.g. 17 May 2021).
+pub const DEFAULT_DATE_FORMAT: &str = "%d %b %Y";
+
+/// The default number of preceding posts against which the text of a post is
+/// compared to detect duplicates.
+pub const DEFAULT_DUPLICATE_WINDOW: usize = 1;
+
+/// The maximum length of the subject of a post, in characters.
+const SUBJECT_LENGTH: usize = 52;
+
+/// The host on which the sbot is assumed to listen if none is configured.
+const DEFAULT_SBOT_HOST: &str = "127.0.0.1";
+
+/// The default time after which the posts of a peer are considered stale, in
+/// seconds.
+const DEFAULT_REFRESH_STALE_AFTER: i64 = 900;
+
+/// The length of time for which the follow list of a peer is cached.
+const FOLLOW_CACHE_TTL: Duration = Duration::from_secs(300);
+
+/// The maximum number of requests in flight when fetching the names of many
+/// peers.
+const MAX_CONCURRENT_NAME_REQUESTS: usize = 8;
+
+/// A follow list, along with the time at which it was fetched.
+type CachedFollows = (Instant, Vec<String>);
+
+/// A short-lived cache of the follow lists of peers, which are expensive to
+/// fetch from the sbot.
+#[derive(Clone, Default)]
+pub struct FollowCache {
+    entries: Arc<Mutex<HashMap<String, CachedFollows>>>,
+}
+
+impl FollowCache {
+    /// Return the public keys of the peers followed by the given peer,
+    /// fetching them from the sbot unless a recently cached list is
+    /// available.
+    pub async fn get_follows(&self, public_key: &str) -> Result<Vec<String>, String> {
+        if let Some(follows) = self.cached(public_key, Instant::now()) {
+            return Ok(follows);
+        }
+
+        let follows = get_follows(public_key).await?;
+        self.insert(public_key, follows.clone(), Instant::now());
+
+        Ok(follows)
+    }
+
+    /// Return the cached follow list of the given peer if it was fetched
+    /// less than the cache lifetime before `now`.
+    fn cached(&self, public_key: &str, now: Instant) -> Option<Vec<String>> {
+        let entries = self.entries.lock().unwrap();
+        match entries.get(public_key) {
+            Some((fetched_at, follows)) if now.duration_since(*fetched_at) < FOLLOW_CACHE_TTL => {
+                Some(follows.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Cache the follow list of the given peer, fetched at `fetched_at`.
+    fn insert(&self, public_key: &str, follows: Vec<String>, fetched_at: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(public_key.to_string(), (fetched_at, follows));
+    }
+}
+
+/// The keys of the messages liked during this session, used to avoid
+/// publishing a second like of the same message before the like has been
+/// recorded in the database.
+#[derive(Clone, Default)]
+pub struct LikedPosts(Arc<Mutex<HashSet<String>>>);
+
+impl LikedPosts {
+    /// Return `true` if the message with the given key was liked during this
+    /// session.
+    pub fn contains(&self, msg_key: &str) -> bool {
+        self.0.lock().unwrap().contains(msg_key)
+    }
+
+    /// Record that the message with the given key is liked. Returns `false`
+    /// if it already was.
+    pub fn insert(&self, msg_key: &str) -> bool {
+        self.0.lock().unwrap().insert(msg_key.to_string())
+    }
+
+    /// Forget that the message with the given key is liked (e.g. if
+    /// publishing the like failed).
+    pub fn remove(&self, msg_key: &str) {
+        self.0.lock().unwrap().remove(msg_key);
+    }
+}
+
+/// Ensure that the given date format is a valid chrono format string.
+///
+/// Return an error string if the format is invalid.
+pub fn validate_date_format(format: &str) -> Result<(), String> {
+    if StrftimeItems::new(format).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid date format string: {}", format));
+    }
+
+    Ok(())
+}
+
+/// Return the format used to display the date a post was published.
+///
+/// The format is read from the `LYKIN_DATE_FORMAT` environment variable,
+/// falling back to the default format if the variable is unset or invalid.
+/// The variable is only read once, since the date of every post which is
+/// read from the database is formatted.
+pub fn date_format() -> &'static str {
+    static DATE_FORMAT: OnceLock<String> = OnceLock::new();
+
+    DATE_FORMAT.get_or_init(|| match env::var("LYKIN_DATE_FORMAT") {
+        Ok(format) if validate_date_format(&format).is_ok() => format,
+        _ => DEFAULT_DATE_FORMAT.to_string(),
+    })
+}
+
+/// Return the number of preceding posts against which the text of a post is
+/// compared to detect duplicates.
+///
+/// The window is read from the `LYKIN_DUPLICATE_WINDOW` environment variable,
+/// falling back to the default window if the variable is unset or invalid. A
+/// window of zero disables duplicate detection.
+pub fn duplicate_window() -> usize {
+    env::var("LYKIN_DUPLICATE_WINDOW")
+        .ok()
+        .and_then(|window| window.parse().ok())
+        .unwrap_or(DEFAULT_DUPLICATE_WINDOW)
+}
+
+/// The posts which are fetched when subscribing to a peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeFetch {
+    /// Fetch only the given number of most recent root posts. With a limit
+    /// of zero, no posts are fetched; only posts published after subscribing
+    /// are fetched later on.
+    Latest(usize),
+    /// Fetch all root posts.
+    All,
+}
+
+/// Parse a setting of the posts to fetch when subscribing to a peer: `none`,
+/// `latest:N` or `all`.
+///
+/// Return an error string if the setting is invalid.
+pub fn parse_subscribe_fetch(setting: &str) -> Result<SubscribeFetch, String> {
+    match setting.trim() {
+        "none" => Ok(SubscribeFetch::Latest(0)),
+        "all" => Ok(SubscribeFetch::All),
+        setting => match setting.strip_prefix("latest:") {
+            Some(limit) => limit
+                .parse()
+                .map(SubscribeFetch::Latest)
+                .map_err(|_| format!("invalid number of posts: {}", limit)),
+            None => Err(format!(
+                "expected none, latest:N or all but found {}",
+                setting
+            )),
+        },
+    }
+}
+
+/// Return the posts to fetch when subscribing to a peer.
+///
+/// The setting is read from the `LYKIN_SUBSCRIBE_FETCH` environment variable,
+/// falling back to fetching all posts if the variable is unset or invalid.
+pub fn subscribe_fetch() -> SubscribeFetch {
+    env::var("LYKIN_SUBSCRIBE_FETCH")
+        .ok()
+        .and_then(|setting| parse_subscribe_fetch(&setting).ok())
+        .unwrap_or(SubscribeFetch::All)
+}
 
-use crate::db::Post;
+/// Return `true` if the latest posts of a peer are to be fetched when the
+/// peer is viewed and their posts are stale.
+///
+/// Refreshing on view is enabled by setting the `LYKIN_REFRESH_ON_VIEW`
+/// environment variable to `true`, `yes` or `1`.
+pub fn refresh_on_view() -> bool {
+    utils::env_flag("LYKIN_REFRESH_ON_VIEW")
+}
+
+/// Return the time since the last fetch after which the posts of a peer are
+/// considered stale, in seconds.
+///
+/// The time is read from the `LYKIN_REFRESH_STALE_AFTER` environment
+/// variable, falling back to the default if the variable is unset or invalid.
+pub fn refresh_stale_after() -> i64 {
+    env::var("LYKIN_REFRESH_STALE_AFTER")
+        .ok()
+        .and_then(|seconds| seconds.parse().ok())
+        .unwrap_or(DEFAULT_REFRESH_STALE_AFTER)
+}
+
+/// Return `true` if a post is to be marked as read when it is opened.
+///
+/// Marking posts as read on open is enabled by setting the
+/// `LYKIN_AUTO_READ_ON_OPEN` environment variable to `true`, `yes` or `1`.
+pub fn auto_read_on_open() -> bool {
+    utils::env_flag("LYKIN_AUTO_READ_ON_OPEN")
+}
+
+/// Return the words for which posts are filtered when they are fetched,
+/// normalized for matching.
+///
+/// The words are read from the comma-separated `LYKIN_FILTER_WORDS`
+/// environment variable and from the file named by the `LYKIN_FILTER_FILE`
+/// environment variable, which lists one word per line. No posts are filtered
+/// if neither variable is set.
+pub fn filter_words() -> Vec<String> {
+    let mut words = Vec::new();
+
+    if let Ok(list) = env::var("LYKIN_FILTER_WORDS") {
+        words.extend(list.split(',').map(str::to_string));
+    }
+
+    if let Ok(path) = env::var("LYKIN_FILTER_FILE") {
+        match fs::read_to_string(&path) {
+            Ok(contents) => words.extend(contents.lines().map(str::to_string)),
+            Err(e) => warn!("Failed to read filter words from {}: {}", path, e),
+        }
+    }
+
+    words
+        .iter()
+        .map(|word| utils::normalize_for_search(word.trim()))
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Return `true` if lykin is running in read-only mode, in which no messages
+/// are published to the sbot (e.g. follows and unfollows).
+///
+/// Read-only mode is enabled by setting the `LYKIN_READ_ONLY` environment
+/// variable to `true`, `yes` or `1`.
+pub fn read_only() -> bool {
+    utils::env_flag("LYKIN_READ_ONLY")
+}
+
+/// Return an error if lykin is running in read-only mode.
+fn ensure_not_read_only() -> Result<(), String> {
+    if read_only() {
+        return Err(
+            "Publishing is disabled because lykin is running in read-only mode (LYKIN_READ_ONLY)"
+                .to_string(),
+        );
+    }
+
+    Ok(())
+}
+
+/// Format the given timestamp (in seconds) as a date using the given format.
+///
+/// The timestamp is claimed by the author of a message, so an empty string is
+/// returned if it is out of range, rather than panicking.
+pub fn format_date(timestamp: i64, format: &str) -> String {
+    NaiveDateTime::from_timestamp_opt(timestamp, 0)
+        .map(|datetime| datetime.format(format).to_string())
+        .unwrap_or_default()
+}
 
 /// Initialise a connection to a Scuttlebutt server.
 pub async fn init_sbot() -> Result<Sbot, String> {
-    let go_sbot_port = env::var("GO_SBOT_PORT").unwrap_or_else(|_| "8021".to_string());
-
-    let keystore = Keystore::GoSbot;
-    let ip_port = Some(format!("127.0.0.1:{}", go_sbot_port));
-    let net_id = None;
+    let keystore = match key_path() {
+        Some(key_path) => Keystore::CustomGoSbot(key_path),
+        None => Keystore::GoSbot,
+    };
+    let ip_port = Some(format!("{}:{}", sbot_host(), sbot_port()));
+    let net_id = network_key();
 
     Sbot::init(keystore, ip_port, net_id)
         .await
         .map_err(|e| e.to_string())
 }
 
+/// Return the host on which the sbot listens.
+///
+/// The host is read from the `GO_SBOT_HOST` environment variable, falling
+/// back to the loopback address if the variable is unset or empty. This
+/// allows connecting to a go-sbot on another machine.
+pub fn sbot_host() -> String {
+    match env::var("GO_SBOT_HOST") {
+        Ok(host) if !host.trim().is_empty() => host.trim().to_string(),
+        Ok(_) => {
+            warn!(
+                "GO_SBOT_HOST is empty; using default of {}",
+                DEFAULT_SBOT_HOST
+            );
+            DEFAULT_SBOT_HOST.to_string()
+        }
+        Err(_) => DEFAULT_SBOT_HOST.to_string(),
+    }
+}
+
+/// Return the path of the secret key file of the identity with which to
+/// connect to the sbot, if an identity other than that of the local go-sbot
+/// is used.
+///
+/// The path is read from the `GO_SBOT_KEY_PATH` environment variable. The
+/// keystore of the local go-sbot is used if the variable is unset or empty.
+pub fn key_path() -> Option<String> {
+    match env::var("GO_SBOT_KEY_PATH") {
+        Ok(path) if !path.trim().is_empty() => Some(path.trim().to_string()),
+        _ => None,
+    }
+}
+
+/// Read the secret key file (as written by go-sbot or patchwork) at the given
+/// path and return its content.
+pub fn read_key_file(path: &str) -> Result<Value, String> {
+    let secret =
+        fs::read_to_string(path).map_err(|e| format!("Failed to read key file {}: {}", path, e))?;
+    // Patchwork prefixes the JSON with comment lines.
+    let json: String = secret
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .collect();
+
+    serde_json::from_str(&json).map_err(|e| format!("Failed to parse key file {}: {}", path, e))
+}
+
+/// Return the public key of the identity of the secret key file at the given
+/// path, as read from its `id` field. The sbot is not contacted.
+pub fn key_file_identity(path: &str) -> Result<String, String> {
+    let identity = read_key_file(path)?
+        .get("id")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| format!("Key file {} holds no id", path))?;
+    utils::validate_public_key(&identity)?;
+
+    Ok(identity)
+}
+
+/// Return the path of the secret key file of the identity with which we
+/// connect to the sbot: the configured key path, if any, or else the secret
+/// key file of the local go-sbot (`~/.ssb-go/secret`).
+fn secret_key_path() -> Option<String> {
+    key_path().or_else(|| {
+        env::var("HOME")
+            .ok()
+            .map(|home| format!("{}/.ssb-go/secret", home))
+    })
+}
+
+/// Return the network key (also known as the caps key) with which to connect
+/// to the sbot, if a network other than the main network is used.
+///
+/// The key is read from the `GO_SBOT_NET_KEY` environment variable, as the
+/// base64 encoding of a 32 byte key. The key of the main network is used if
+/// the variable is unset, empty or malformed.
+pub fn network_key() -> Option<String> {
+    match env::var("GO_SBOT_NET_KEY") {
+        Ok(key) if key.trim().is_empty() => None,
+        Ok(key) => match utils::validate_network_key(key.trim()) {
+            Ok(_) => Some(key.trim().to_string()),
+            Err(e) => {
+                warn!(
+                    "Invalid GO_SBOT_NET_KEY ({}); using the key of the main network",
+                    e
+                );
+                None
+            }
+        },
+        Err(_) => None,
+    }
+}
+
+/// Return the port on which the local sbot listens.
+///
+/// The port is read from the `GO_SBOT_PORT` environment variable, falling
+/// back to the default port of go-sbot if the variable is unset.
+pub fn sbot_port() -> String {
+    env::var("GO_SBOT_PORT").unwrap_or_else(|_| "8021".to_string())
+}
+
 /// Return the public key of the local sbot instance.
 pub async fn whoami() -> Result<String, String> {
     let mut sbot = init_sbot().await?;
@@ -33,6 +412,26 @@ pub async fn whoami() -> Result<String, String> {
     sbot.whoami().await.map_err(|e| e.to_string())
 }
 
+/// Check whether the local sbot instance is reachable by performing a
+/// `whoami` call.
+pub async fn is_reachable() -> bool {
+    health_check().await.is_ok()
+}
+
+/// Check whether the local sbot instance responds to a `whoami` call,
+/// returning a diagnostic message if it does not.
+pub async fn health_check() -> Result<(), String> {
+    match whoami().await {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!(
+            "The sbot at {}:{} did not respond ({}). Please ensure the go-sbot is running",
+            sbot_host(),
+            sbot_port(),
+            e
+        )),
+    }
+}
+
 /// Check follow status.
 ///
 /// Is peer A (`public_key_a`) following peer B (`public_key_b`)?
@@ -49,20 +448,174 @@ pub async fn is_following(public_key_a: &str, public_key_b: &str) -> Result<Stri
         .map_err(|e| e.to_string())
 }
 
+/// Return the public keys of the peers followed by the given peer.
+///
+/// The list is empty if the feed of the peer is not replicated by the local
+/// sbot.
+pub async fn get_follows(public_key: &str) -> Result<Vec<String>, String> {
+    let mut sbot = init_sbot().await?;
+
+    let hops = FriendsHops {
+        max: 1,
+        start: Some(public_key.to_string()),
+        reverse: Some(false),
+    };
+
+    let follows = sbot.friends_hops(hops).await.map_err(|e| e.to_string())?;
+
+    // The starting peer is included in the hops at a distance of zero.
+    Ok(follows
+        .into_iter()
+        .filter(|follow| follow != public_key)
+        .collect())
+}
+
 /// Follow a peer.
+///
+/// Returns an error without publishing a follow message in read-only mode.
 pub async fn follow_peer(public_key: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
     let mut sbot = init_sbot().await?;
 
     sbot.follow(public_key).await.map_err(|e| e.to_string())
 }
 
+/// Block a peer, which also stops the replication of their feed.
+///
+/// Returns an error without publishing a block message in read-only mode.
+pub async fn block_peer(public_key: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
+    let mut sbot = init_sbot().await?;
+
+    sbot.block(public_key).await.map_err(|e| e.to_string())
+}
+
+/// Unblock a peer.
+///
+/// Returns an error without publishing an unblock message in read-only mode.
+pub async fn unblock_peer(public_key: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
+    let mut sbot = init_sbot().await?;
+
+    sbot.unblock(public_key).await.map_err(|e| e.to_string())
+}
+
+/// Accept an invite to a pub, which will then replicate with the local sbot.
+///
+/// Returns an error without using the invite in read-only mode.
+pub async fn use_invite(invite_code: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
+    let mut sbot = init_sbot().await?;
+
+    sbot.invite_use(invite_code)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 /// Unfollow a peer.
+///
+/// Returns an error without publishing an unfollow message in read-only mode.
 pub async fn unfollow_peer(public_key: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
     let mut sbot = init_sbot().await?;
 
     sbot.unfollow(public_key).await.map_err(|e| e.to_string())
 }
 
+/// Publish a post with the given text, returning the key of the new message.
+///
+/// Returns an error without publishing the post in read-only mode.
+pub async fn publish_post(text: &str) -> Result<String, String> {
+    ensure_not_read_only()?;
+    let mut sbot = init_sbot().await?;
+
+    sbot.publish_post(text).await.map_err(|e| e.to_string())
+}
+
+/// Publish a vote on the message with the given key: a value of 1 likes the
+/// message and a value of 0 unlikes it. Returns the key of the vote message.
+///
+/// Returns an error without publishing the vote in read-only mode.
+pub async fn publish_vote(msg_key: &str, value: i32) -> Result<String, String> {
+    ensure_not_read_only()?;
+    let mut sbot = init_sbot().await?;
+
+    let content: SsbMessageContent =
+        serde_json::from_value(vote_content(msg_key, value)).map_err(|e| e.to_string())?;
+
+    sbot.publish(content).await.map_err(|e| e.to_string())
+}
+
+/// Return the content of a vote message with the given value on the message
+/// with the given key.
+fn vote_content(msg_key: &str, value: i32) -> Value {
+    let expression = if value > 0 { "Like" } else { "Unlike" };
+
+    serde_json::json!({
+        "type": "vote",
+        "vote": {
+            "link": msg_key,
+            "value": value,
+            "expression": expression,
+        },
+    })
+}
+
+/// Return the self-description of the peer associated with the given public
+/// key, as set by their latest about message with a description.
+///
+/// An empty string is returned if the peer has not described themselves.
+pub async fn get_description(public_key: &str) -> Result<String, String> {
+    let mut sbot = init_sbot().await?;
+
+    sbot.get_description(public_key)
+        .await
+        .map(|description| description.unwrap_or_default())
+        .map_err(|e| e.to_string())
+}
+
+/// Return the blob ID of the profile image of the peer associated with the
+/// given public key, as set by their latest about message with an image.
+pub async fn get_profile_image_blob(public_key: &str) -> Result<Option<String>, String> {
+    let mut sbot = init_sbot().await?;
+
+    let image = sbot
+        .get_latest_about_message(public_key, "image")
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(image.as_deref().and_then(image_blob_id))
+}
+
+/// Return the blob ID referenced by the given image of an about message,
+/// which is either a blob ID or an object linking to one.
+fn image_blob_id(image: &str) -> Option<String> {
+    let is_blob_id = |id: &&str| id.starts_with('&');
+
+    if is_blob_id(&image) {
+        return Some(image.to_string());
+    }
+
+    match serde_json::from_str(image).ok()? {
+        Value::String(link) => Some(link),
+        Value::Object(image) => image.get("link")?.as_str().map(|link| link.to_string()),
+        _ => None,
+    }
+    .filter(|link| is_blob_id(&link.as_str()))
+}
+
+/// Read the blob with the given ID from the blob store of the local sbot.
+///
+/// Returns an error if the blob is not stored locally (e.g. because it has
+/// not been replicated).
+pub async fn fetch_blob(blob_id: &str) -> Result<Vec<u8>, String> {
+    let blob_path = golgi::blobs::get_blob_path(blob_id).map_err(|e| e.to_string())?;
+
+    async_std::fs::read(&blob_path)
+        .await
+        .map_err(|e| e.to_string())
+}
+
 /// Return the name (self-identifier) for the peer associated with the given
 /// public key.
 ///
@@ -73,9 +626,76 @@ pub async fn get_name(public_key: &str) -> Result<String, String> {
     sbot.get_name(public_key).await.map_err(|e| e.to_string())
 }
 
+/// Return the sequence number of the latest about message published by the
+/// peer associated with the given public key about themselves.
+///
+/// Zero is returned if the peer has not published any such about message. An
+/// error is returned if the about stream could not be read in full, in which
+/// case the position of the stream is unknown.
+pub async fn get_latest_about_sequence(public_key: &str) -> Result<u64, String> {
+    let mut sbot = init_sbot().await?;
+
+    let about_stream = sbot
+        .get_about_message_stream(public_key)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    futures::pin_mut!(about_stream);
+
+    let mut messages = Vec::new();
+    while let Some(res) = about_stream.next().await {
+        messages.push(res.map_err(|e| e.to_string())?);
+    }
+
+    Ok(latest_sequence_by(public_key, &messages))
+}
+
+/// Return the greatest sequence number of the given messages which were
+/// authored by the peer associated with the given public key, or zero if
+/// there are none.
+fn latest_sequence_by(public_key: &str, messages: &[SsbMessageValue]) -> u64 {
+    messages
+        .iter()
+        .filter(|msg| msg.author == public_key)
+        .map(|msg| msg.sequence)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Fetch the names of the peers represented by the given public keys.
+///
+/// golgi offers no batched RPC for names, so the requests are made
+/// concurrently, with a bounded number in flight at any one time. The names
+/// which were fetched successfully are returned; failures are logged.
+pub async fn get_names(public_keys: &[String]) -> HashMap<String, String> {
+    let requests = public_keys.iter().cloned().map(|public_key| async move {
+        let res = get_name(&public_key).await;
+        (public_key, res)
+    });
+    let responses = futures::StreamExt::buffer_unordered(
+        futures::stream::iter(requests),
+        MAX_CONCURRENT_NAME_REQUESTS,
+    );
+
+    futures::pin_mut!(responses);
+
+    let mut names = HashMap::new();
+    while let Some((public_key, res)) = responses.next().await {
+        match res {
+            Ok(name) => {
+                names.insert(public_key, name);
+            }
+            Err(e) => warn!("Failed to fetch name for {}: {}", public_key, e),
+        }
+    }
+
+    names
+}
+
 /// Check the follow status of a remote peer and follow them if not already
 /// following.
 pub async fn follow_if_not_following(remote_peer: &str) -> Result<(), String> {
+    ensure_not_read_only()?;
     if let Ok(whoami) = whoami().await {
         match is_following(&whoami, remote_peer).await {
             Ok(status) if status.as_str() == "false" => match follow_peer(remote_peer).await {
@@ -111,6 +731,7 @@ pub async fn follow_if_not_following(remote_peer: &str) -> Result<(), String> {
 /// Check the follow status of a remote peer and unfollow them if already
 /// following.
 pub async fn unfollow_if_following(remote_peer: &str) -> Result<(), String> {
+    ensure_not_read_only()?;
     if let Ok(whoami) = whoami().await {
         match is_following(&whoami, remote_peer).await {
             Ok(status) if status.as_str() == "true" => {
@@ -141,12 +762,13 @@ pub async fn unfollow_if_following(remote_peer: &str) -> Result<(), String> {
 
 /// Return a stream of messages authored by the given public key.
 ///
-/// This returns all messages regardless of type.
+/// This returns all messages regardless of type. An error is returned if the
+/// sbot cannot be reached or the stream cannot be opened.
 pub async fn get_message_stream(
     public_key: &str,
     sequence_number: u64,
-) -> impl futures::Stream<Item = Result<SsbMessageKVT, GolgiError>> {
-    let mut sbot = init_sbot().await.unwrap();
+) -> Result<impl futures::Stream<Item = Result<SsbMessageKVT, GolgiError>>, String> {
+    let mut sbot = init_sbot().await?;
 
     let history_stream_args = CreateHistoryStream::new(public_key.to_string())
         .keys_values(true, true)
@@ -154,7 +776,375 @@ pub async fn get_message_stream(
 
     sbot.create_history_stream(history_stream_args)
         .await
-        .unwrap()
+        .map_err(|e| e.to_string())
+}
+
+/// Return the public keys of the feeds in which private messages (DMs) are
+/// sought: our own feed and the feeds of the peers we follow.
+pub async fn get_private_message_feeds() -> Result<Vec<String>, String> {
+    let mut sbot = init_sbot().await?;
+    let mut feeds = vec![sbot.whoami().await.map_err(|e| e.to_string())?];
+    feeds.extend(sbot.get_follows().await.map_err(|e| e.to_string())?);
+
+    Ok(feeds)
+}
+
+/// Read the secret key of the identity with which we connect to the sbot and
+/// return the Curve25519 secret key with which private messages are
+/// decrypted.
+pub fn read_secret_key() -> Result<[u8; 32], String> {
+    let secret_key_path =
+        secret_key_path().ok_or_else(|| "Failed to locate the secret key file".to_string())?;
+
+    private_box::read_secret_key(&secret_key_path)
+}
+
+/// Decrypt the private messages (DMs) in the given message stream with the
+/// given secret key and return the latest sequence number along with the
+/// messages which we can read.
+///
+/// Only post-type messages are returned; messages which were not encrypted
+/// for us, or which cannot be decrypted, are skipped. The returned sequence
+/// number is that of the latest message of any type, so that the stream can
+/// be resumed from it.
+pub async fn get_private_messages(
+    history_stream: impl futures::Stream<Item = Result<SsbMessageKVT, GolgiError>>,
+    secret_key: &[u8; 32],
+) -> (u64, Vec<Post>) {
+    let mut latest_sequence = 0;
+    let mut private_messages = Vec::new();
+
+    futures::pin_mut!(history_stream);
+
+    while let Some(res) = history_stream.next().await {
+        match res {
+            Ok(msg) => {
+                latest_sequence = latest_sequence.max(msg.value.sequence);
+                if let Some(post) = unbox_post(&msg, secret_key) {
+                    private_messages.push(post)
+                }
+            }
+            Err(err) => warn!("err: {:?}", err),
+        }
+    }
+
+    (latest_sequence, private_messages)
+}
+
+/// Decrypt the given private message with the given secret key and build an
+/// instance of the Post struct from it, if it is a post-type message which
+/// we can read.
+fn unbox_post(msg: &SsbMessageKVT, secret_key: &[u8; 32]) -> Option<Post> {
+    let content = private_box::unbox(msg.value.content.as_str()?, secret_key)?;
+    if content.get("type").and_then(Value::as_str) != Some("post") {
+        return None;
+    }
+
+    content
+        .as_object()
+        .map(|content_map| build_post(msg, content_map))
+}
+
+/// Build an instance of the Post struct from the given message and its
+/// content.
+fn build_post(msg: &SsbMessageKVT, content_map: &Map<String, Value>) -> Post {
+    let text = match content_map.get_key_value("text") {
+        Some(value) => value.1.to_string(),
+        None => String::from(""),
+    };
+    let timestamp = msg.value.timestamp.round() as i64 / 1000;
+    let subject = post_subject(&text);
+
+    Post::builder(msg.key.to_owned())
+        .author(msg.value.author.to_owned())
+        .channels(utils::extract_channels(&text))
+        .text(text)
+        .sequence(msg.value.sequence)
+        .timestamp(timestamp)
+        .subject(subject)
+        .quotes(quoted_message(content_map))
+        .build()
+}
+
+/// Return the subject of a post with the given text (a JSON string): the first
+/// `SUBJECT_LENGTH` characters of the text, with markdown syntax stripped.
+/// Posts whose stripped text fits within the limit have no subject, since
+/// they are shown in full.
+fn post_subject(text: &str) -> Option<String> {
+    let stripped = strip_markdown(&utils::decode_text(text));
+
+    // Truncate on a character boundary, so that multibyte characters are
+    // never split.
+    stripped
+        .char_indices()
+        .nth(SUBJECT_LENGTH)
+        .map(|(end, _)| stripped[..end].trim_end().to_string())
+}
+
+/// Strip common markdown syntax from the given text: headers, block quotes,
+/// list markers, emphasis, inline code and links (of which only the label is
+/// kept). Lines are joined and runs of whitespace collapsed into one space.
+fn strip_markdown(text: &str) -> String {
+    let mut stripped = String::new();
+
+    for line in text.lines() {
+        let mut line = line.trim_start().trim_start_matches('>').trim_start();
+
+        // Headers need a space after the hashes, unlike hashtags.
+        let hashes = line.len() - line.trim_start_matches('#').len();
+        if (1..=6).contains(&hashes) && line[hashes..].starts_with(' ') {
+            line = &line[hashes..];
+        }
+        for marker in ["- ", "* ", "+ "] {
+            if let Some(item) = line.strip_prefix(marker) {
+                line = item;
+                break;
+            }
+        }
+
+        stripped.push_str(&strip_inline_markdown(line));
+        stripped.push(' ');
+    }
+
+    stripped.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Strip the emphasis, inline code and link syntax from a line of markdown.
+fn strip_inline_markdown(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(c) = rest.chars().next() {
+        // Replace links and images (`[label](url)`, `![alt](url)`) with their
+        // label.
+        let label_start = match c {
+            '!' if rest.starts_with("![") => Some(2),
+            '[' => Some(1),
+            _ => None,
+        };
+        if let Some(label_start) = label_start {
+            if let Some(label_len) = rest[label_start..].find("](") {
+                let label_end = label_start + label_len;
+                if let Some(url_len) = rest[label_end + 2..].find(')') {
+                    stripped.push_str(&rest[label_start..label_end]);
+                    rest = &rest[label_end + 2 + url_len + 1..];
+                    continue;
+                }
+            }
+        }
+
+        // Single underscores are left alone, since they are common in names.
+        if rest.starts_with("__") || rest.starts_with("~~") {
+            rest = &rest[2..];
+            continue;
+        }
+        if c != '*' && c != '`' {
+            stripped.push(c);
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+
+    stripped
+}
+
+/// Return the key of the message quoted by a post with the given content, if
+/// any: the first message mentioned by the post.
+///
+/// Forks are not quotes here: a fork carries the root of the thread from
+/// which it was forked, so it is ingested as a reply rather than as a root
+/// post.
+fn quoted_message(content_map: &Map<String, Value>) -> Option<String> {
+    // Mentions are either links or objects with a link.
+    content_map
+        .get("mentions")?
+        .as_array()?
+        .iter()
+        .filter_map(|mention| match mention {
+            Value::String(link) => Some(link.as_str()),
+            Value::Object(mention) => mention.get("link")?.as_str(),
+            _ => None,
+        })
+        .find(|key| key.starts_with('%'))
+        .map(|key| key.to_string())
+}
+
+/// Filter the given messages and return the replies among them, each paired
+/// with the key of the root post of the thread.
+pub fn get_replies(msgs: &[SsbMessageKVT]) -> Vec<(String, Post)> {
+    msgs.iter()
+        .filter(|msg| msg.value.is_message_type(SsbMessageContentType::Post))
+        .filter_map(|msg| match &msg.value.content {
+            Value::Object(content_map) => {
+                let root = content_map.get("root")?.as_str()?;
+                Some((root.to_string(), build_post(msg, content_map)))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Filter the given messages and return the replies among them to the root
+/// post with the given key.
+fn replies_to(msgs: &[SsbMessageKVT], root_key: &str) -> Vec<Post> {
+    get_replies(msgs)
+        .into_iter()
+        .filter(|(root, _)| root == root_key)
+        .map(|(_, reply)| reply)
+        .collect()
+}
+
+/// Fetch the replies of any author to the root post with the given key, by
+/// streaming the messages of the thread of the post.
+///
+/// Only the replies in feeds which are replicated by the local sbot are
+/// returned. The replies are returned without being stored in the database.
+pub async fn get_thread_replies(root_key: &str) -> Result<Vec<Post>, String> {
+    let mut sbot = init_sbot().await?;
+
+    let thread_args = TanglesThread::new(root_key.to_string()).keys_values(true, true);
+    let thread_stream = sbot
+        .tangles_thread(thread_args)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    futures::pin_mut!(thread_stream);
+
+    let mut msgs = Vec::new();
+    while let Some(res) = thread_stream.next().await {
+        match res {
+            Ok(msg) => msgs.push(msg),
+            Err(err) => warn!("err: {:?}", err),
+        }
+    }
+
+    Ok(replies_to(&msgs, root_key))
+}
+
+/// Search the messages authored by the given public key for the post-type
+/// message with the given key.
+///
+/// The post is returned without being stored in the database. `None` is
+/// returned if the message is not found in the feed, for example because the
+/// feed of the author is not replicated by the local sbot, or if the message
+/// is not a post.
+pub async fn get_post(public_key: &str, msg_key: &str) -> Result<Option<Post>, String> {
+    let mut sbot = init_sbot().await?;
+
+    let history_stream_args =
+        CreateHistoryStream::new(public_key.to_string()).keys_values(true, true);
+    let history_stream = sbot
+        .create_history_stream(history_stream_args)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    futures::pin_mut!(history_stream);
+
+    while let Some(res) = history_stream.next().await {
+        match res {
+            Ok(msg) if msg.key == msg_key => {
+                if msg.value.is_message_type(SsbMessageContentType::Post) {
+                    if let Value::Object(content_map) = &msg.value.content {
+                        return Ok(Some(build_post(&msg, content_map)));
+                    }
+                }
+                return Ok(None);
+            }
+            Ok(_) => (),
+            Err(err) => warn!("err: {:?}", err),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Fetch the message with the given key, authored by the given public key,
+/// including its key, value and timestamp.
+///
+/// If the sequence number of the message is known, only that part of the
+/// feed of the author is read; otherwise the entire feed is searched. `None`
+/// is returned if the message is not found.
+pub async fn get_message(
+    public_key: &str,
+    msg_key: &str,
+    sequence: Option<u64>,
+) -> Result<Option<SsbMessageKVT>, String> {
+    let mut sbot = init_sbot().await?;
+
+    let mut history_stream_args =
+        CreateHistoryStream::new(public_key.to_string()).keys_values(true, true);
+    if let Some(sequence) = sequence {
+        history_stream_args = history_stream_args
+            .after_seq(sequence.saturating_sub(1))
+            .limit(1);
+    }
+    let history_stream = sbot
+        .create_history_stream(history_stream_args)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    futures::pin_mut!(history_stream);
+
+    while let Some(res) = history_stream.next().await {
+        match res {
+            Ok(msg) if msg.key == msg_key => return Ok(Some(msg)),
+            Ok(_) => (),
+            Err(err) => warn!("err: {:?}", err),
+        }
+    }
+
+    Ok(None)
+}
+
+/// Return the public keys of the feeds whose votes are tallied as likes: our
+/// own feed and the feeds of the peers we follow. Our own votes are tallied
+/// so that the posts we have liked are known, even if they were liked with
+/// another client.
+pub async fn get_vote_feeds() -> Result<Vec<String>, String> {
+    let mut sbot = init_sbot().await?;
+    let mut feeds = vec![sbot.whoami().await.map_err(|e| e.to_string())?];
+    feeds.extend(sbot.get_follows().await.map_err(|e| e.to_string())?);
+
+    Ok(feeds)
+}
+
+/// Collect the votes in the given message stream and return the latest
+/// sequence number along with the votes, in order of publication.
+///
+/// Each vote is given as the key of the voted-on message and whether it is a
+/// like; a vote with a value of zero (an unvote) cancels an earlier like. The
+/// returned sequence number is that of the latest message of any type, so
+/// that the stream can be resumed from it.
+pub async fn get_votes(
+    history_stream: impl futures::Stream<Item = Result<SsbMessageKVT, GolgiError>>,
+) -> (u64, Vec<(String, bool)>) {
+    let mut latest_sequence = 0;
+    let mut votes = Vec::new();
+
+    futures::pin_mut!(history_stream);
+
+    while let Some(res) = history_stream.next().await {
+        match res {
+            Ok(msg) => {
+                latest_sequence = latest_sequence.max(msg.value.sequence);
+                if msg.value.is_message_type(SsbMessageContentType::Vote) {
+                    votes.extend(parse_vote(&msg.value.content));
+                }
+            }
+            Err(err) => warn!("err: {:?}", err),
+        }
+    }
+
+    (latest_sequence, votes)
+}
+
+/// Return the key of the message voted on by the vote with the given content
+/// and whether the vote is a like.
+fn parse_vote(content: &Value) -> Option<(String, bool)> {
+    let vote = content.get("vote")?;
+    let link = vote.get("link").and_then(Value::as_str)?;
+    let liked = vote.get("value").and_then(Value::as_i64).unwrap_or(0) > 0;
+
+    Some((link.to_string(), liked))
 }
 
 /// Filter a stream of messages and return a vector of root posts.
@@ -162,40 +1152,65 @@ pub async fn get_message_stream(
 /// Each returned vector element includes the key of the post, the content
 /// text, the date the post was published, the sequence number of the post
 /// and whether it is read or unread.
+///
+/// A post is flagged as a duplicate if its text matches that of one of the
+/// `duplicate_window` preceding posts by the same author. The texts of the
+/// most recent posts which have already been stored are given, oldest first,
+/// in `previous_texts`.
+///
+/// A post is flagged as filtered, and marked as read, if its text contains
+/// one of the given `filter_words`.
+///
+/// Private messages, whose content is an encrypted string, are skipped; they
+/// are fetched separately (see `get_private_messages`). The returned sequence
+/// number is that of the latest message of any type.
 pub async fn get_root_posts(
     history_stream: impl futures::Stream<Item = Result<SsbMessageKVT, GolgiError>>,
+    previous_texts: Vec<String>,
+    duplicate_window: usize,
+    filter_words: &[String],
 ) -> (u64, Vec<Post>) {
     let mut latest_sequence = 0;
     let mut posts = Vec::new();
 
+    let mut recent_texts: VecDeque<String> = previous_texts.into();
+    while recent_texts.len() > duplicate_window {
+        recent_texts.pop_front();
+    }
+
     futures::pin_mut!(history_stream);
 
     while let Some(res) = history_stream.next().await {
         match res {
             Ok(msg) => {
+                // Every message counts towards the latest sequence number,
+                // so that skipped messages are not fetched again.
+                latest_sequence = latest_sequence.max(msg.value.sequence);
+
+                // The content of private messages is an encrypted string.
+                if msg.value.content.is_string() {
+                    debug!("Skipping private message {}", msg.key);
+                    continue;
+                }
+
                 if msg.value.is_message_type(SsbMessageContentType::Post) {
                     let content = msg.value.content.to_owned();
                     if let Value::Object(content_map) = content {
                         if !content_map.contains_key("root") {
-                            latest_sequence = msg.value.sequence;
-
-                            let text = match content_map.get_key_value("text") {
-                                Some(value) => value.1.to_string(),
-                                None => String::from(""),
-                            };
-                            let timestamp = msg.value.timestamp.round() as i64 / 1000;
-                            let datetime = NaiveDateTime::from_timestamp(timestamp, 0);
-                            let date = datetime.format("%d %b %Y").to_string();
-                            let subject = text.get(0..52).map(|s| s.to_string());
-
-                            let post = Post::new(
-                                msg.key.to_owned(),
-                                text,
-                                date,
-                                msg.value.sequence,
-                                timestamp,
-                                subject,
-                            );
+                            let mut post = build_post(&msg, &content_map);
+                            if recent_texts.contains(&post.text) {
+                                post.duplicate = true;
+                            }
+                            if utils::contains_filter_word(&post.text, filter_words) {
+                                post.filtered = true;
+                                post.read = true;
+                            }
+                            if duplicate_window > 0 {
+                                if recent_texts.len() == duplicate_window {
+                                    recent_texts.pop_front();
+                                }
+                                recent_texts.push_back(post.text.clone());
+                            }
 
                             posts.push(post)
                         }
@@ -211,3 +1226,386 @@ pub async fn get_root_posts(
 
     (latest_sequence, posts)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode the given text as a JSON string, as post text is stored.
+    fn json_text(text: &str) -> String {
+        serde_json::to_string(text).unwrap()
+    }
+
+    /// Build an about message with the given author and sequence number.
+    fn about_message(author: &str, sequence: u64) -> SsbMessageValue {
+        serde_json::from_value(serde_json::json!({
+            "previous": null,
+            "author": author,
+            "sequence": sequence,
+            "timestamp": 1650000000000.0,
+            "hash": "sha256",
+            "content": { "type": "about", "about": author, "name": "glyph" },
+            "signature": "sig",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn key_file_identity_is_read_from_the_id_field() {
+        let peer = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        let path = env::temp_dir().join(format!("lykin-secret-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let secret = serde_json::json!({ "curve": "ed25519", "id": peer });
+        fs::write(path, format!("# this is your SECRET name.\n{}", secret)).unwrap();
+        assert_eq!(key_file_identity(path).unwrap(), peer);
+
+        fs::write(path, "{}").unwrap();
+        assert!(key_file_identity(path).is_err());
+        fs::remove_file(path).unwrap();
+        assert!(key_file_identity(path).is_err());
+    }
+
+    #[test]
+    fn latest_about_sequence_is_the_greatest_by_the_peer() {
+        let peer = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        let other = "@zqshk7o2Rpd/OaZ/MxH6xXONgonP1jH+edK9+GZb/NY=.ed25519";
+
+        assert_eq!(latest_sequence_by(peer, &[]), 0);
+
+        let messages = vec![
+            about_message(peer, 12),
+            about_message(other, 90),
+            about_message(peer, 37),
+            about_message(peer, 5),
+        ];
+        assert_eq!(latest_sequence_by(peer, &messages), 37);
+    }
+
+    #[test]
+    fn subject_strips_markdown() {
+        let text = "## Release notes\n\nThe **new** [lykin](https://example.com) \
+                    release has `fixes` and __more__ for #rust";
+        assert_eq!(
+            post_subject(&json_text(text)).as_deref(),
+            Some("Release notes The new lykin release has fixes and mo")
+        );
+        assert_eq!(
+            strip_markdown("> quoted\n- item ![alt](&blob.sha256) ~~gone~~ snake_case"),
+            "quoted item alt gone snake_case"
+        );
+    }
+
+    #[test]
+    fn short_posts_have_no_subject() {
+        assert_eq!(post_subject(&json_text("A short post")), None);
+        // The limit applies to the stripped text.
+        let text = format!("# {}", "a".repeat(SUBJECT_LENGTH));
+        assert_eq!(post_subject(&json_text(&text)), None);
+    }
+
+    #[test]
+    fn subject_is_truncated_on_character_boundaries() {
+        let text = "🦀".repeat(SUBJECT_LENGTH + 10);
+        let subject = post_subject(&json_text(&text)).unwrap();
+        assert_eq!(subject.chars().count(), SUBJECT_LENGTH);
+        assert!(subject.chars().all(|c| c == '🦀'));
+
+        // The 52nd byte falls inside an accented character.
+        let text = format!("{}é and more text", "a".repeat(51));
+        let subject = post_subject(&json_text(&text)).unwrap();
+        assert_eq!(subject, format!("{}é", "a".repeat(51)));
+    }
+
+    fn post_msg(sequence: u64, text: &str) -> Result<SsbMessageKVT, GolgiError> {
+        msg(
+            sequence,
+            serde_json::json!({ "type": "post", "text": text }),
+        )
+    }
+
+    fn msg(sequence: u64, content: Value) -> Result<SsbMessageKVT, GolgiError> {
+        let msg = serde_json::json!({
+            "key": format!("%post{}", sequence),
+            "value": {
+                "previous": null,
+                "author": "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519",
+                "sequence": sequence,
+                "timestamp": 1621252800000.0,
+                "hash": "sha256",
+                "content": content,
+                "signature": "sig"
+            },
+            "timestamp": null,
+            "rts": null
+        });
+
+        Ok(serde_json::from_value(msg).unwrap())
+    }
+
+    fn vote(link: &str, value: i64) -> Value {
+        serde_json::json!({ "type": "vote", "vote": { "link": link, "value": value } })
+    }
+
+    #[test]
+    fn parse_vote_reads_likes_and_unvotes() {
+        assert_eq!(
+            parse_vote(&vote("%post1", 1)),
+            Some(("%post1".to_string(), true))
+        );
+        assert_eq!(
+            parse_vote(&vote("%post1", 0)),
+            Some(("%post1".to_string(), false))
+        );
+        assert_eq!(parse_vote(&serde_json::json!({ "type": "vote" })), None);
+    }
+
+    #[test]
+    fn vote_content_is_parsed_as_like_or_unlike() {
+        assert_eq!(
+            parse_vote(&vote_content("%post1", 1)),
+            Some(("%post1".to_string(), true))
+        );
+        assert_eq!(
+            parse_vote(&vote_content("%post1", 0)),
+            Some(("%post1".to_string(), false))
+        );
+    }
+
+    #[test]
+    fn get_votes_returns_votes_in_order_and_latest_sequence() {
+        let messages = vec![
+            msg(4, vote("%post1", 1)),
+            msg(5, serde_json::json!({ "type": "post", "text": "hi" })),
+            msg(6, vote("%post1", 0)),
+        ];
+        let (latest_sequence, votes) =
+            async_std::task::block_on(get_votes(futures::stream::iter(messages)));
+
+        assert_eq!(latest_sequence, 6);
+        assert_eq!(
+            votes,
+            vec![("%post1".to_string(), true), ("%post1".to_string(), false)]
+        );
+    }
+
+    #[test]
+    fn image_blob_id_accepts_blob_ids_and_links() {
+        let blob_id = "&uv0Hk0hKcrgL9wSkXMRyvBq3XrUmSLh/bf6D3vXF6hY=.sha256";
+        assert_eq!(image_blob_id(blob_id), Some(blob_id.to_string()));
+
+        let link = serde_json::json!({ "link": blob_id, "size": 1024 }).to_string();
+        assert_eq!(image_blob_id(&link), Some(blob_id.to_string()));
+
+        let quoted = serde_json::json!(blob_id).to_string();
+        assert_eq!(image_blob_id(&quoted), Some(blob_id.to_string()));
+
+        assert_eq!(image_blob_id("%not-a-blob.sha256"), None);
+        assert_eq!(image_blob_id("{\"link\": \"%msg\"}"), None);
+    }
+
+    #[test]
+    fn liked_posts_are_only_recorded_once() {
+        let liked_posts = LikedPosts::default();
+        assert!(liked_posts.insert("%post1"));
+        assert!(!liked_posts.insert("%post1"));
+        assert!(liked_posts.contains("%post1"));
+
+        liked_posts.remove("%post1");
+        assert!(!liked_posts.contains("%post1"));
+    }
+
+    #[test]
+    fn follow_cache_expires_entries() {
+        let cache = FollowCache::default();
+        let fetched_at = Instant::now();
+        cache.insert("@a", vec!["@b".to_string()], fetched_at);
+
+        assert_eq!(cache.cached("@a", fetched_at), Some(vec!["@b".to_string()]));
+        assert_eq!(cache.cached("@other", fetched_at), None);
+        assert_eq!(cache.cached("@a", fetched_at + FOLLOW_CACHE_TTL), None);
+    }
+
+    #[test]
+    fn get_root_posts_flags_repeated_posts_as_duplicate() {
+        let msgs = vec![
+            post_msg(1, "hello"),
+            post_msg(2, "hello"),
+            post_msg(3, "world"),
+        ];
+        let (latest_sequence, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(msgs),
+            Vec::new(),
+            DEFAULT_DUPLICATE_WINDOW,
+            &[],
+        ));
+
+        assert_eq!(latest_sequence, 3);
+        let duplicates: Vec<bool> = posts.iter().map(|post| post.duplicate).collect();
+        assert_eq!(duplicates, vec![false, true, false]);
+    }
+
+    #[test]
+    fn get_root_posts_compares_against_previous_texts_within_window() {
+        let previous_texts = vec!["\"hello\"".to_string(), "\"other\"".to_string()];
+
+        let (_, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(vec![post_msg(3, "hello")]),
+            previous_texts.clone(),
+            1,
+            &[],
+        ));
+        assert!(!posts[0].duplicate);
+
+        let (_, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(vec![post_msg(3, "hello"), post_msg(4, "world")]),
+            previous_texts,
+            2,
+            &[],
+        ));
+        assert!(posts[0].duplicate);
+        assert!(!posts[1].duplicate);
+    }
+
+    #[test]
+    fn get_root_posts_filters_and_marks_read_matching_posts() {
+        let filter_words = vec!["spoiler".to_string()];
+        let msgs = vec![post_msg(1, "Spoiler: it ends"), post_msg(2, "spoilers")];
+        let (_, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(msgs),
+            Vec::new(),
+            DEFAULT_DUPLICATE_WINDOW,
+            &filter_words,
+        ));
+
+        assert!(posts[0].filtered);
+        assert!(posts[0].read);
+        assert!(!posts[1].filtered);
+        assert!(!posts[1].read);
+    }
+
+    #[test]
+    fn get_root_posts_skips_private_messages_but_tracks_their_sequence() {
+        let msgs = vec![
+            post_msg(1, "hello"),
+            msg(2, Value::String("c2VjcmV0.box".to_string())),
+        ];
+        let (latest_sequence, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(msgs),
+            Vec::new(),
+            DEFAULT_DUPLICATE_WINDOW,
+            &[],
+        ));
+
+        assert_eq!(latest_sequence, 2);
+        assert_eq!(posts.len(), 1);
+        assert_eq!(posts[0].key, "%post1");
+    }
+
+    #[test]
+    fn get_private_messages_skips_unreadable_messages_but_tracks_their_sequence() {
+        let msgs = vec![
+            post_msg(1, "hello"),
+            msg(2, Value::String("c2VjcmV0.box".to_string())),
+        ];
+        let (latest_sequence, messages) =
+            async_std::task::block_on(get_private_messages(futures::stream::iter(msgs), &[3; 32]));
+
+        assert_eq!(latest_sequence, 2);
+        assert!(messages.is_empty());
+    }
+
+    #[test]
+    fn get_root_posts_records_quoted_messages() {
+        let msgs = vec![
+            post_msg(1, "hello"),
+            msg(
+                2,
+                serde_json::json!({
+                    "type": "post",
+                    "text": "forked",
+                    "root": "%root",
+                    "fork": "%quoted1"
+                }),
+            ),
+            msg(
+                3,
+                serde_json::json!({
+                    "type": "post",
+                    "text": "see this",
+                    "mentions": [{ "link": "@someone" }, { "link": "%quoted2" }]
+                }),
+            ),
+        ];
+        let (_, posts) = async_std::task::block_on(get_root_posts(
+            futures::stream::iter(msgs),
+            Vec::new(),
+            DEFAULT_DUPLICATE_WINDOW,
+            &[],
+        ));
+
+        let quotes: Vec<Option<&str>> = posts.iter().map(|post| post.quotes.as_deref()).collect();
+        // The fork carries a root, so it is not a root post.
+        assert_eq!(quotes, vec![None, Some("%quoted2")]);
+    }
+
+    #[test]
+    fn get_replies_pairs_replies_with_their_root() {
+        let mut reply: SsbMessageKVT = post_msg(2, "a reply").unwrap();
+        reply.value.content["root"] = Value::String("%post1".to_string());
+        let msgs = vec![post_msg(1, "a root post").unwrap(), reply];
+
+        let replies = get_replies(&msgs);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].0, "%post1");
+        assert_eq!(replies[0].1.key, "%post2");
+    }
+
+    #[test]
+    fn replies_to_keeps_only_replies_to_the_given_root() {
+        let mut reply: SsbMessageKVT = post_msg(2, "a reply").unwrap();
+        reply.value.content["root"] = Value::String("%post1".to_string());
+        let mut other_reply: SsbMessageKVT = post_msg(3, "another reply").unwrap();
+        other_reply.value.content["root"] = Value::String("%other".to_string());
+        let msgs = vec![post_msg(1, "a root post").unwrap(), reply, other_reply];
+
+        let replies = replies_to(&msgs, "%post1");
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].key, "%post2");
+    }
+
+    #[test]
+    fn format_date_applies_the_given_format() {
+        // 17 May 2021 12:00:00 UTC.
+        let timestamp = 1621252800;
+        assert_eq!(format_date(timestamp, DEFAULT_DATE_FORMAT), "17 May 2021");
+        assert_eq!(format_date(timestamp, "%Y-%m-%d"), "2021-05-17");
+        assert_eq!(format_date(timestamp, "%m/%d/%y %H:%M"), "05/17/21 12:00");
+    }
+
+    #[test]
+    fn format_date_skips_out_of_range_timestamps() {
+        assert_eq!(format_date(i64::MAX, DEFAULT_DATE_FORMAT), "");
+        assert_eq!(format_date(i64::MIN, DEFAULT_DATE_FORMAT), "");
+    }
+
+    #[test]
+    fn parse_subscribe_fetch_accepts_none_latest_and_all() {
+        assert_eq!(parse_subscribe_fetch("none"), Ok(SubscribeFetch::Latest(0)));
+        assert_eq!(
+            parse_subscribe_fetch("latest:20"),
+            Ok(SubscribeFetch::Latest(20))
+        );
+        assert_eq!(parse_subscribe_fetch("all"), Ok(SubscribeFetch::All));
+        assert!(parse_subscribe_fetch("latest:").is_err());
+        assert!(parse_subscribe_fetch("latest:-1").is_err());
+        assert!(parse_subscribe_fetch("some").is_err());
+    }
+
+    #[test]
+    fn validate_date_format_rejects_invalid_formats() {
+        assert!(validate_date_format(DEFAULT_DATE_FORMAT).is_ok());
+        assert!(validate_date_format("%Y-%m-%d").is_ok());
+        assert!(validate_date_format("%Y-%m-%").is_err());
+    }
+}
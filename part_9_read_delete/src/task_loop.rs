@@ -1,71 +1,1025 @@
-use async_std::{channel::Receiver, task};
-use log::{info, warn};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
 
-use crate::{sbot, Database};
+use async_std::{
+    channel::Receiver,
+    sync::Mutex,
+    task::{self, JoinHandle},
+};
+use chrono::Utc;
+use futures::{
+    future::{AbortHandle, Abortable, Aborted, BoxFuture},
+    stream::FuturesUnordered,
+    Future, FutureExt, Stream, StreamExt,
+};
+use golgi::{messages::SsbMessageKVT, GolgiError};
+use log::{debug, info, warn};
+use rocket::tokio::sync::broadcast;
+use serde::Serialize;
 
-async fn fetch_posts_and_update_db(db: &Database, peer_id: String, after_sequence: u64) {
-    let peer_msgs = sbot::get_message_stream(&peer_id, after_sequence).await;
-    let (latest_sequence, root_posts) = sbot::get_root_posts(peer_msgs).await;
+use crate::{
+    activity::ActivityLog,
+    db::Post,
+    sbot,
+    webhook::{NewPosts, WebhookBatch},
+    Database,
+};
 
-    match db.add_post_batch(&peer_id, root_posts) {
+/// The number of messages after which the posts fetched for a peer are
+/// written to the database, along with the sequence number reached.
+const FETCH_PROGRESS_INTERVAL: usize = 100;
+
+/// The number of times the opening of a message stream is retried after the
+/// first attempt fails (e.g. because the sbot is briefly unavailable).
+const FETCH_RETRIES: u32 = 3;
+
+/// The delay before the first retry of a failed fetch. The delay is doubled
+/// for each subsequent retry.
+const FETCH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// The maximum number of fetches (of posts, names, descriptions or private
+/// messages) which run at the same time.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+
+/// The number of progress updates buffered for each subscriber. Updates
+/// missed by a slow subscriber are skipped.
+const PROGRESS_CAPACITY: usize = 64;
+
+/// The delay between the name requests made when fetching the names of all
+/// peers, so that the sbot is not flooded with requests.
+const NAME_FETCH_DELAY: Duration = Duration::from_millis(200);
+
+/// A set of per-peer locks ensuring that only one fetch runs at a time for
+/// any given peer, while fetches for different peers may run in parallel.
+#[derive(Clone, Default)]
+pub struct FetchLocks {
+    locks: Arc<StdMutex<HashMap<String, Arc<Mutex<()>>>>>,
+}
+
+impl FetchLocks {
+    /// Return the lock for the peer represented by the given public key,
+    /// creating it if it does not yet exist.
+    fn get(&self, peer_id: &str) -> Arc<Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.entry(peer_id.to_string()).or_default().clone()
+    }
+}
+
+/// The peers for which a fetch of posts has been requested and has not yet
+/// completed, used to avoid requesting the same fetch more than once.
+#[derive(Clone, Default)]
+pub struct PendingFetches(Arc<StdMutex<HashSet<String>>>);
+
+impl PendingFetches {
+    /// Mark a fetch as pending for the peer represented by the given public
+    /// key. Returns `false` if a fetch was already pending.
+    pub fn insert(&self, peer_id: &str) -> bool {
+        self.0.lock().unwrap().insert(peer_id.to_string())
+    }
+
+    /// Mark the fetch for the peer represented by the given public key as
+    /// complete.
+    pub fn remove(&self, peer_id: &str) {
+        self.0.lock().unwrap().remove(peer_id);
+    }
+}
+
+/// An update on the progress of a fetch of posts, sent after each chunk of
+/// messages is written.
+#[derive(Clone, Debug, Serialize)]
+pub struct FetchProgress {
+    /// The public key of the peer whose posts are fetched.
+    pub peer: String,
+    /// The number of root posts processed so far by the fetch.
+    pub posts: usize,
+    /// The sequence number of the latest message processed so far.
+    pub latest_sequence: u64,
+}
+
+/// A channel on which the task loop broadcasts the progress of fetches of
+/// posts to any live subscribers.
+#[derive(Clone)]
+pub struct FetchProgressChannel {
+    sender: broadcast::Sender<FetchProgress>,
+}
+
+impl Default for FetchProgressChannel {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(PROGRESS_CAPACITY);
+
+        FetchProgressChannel { sender }
+    }
+}
+
+impl FetchProgressChannel {
+    /// Broadcast the progress of the fetch for the given peer.
+    fn report(&self, peer_id: &str, posts: usize, latest_sequence: u64) {
+        // Sending only fails if there are no live subscribers.
+        let _ = self.sender.send(FetchProgress {
+            peer: peer_id.to_string(),
+            posts,
+            latest_sequence,
+        });
+    }
+
+    /// Subscribe to the progress updates sent from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<FetchProgress> {
+        self.sender.subscribe()
+    }
+}
+
+/// The fetches spawned by the task loop which have not yet completed. No more
+/// than `limit` fetches are in flight at any time; further fetches are queued
+/// and started as the in-flight fetches complete.
+struct FetchPool<T> {
+    limit: usize,
+    queued: VecDeque<BoxFuture<'static, T>>,
+    in_flight: FuturesUnordered<JoinHandle<T>>,
+}
+
+impl<T: Send + 'static> FetchPool<T> {
+    fn new(limit: usize) -> Self {
+        FetchPool {
+            limit,
+            queued: VecDeque::new(),
+            in_flight: FuturesUnordered::new(),
+        }
+    }
+
+    /// Spawn the given fetch, or queue it if the limit has been reached. The
+    /// caller is never held up.
+    fn spawn<F>(&mut self, fetch: F)
+    where
+        F: Future<Output = T> + Send + 'static,
+    {
+        if self.in_flight.len() < self.limit {
+            self.in_flight.push(task::spawn(fetch));
+        } else {
+            self.queued.push_back(fetch.boxed());
+        }
+    }
+
+    /// Return `true` if no fetches are in flight (and so none are queued).
+    fn is_empty(&self) -> bool {
+        self.in_flight.is_empty()
+    }
+
+    /// Wait for an in-flight fetch to complete and return its output, starting
+    /// the next queued fetch in its place. Returns `None` if no fetches are in
+    /// flight.
+    ///
+    /// Dropping the returned future before it completes loses no output, so
+    /// it can be raced against the receipt of the next task.
+    async fn next(&mut self) -> Option<T> {
+        let output = self.in_flight.next().await?;
+        if let Some(fetch) = self.queued.pop_front() {
+            self.in_flight.push(task::spawn(fetch));
+        }
+
+        Some(output)
+    }
+
+    /// Wait for all in-flight and queued fetches to complete and return their
+    /// outputs.
+    async fn join_all(&mut self) -> Vec<T> {
+        let mut outputs = Vec::new();
+        while let Some(output) = self.next().await {
+            outputs.push(output);
+        }
+
+        outputs
+    }
+}
+
+/// The outcome of a fetch spawned by the task loop.
+#[derive(Default)]
+struct FetchOutcome {
+    /// The new posts found by a fetch of the latest posts, which are
+    /// notified.
+    new_posts: Option<NewPosts>,
+    /// The ID of the abort handle of a fetch of all posts, which can be
+    /// cancelled.
+    abort_id: Option<u64>,
+}
+
+impl From<NewPosts> for FetchOutcome {
+    fn from(new_posts: NewPosts) -> FetchOutcome {
+        FetchOutcome {
+            new_posts: Some(new_posts),
+            abort_id: None,
+        }
+    }
+}
+
+/// Whether the task loop is running, i.e. it has started receiving tasks and
+/// has not yet exited.
+#[derive(Clone, Default)]
+pub struct TaskLoopStatus(Arc<AtomicBool>);
+
+impl TaskLoopStatus {
+    /// Return `true` if the task loop is running.
+    pub fn is_running(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn set_running(&self, running: bool) {
+        self.0.store(running, Ordering::Relaxed)
+    }
+}
+
+/// Call `attempt` until it succeeds, retrying up to `retries` times after the
+/// first failure, with a delay which starts at `delay` and is doubled for each
+/// subsequent retry. Returns the last error if every attempt fails.
+async fn with_retries<T, F, Fut>(
+    retries: u32,
+    mut delay: Duration,
+    mut attempt: F,
+) -> Result<T, String>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, String>>,
+{
+    let mut retry = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(e) if retry < retries => {
+                retry += 1;
+                debug!(
+                    "Retrying in {:?} (retry {} of {}): {}",
+                    delay, retry, retries, e
+                );
+                task::sleep(delay).await;
+                delay *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Open a stream of the messages authored by the given peer with sequence
+/// numbers greater than `after_sequence`, retrying if the sbot cannot be
+/// reached. Only the opening of the stream is retried; a stream which turns
+/// out to be empty is a successful fetch.
+///
+/// Returns `None`, after logging a warning, if every attempt fails.
+async fn open_message_stream(
+    peer_id: &str,
+    after_sequence: u64,
+) -> Option<impl Stream<Item = Result<SsbMessageKVT, GolgiError>>> {
+    match with_retries(FETCH_RETRIES, FETCH_RETRY_DELAY, || {
+        sbot::get_message_stream(peer_id, after_sequence)
+    })
+    .await
+    {
+        Ok(peer_msgs) => Some(peer_msgs),
+        Err(e) => {
+            warn!(
+                "Giving up on fetching messages for peer: {} after {} attempts: {}",
+                peer_id,
+                FETCH_RETRIES + 1,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Fetch the root posts authored by the given peer and insert them into the
+/// database, updating the latest sequence number of the peer.
+///
+/// Messages with a sequence number greater than `after_sequence` are
+/// fetched. If `after_sequence` is `None`, the latest sequence number stored
+/// for the peer is used instead; it is read after the fetch lock is acquired
+/// so that it reflects the outcome of any preceding fetch for the same peer.
+///
+/// Returns a summary of the posts which were inserted.
+async fn fetch_posts_and_update_db(
+    db: &Database,
+    locks: &FetchLocks,
+    progress: &FetchProgressChannel,
+    peer_id: String,
+    after_sequence: Option<u64>,
+) -> NewPosts {
+    let lock = locks.get(&peer_id);
+    debug!("Acquiring fetch lock for peer: {}", &peer_id);
+    let _guard = lock.lock().await;
+
+    let after_sequence = match after_sequence {
+        Some(sequence) => sequence,
+        None => match db.get_peer(&peer_id) {
+            Ok(Some(peer)) => peer.latest_sequence,
+            _ => return NewPosts::new(&peer_id),
+        },
+    };
+
+    let peer_msgs = match open_message_stream(&peer_id, after_sequence).await {
+        Some(peer_msgs) => peer_msgs,
+        None => return NewPosts::new(&peer_id),
+    };
+    store_root_posts(db, progress, &peer_id, peer_msgs, after_sequence).await
+}
+
+/// Fetch the given number of most recent root posts authored by the given
+/// peer and insert them into the database, updating the latest sequence
+/// number of the peer to the end of their feed.
+async fn fetch_recent_posts_and_update_db(
+    db: &Database,
+    locks: &FetchLocks,
+    peer_id: String,
+    limit: usize,
+) {
+    let lock = locks.get(&peer_id);
+    debug!("Acquiring fetch lock for peer: {}", &peer_id);
+    let _guard = lock.lock().await;
+
+    let peer_msgs = match open_message_stream(&peer_id, 0).await {
+        Some(peer_msgs) => peer_msgs,
+        None => return,
+    };
+    store_recent_root_posts(db, &peer_id, peer_msgs, limit).await;
+}
+
+/// Filter the root posts from the given stream of messages authored by the
+/// given peer and insert the `limit` most recent of them into the database.
+///
+/// The sbot can only stream a feed from the start, so every message is read,
+/// but no more than `limit` posts are held in memory. The posts are written,
+/// and the latest sequence number of the peer updated, once the stream ends;
+/// later fetches of the latest posts then continue from the end of the feed.
+async fn store_recent_root_posts(
+    db: &Database,
+    peer_id: &str,
+    peer_msgs: impl Stream<Item = Result<SsbMessageKVT, GolgiError>>,
+    limit: usize,
+) {
+    let duplicate_window = sbot::duplicate_window();
+    let filter_words = sbot::filter_words();
+
+    let mut latest_sequence = 0;
+    let mut previous_texts: Vec<String> = Vec::new();
+    let mut recent_posts: VecDeque<Post> = VecDeque::new();
+    let chunks = peer_msgs.chunks(FETCH_PROGRESS_INTERVAL);
+    futures::pin_mut!(chunks);
+
+    while let Some(chunk) = chunks.next().await {
+        let msgs: Vec<SsbMessageKVT> = chunk
+            .into_iter()
+            .filter_map(|res| match res {
+                Ok(msg) => Some(msg),
+                Err(err) => {
+                    warn!("err: {:?}", err);
+                    None
+                }
+            })
+            .collect();
+
+        latest_sequence = msgs
+            .iter()
+            .map(|msg| msg.value.sequence)
+            .fold(latest_sequence, u64::max);
+
+        let (_, root_posts) = sbot::get_root_posts(
+            futures::stream::iter(msgs.into_iter().map(Ok::<_, GolgiError>)),
+            previous_texts.clone(),
+            duplicate_window,
+            &filter_words,
+        )
+        .await;
+
+        previous_texts.extend(root_posts.iter().map(|post| post.text.clone()));
+        let excess = previous_texts.len().saturating_sub(duplicate_window);
+        previous_texts.drain(..excess);
+
+        for post in root_posts {
+            recent_posts.push_back(post);
+            if recent_posts.len() > limit {
+                recent_posts.pop_front();
+            }
+        }
+    }
+
+    commit_fetch(db, peer_id, latest_sequence, || {
+        db.add_post_batch(peer_id, recent_posts.into())
+    });
+}
+
+/// Filter the root posts from the given stream of messages authored by the
+/// given peer and insert them into the database.
+///
+/// The posts are written, and the latest sequence number of the peer updated,
+/// after every `FETCH_PROGRESS_INTERVAL` messages. If the fetch is interrupted
+/// (for example because lykin is restarted), a subsequent fetch of the latest
+/// posts resumes from the last sequence number written rather than starting
+/// over. The progress of the fetch is reported after each write.
+///
+/// Returns a summary of the posts which were inserted.
+async fn store_root_posts(
+    db: &Database,
+    progress: &FetchProgressChannel,
+    peer_id: &str,
+    peer_msgs: impl Stream<Item = Result<SsbMessageKVT, GolgiError>>,
+    after_sequence: u64,
+) -> NewPosts {
+    // Collect the texts of the most recent stored posts, oldest first, so that
+    // duplicates of posts from a preceding fetch are detected.
+    let duplicate_window = sbot::duplicate_window();
+    let mut previous_texts: Vec<String> = match db.get_posts(peer_id) {
+        Ok(posts) => posts
+            .into_iter()
+            .take(duplicate_window)
+            .map(|post| post.text)
+            .rev()
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    let filter_words = sbot::filter_words();
+
+    let mut new_posts = NewPosts::new(peer_id);
+    let mut posts_processed = 0;
+    let mut latest_sequence = after_sequence;
+    let chunks = peer_msgs.chunks(FETCH_PROGRESS_INTERVAL);
+    futures::pin_mut!(chunks);
+
+    while let Some(chunk) = chunks.next().await {
+        let msgs: Vec<SsbMessageKVT> = chunk
+            .into_iter()
+            .filter_map(|res| match res {
+                Ok(msg) => Some(msg),
+                Err(err) => {
+                    warn!("err: {:?}", err);
+                    None
+                }
+            })
+            .collect();
+
+        // Progress is tracked across all messages, not only root posts, so
+        // that the messages of a written chunk are not fetched again.
+        latest_sequence = msgs
+            .iter()
+            .map(|msg| msg.value.sequence)
+            .fold(latest_sequence, u64::max);
+
+        let (_, root_posts) = sbot::get_root_posts(
+            futures::stream::iter(msgs.iter().cloned().map(Ok::<_, GolgiError>)),
+            previous_texts.clone(),
+            duplicate_window,
+            &filter_words,
+        )
+        .await;
+
+        // Store all replies of the peer as comments to their root posts,
+        // which are shown with the root post. Replies to root posts which
+        // have not been downloaded are kept until they are.
+        let replies = sbot::get_replies(&msgs);
+        if let Err(e) = db.add_comment_batch(&replies) {
+            warn!("Failed to insert comments for peer: {}: {}", peer_id, e)
+        }
+
+        // Store the replies of the peer to their own root posts, which are
+        // shown in the thread view.
+        let root_keys: HashSet<&str> = root_posts.iter().map(|post| post.key.as_str()).collect();
+        let replies: Vec<(String, Post)> = replies
+            .into_iter()
+            .filter(|(root_key, _)| {
+                root_keys.contains(root_key.as_str())
+                    || matches!(db.get_post(peer_id, root_key), Ok(Some(_)))
+            })
+            .collect();
+        if let Err(e) = db.add_reply_batch(peer_id, replies) {
+            warn!("Failed to insert replies for peer: {}: {}", peer_id, e)
+        }
+
+        // Carry the most recent texts over to the next chunk.
+        previous_texts.extend(root_posts.iter().map(|post| post.text.clone()));
+        let excess = previous_texts.len().saturating_sub(duplicate_window);
+        previous_texts.drain(..excess);
+
+        // The posts of the chunk are only counted as new once written.
+        let mut chunk_posts = NewPosts::new(peer_id);
+        chunk_posts.add(&root_posts);
+        let chunk_len = root_posts.len();
+        if !commit_fetch(db, peer_id, latest_sequence, || {
+            db.add_post_batch(peer_id, root_posts)
+        }) {
+            return new_posts;
+        }
+        new_posts.extend(chunk_posts);
+        posts_processed += chunk_len;
+        progress.report(peer_id, posts_processed, latest_sequence);
+    }
+
+    // Record the time of the fetch even if there were no new messages.
+    commit_fetch(db, peer_id, latest_sequence, || Ok(()));
+
+    new_posts
+}
+
+/// Tally the votes of the peers we follow and update the like counts of the
+/// stored posts which have changed. The votes are tallied once per refresh
+/// cycle.
+///
+/// Each feed is searched from the sequence number at which the previous
+/// tally ended, and the votes found are recorded along with the sequence
+/// number reached, so that the full history of each feed is only read once.
+/// A feed which cannot be streamed is skipped, so that the votes of the other
+/// follows are still tallied.
+async fn update_like_counts(db: &Database) {
+    let feeds = match sbot::get_vote_feeds().await {
+        Ok(feeds) => feeds,
+        Err(e) => {
+            warn!("Failed to fetch votes: {}", e);
+            return;
+        }
+    };
+
+    for feed in feeds {
+        let after_sequence = match db.get_vote_sequence(&feed) {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                warn!(
+                    "Failed to retrieve vote sequence number for feed: {}: {}",
+                    &feed, e
+                );
+                continue;
+            }
+        };
+        let history_stream = match sbot::get_message_stream(&feed, after_sequence).await {
+            Ok(history_stream) => history_stream,
+            Err(e) => {
+                warn!("Failed to stream the votes of {}: {}", &feed, e);
+                continue;
+            }
+        };
+
+        let (latest_sequence, votes) = sbot::get_votes(history_stream).await;
+        if latest_sequence > after_sequence {
+            if let Err(e) = db.add_votes(&feed, &votes, latest_sequence) {
+                warn!("Failed to add votes of {} to the database: {}", &feed, e);
+            }
+        }
+    }
+
+    let like_counts = match db.get_like_counts() {
+        Ok(like_counts) => like_counts,
+        Err(e) => {
+            warn!("Failed to read the like counts of posts: {}", e);
+            return;
+        }
+    };
+    let updated = apply_like_counts(db, like_counts);
+    debug!("Updated the like counts of {} posts", updated);
+}
+
+/// Update the like counts of the given posts, each given with its author and
+/// stored like count, to the number of their likers in the database. Only the
+/// like counts which have changed are written. Returns the number of posts
+/// updated.
+fn apply_like_counts(db: &Database, like_counts: Vec<(String, String, u32)>) -> usize {
+    let mut updated = 0;
+    for (peer_id, key, stored_like_count) in like_counts {
+        let like_count = db.get_like_count(&key) as u32;
+        if like_count == stored_like_count {
+            continue;
+        }
+        match db.update_post_like_count(&peer_id, &key, like_count) {
+            Ok(_) => updated += 1,
+            Err(e) => warn!("Failed to update like count of post: {}: {}", &key, e),
+        }
+    }
+
+    updated
+}
+
+/// Write the posts fetched for the given peer by calling `insert_posts` and,
+/// only if the write succeeds, update the value of the latest sequence number
+/// and the time of the last fetch for the peer (these are stored in the
+/// database).
+///
+/// Leaving the latest sequence number unchanged after a failed write ensures
+/// that the posts are fetched again next time, rather than being skipped.
+/// Returns `true` if the posts were written.
+fn commit_fetch<F>(db: &Database, peer_id: &str, latest_sequence: u64, insert_posts: F) -> bool
+where
+    F: FnOnce() -> sled::Result<()>,
+{
+    match insert_posts() {
         Ok(_) => {
             info!(
                 "Inserted batch of posts into database post tree for peer: {}",
                 &peer_id
             )
         }
+        Err(e) => {
+            warn!(
+                "Failed to insert batch of posts into database post tree for peer: {}: {}",
+                &peer_id, e
+            );
+            return false;
+        }
+    }
+
+    // The latest sequence number is never lowered, so that a fetch which did
+    // not advance it (e.g. an empty stream) does not cause a full refetch.
+    let last_fetched = Utc::now().timestamp();
+    match db.update_peer(peer_id, |peer| {
+        let latest_sequence = latest_sequence.max(peer.latest_sequence);
+        peer.set_latest_sequence(latest_sequence)
+            .set_last_fetched(last_fetched)
+    }) {
+        Ok(Some(peer)) => info!(
+            "Updated latest sequence number for peer: {} to {}",
+            &peer_id, peer.latest_sequence
+        ),
+        Ok(None) => (),
         Err(e) => warn!(
-            "Failed to insert batch of posts into database post tree for peer: {}: {}",
+            "Failed to update latest sequence number for peer: {}: {}",
             &peer_id, e
         ),
     }
 
-    // Update the value of the latest sequence number for
-    // the peer (this is stored in the database).
-    if let Ok(Some(peer)) = db.get_peer(&peer_id) {
-        db.add_peer(peer.set_latest_sequence(latest_sequence))
-            .unwrap();
+    true
+}
+
+/// The state of the stored profile (name, description and profile image) of
+/// a peer, as determined by the about messages they have published.
+enum ProfileState {
+    /// The peer has a name and has published no about messages since their
+    /// profile was last fetched.
+    Current,
+    /// The profile must be fetched; the sequence number of the latest about
+    /// message is given if it could be determined.
+    Stale(Option<u64>),
+}
+
+/// Determine whether the profile of the peer represented by the given public
+/// key (ID) must be fetched, by comparing the sequence number of their latest
+/// about message with the one recorded when their profile was last fetched.
+/// If the latest about message can't be determined, the profile is stale.
+async fn profile_state(db: &Database, peer_id: &str) -> ProfileState {
+    let about_sequence = match sbot::get_latest_about_sequence(peer_id).await {
+        Ok(sequence) => sequence,
+        Err(e) => {
+            debug!("Failed to check about messages of {}: {}", peer_id, e);
+            return ProfileState::Stale(None);
+        }
+    };
+
+    match db.get_peer(peer_id) {
+        Ok(Some(peer)) if !peer.name.is_empty() && about_sequence <= peer.latest_about_sequence => {
+            debug!("Profile of peer {} is up to date", peer_id);
+            ProfileState::Current
+        }
+        _ => ProfileState::Stale(Some(about_sequence)),
     }
 }
 
-/// Request the name of the peer represented by the given public key (ID)
-/// and update the existing entry in the database.
-async fn fetch_name_and_update_db(db: &Database, peer_id: String) {
-    match sbot::get_name(&peer_id).await {
-        Ok(name) => {
-            if let Ok(Some(peer)) = db.get_peer(&peer_id) {
-                let updated_peer = peer.set_name(&name);
-                match db.add_peer(updated_peer) {
-                    Ok(_) => info!("Updated name for peer: {}", &peer_id),
-                    Err(e) => {
-                        warn!("Failed to update name for peer: {}: {}", &peer_id, e)
-                    }
-                }
+/// Request the name, description and profile image of the peer represented
+/// by the given public key (ID) and update the existing entry in the
+/// database.
+///
+/// The profile is only requested if the peer has published about messages
+/// since it was last fetched (see `profile_state`).
+async fn fetch_profile_and_update_db(db: &Database, peer_id: &str) {
+    let about_sequence = match profile_state(db, peer_id).await {
+        ProfileState::Current => return,
+        ProfileState::Stale(about_sequence) => about_sequence,
+    };
+
+    let name = match sbot::get_name(peer_id).await {
+        Ok(name) => Some(name),
+        Err(e) => {
+            warn!("Failed to fetch name for {}: {}", peer_id, e);
+            None
+        }
+    };
+    update_profile(db, peer_id, about_sequence, name).await;
+}
+
+/// Request the profiles of the peers represented by the given public keys
+/// (IDs) and update the existing entries in the database. The names are
+/// requested in a single batch.
+///
+/// Only the profiles of the peers who have published about messages since
+/// their profile was last fetched are requested (see `profile_state`).
+async fn fetch_profiles_and_update_db(db: &Database, peer_ids: Vec<String>) {
+    let mut stale_peers = Vec::new();
+    for peer_id in peer_ids {
+        if let ProfileState::Stale(about_sequence) = profile_state(db, &peer_id).await {
+            stale_peers.push((peer_id, about_sequence));
+        }
+    }
+
+    let stale_peer_ids: Vec<String> = stale_peers
+        .iter()
+        .map(|(peer_id, _)| peer_id.clone())
+        .collect();
+    let mut names = sbot::get_names(&stale_peer_ids).await;
+    info!(
+        "Fetched {} of {} stale peer names",
+        names.len(),
+        stale_peer_ids.len()
+    );
+
+    for (peer_id, about_sequence) in stale_peers {
+        let name = names.remove(&peer_id);
+        update_profile(db, &peer_id, about_sequence, name).await;
+    }
+}
+
+/// Request the description and profile image of the peer represented by the
+/// given public key (ID) and update the existing entry in the database with
+/// them and the given name, if any.
+///
+/// The sequence number of the latest about message is only recorded once the
+/// whole profile has been fetched, so that a failed request is retried.
+async fn update_profile(
+    db: &Database,
+    peer_id: &str,
+    about_sequence: Option<u64>,
+    name: Option<String>,
+) {
+    let description = match sbot::get_description(peer_id).await {
+        Ok(description) => Some(description),
+        Err(e) => {
+            warn!("Failed to fetch description for {}: {}", peer_id, e);
+            None
+        }
+    };
+    let image_blob = match sbot::get_profile_image_blob(peer_id).await {
+        Ok(image_blob) => Some(image_blob),
+        Err(e) => {
+            warn!("Failed to fetch image for {}: {}", peer_id, e);
+            None
+        }
+    };
+    let about_sequence =
+        about_sequence.filter(|_| name.is_some() && description.is_some() && image_blob.is_some());
+
+    let updated_peer = db.update_peer(peer_id, |mut peer| {
+        if let Some(name) = &name {
+            peer = peer.set_name(name);
+        }
+        if let Some(description) = &description {
+            peer = peer.set_description(description);
+        }
+        if let Some(image_blob) = &image_blob {
+            peer = peer.set_image_blob(image_blob.clone());
+        }
+        if let Some(about_sequence) = about_sequence {
+            peer = peer.set_latest_about_sequence(about_sequence);
+        }
+        peer
+    });
+    match updated_peer {
+        Ok(Some(_)) => info!("Updated profile for peer: {}", peer_id),
+        Ok(None) => (),
+        Err(e) => warn!("Failed to update profile for peer: {}: {}", peer_id, e),
+    }
+}
+
+/// Fetch the private messages we can read and add those which are new to the
+/// private tree of the database.
+///
+/// Each feed is searched from the sequence number at which the previous
+/// search ended. The sequence number is only advanced once the messages have
+/// been written, so that messages are not skipped after a failed write.
+async fn fetch_private_messages_and_update_db(db: &Database, activity: &ActivityLog) {
+    let secret_key = match sbot::read_secret_key() {
+        Ok(secret_key) => secret_key,
+        Err(e) => {
+            warn!("Failed to read the secret key: {}", e);
+            return;
+        }
+    };
+    let feeds = match sbot::get_private_message_feeds().await {
+        Ok(feeds) => feeds,
+        Err(e) => {
+            warn!("Failed to fetch private messages: {}", e);
+            return;
+        }
+    };
+
+    let mut fetched = 0;
+    let mut inserted = 0;
+    for feed in feeds {
+        let after_sequence = match db.get_private_sequence(&feed) {
+            Ok(sequence) => sequence,
+            Err(e) => {
+                warn!(
+                    "Failed to retrieve private message sequence number for feed: {}: {}",
+                    &feed, e
+                );
+                continue;
+            }
+        };
+        let history_stream = match sbot::get_message_stream(&feed, after_sequence).await {
+            Ok(history_stream) => history_stream,
+            Err(e) => {
+                warn!(
+                    "Failed to fetch private messages from feed: {}: {}",
+                    &feed, e
+                );
+                continue;
+            }
+        };
+
+        let (latest_sequence, messages) =
+            sbot::get_private_messages(history_stream, &secret_key).await;
+        fetched += messages.len();
+        match db.add_private_messages(messages) {
+            Ok(count) => inserted += count,
+            Err(e) => {
+                warn!("Failed to add private messages to the database: {}", e);
+                continue;
+            }
+        }
+
+        if latest_sequence > after_sequence {
+            if let Err(e) = db.set_private_sequence(&feed, latest_sequence) {
+                warn!(
+                    "Failed to update private message sequence number for feed: {}: {}",
+                    &feed, e
+                );
             }
         }
-        Err(e) => warn!("Failed to fetch name for {}: {}", &peer_id, e),
     }
+
+    info!(
+        "Fetched {} private messages, of which {} are new",
+        fetched, inserted
+    );
+    activity.record(format!("Fetched {} new private messages", inserted));
 }
 
 pub enum Task {
     Cancel,
+    CancelPeer(String),
     FetchAllPosts(String),
     FetchLatestPosts(String),
+    FetchRecentPosts(String, usize),
     FetchLatestName(String),
+    FetchLatestNames(Vec<String>),
+    FetchAllNames,
+    FetchPrivateMessages,
 }
 
 /// Spawn an asynchronous loop which receives tasks over an unbounded channel
-/// and invokes task functions accordingly.
-pub async fn spawn(db: Database, rx: Receiver<Task>) {
+/// and invokes task functions accordingly. The start and end of each task are
+/// recorded in the activity log, and pending fetches are marked as complete.
+///
+/// Fetches of posts, names, descriptions and private messages are spawned so
+/// that up to `MAX_CONCURRENT_FETCHES` of them run at the same time, while
+/// fetches of posts for the same peer are still serialized by the fetch locks.
+/// Further fetches are queued. The database is safe to write from several
+/// fetches at once, since sled handles its own locking and peers are updated
+/// atomically.
+/// Tasks keep being received while fetches are in flight, so that a
+/// `Task::CancelPeer` takes effect without waiting for them.
+///
+/// The new posts found by fetches of the latest posts are collected until no
+/// tasks are left in the channel and no fetches are in flight (i.e. at the end
+/// of a refresh cycle), and then delivered to the configured webhook, if any,
+/// in a single notification. The like counts of the stored posts are then
+/// tallied through the fetch pool, if any posts were fetched during the
+/// cycle, so that tasks keep being received during the tally.
+///
+/// The progress of fetches of posts is broadcast on the given channel.
+///
+/// In-flight fetches are allowed to complete before the loop exits. The given
+/// status reports whether the loop is running.
+///
+/// A fetch of all posts can be aborted for a single peer with
+/// `Task::CancelPeer`. Since the posts of a fetch are committed in chunks,
+/// along with the sequence number reached, and an abort only takes effect
+/// while the fetch is waiting (never during a commit), the posts written
+/// before the abort are consistent and a later fetch resumes after them.
+pub async fn spawn(
+    db: Database,
+    rx: Receiver<Task>,
+    activity: ActivityLog,
+    pending_fetches: PendingFetches,
+    progress: FetchProgressChannel,
+    status: TaskLoopStatus,
+) {
+    spawn_with_fetch(
+        db,
+        rx,
+        activity,
+        pending_fetches,
+        progress,
+        status,
+        |db, fetch_locks, progress, peer_id| async move {
+            fetch_posts_and_update_db(&db, &fetch_locks, &progress, peer_id, Some(0)).await;
+        },
+    )
+    .await
+}
+
+/// Spawn the task loop (see `spawn`), fetching all posts of a peer with the
+/// given function, so that tests can substitute a simulated fetch.
+async fn spawn_with_fetch<F, Fut>(
+    db: Database,
+    rx: Receiver<Task>,
+    activity: ActivityLog,
+    pending_fetches: PendingFetches,
+    progress: FetchProgressChannel,
+    status: TaskLoopStatus,
+    fetch_all_posts: F,
+) where
+    F: Fn(Database, FetchLocks, FetchProgressChannel, String) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
     task::spawn(async move {
-        while let Ok(task) = rx.recv().await {
+        let fetch_locks = FetchLocks::default();
+        let mut fetches = FetchPool::new(MAX_CONCURRENT_FETCHES);
+        let mut webhook_batch = WebhookBatch::default();
+        // The abort handle of the most recent fetch of all posts of each peer,
+        // along with its ID. Handles are removed once their fetch completes.
+        let mut fetch_handles: HashMap<String, (u64, AbortHandle)> = HashMap::new();
+        let mut next_abort_id = 0;
+        // Whether posts have been fetched since the likes were last tallied.
+        let mut likes_stale = false;
+        status.set_running(true);
+
+        loop {
+            // At the end of a refresh cycle, deliver the new posts and update
+            // the like counts.
+            if rx.is_empty() && fetches.is_empty() {
+                webhook_batch.flush();
+                if likes_stale {
+                    let db = db.clone();
+                    fetches.spawn(async move {
+                        update_like_counts(&db).await;
+                        FetchOutcome::default()
+                    });
+                    likes_stale = false;
+                }
+            }
+
+            // Wait for the next task, collecting the new posts of the fetches
+            // which complete in the meantime.
+            let received = if fetches.is_empty() {
+                rx.recv().await
+            } else {
+                futures::select! {
+                    received = rx.recv().fuse() => received,
+                    completed = fetches.next().fuse() => {
+                        if let Some(FetchOutcome { new_posts, abort_id }) = completed {
+                            if let Some(abort_id) = abort_id {
+                                fetch_handles.retain(|_, (id, _)| *id != abort_id);
+                            }
+                            webhook_batch.extend(new_posts);
+                        }
+                        continue;
+                    }
+                }
+            };
+            let task = match received {
+                Ok(task) => task,
+                Err(_) => break,
+            };
+
             match task {
                 // Fetch all messages authored by the given peer, filter
                 // the root posts and insert them into the posts tree of the
                 // database.
                 Task::FetchAllPosts(peer_id) => {
                     info!("Fetching all posts for peer: {}", peer_id);
-                    fetch_posts_and_update_db(&db, peer_id, 0).await;
+                    activity.record(format!("Fetching all posts for peer {}", peer_id));
+                    let (db, fetch_locks, activity, pending_fetches, progress) = (
+                        db.clone(),
+                        fetch_locks.clone(),
+                        activity.clone(),
+                        pending_fetches.clone(),
+                        progress.clone(),
+                    );
+                    likes_stale = true;
+                    let (abort_handle, abort_registration) = AbortHandle::new_pair();
+                    let abort_id = next_abort_id;
+                    next_abort_id += 1;
+                    fetch_handles.insert(peer_id.clone(), (abort_id, abort_handle));
+                    fetches.spawn(async move {
+                        let fetch = fetch_all_posts(db, fetch_locks, progress, peer_id.clone());
+                        match Abortable::new(fetch, abort_registration).await {
+                            Ok(_) => activity
+                                .record(format!("Finished fetching posts for peer {}", peer_id)),
+                            Err(Aborted) => activity
+                                .record(format!("Cancelled fetching posts for peer {}", peer_id)),
+                        }
+                        pending_fetches.remove(&peer_id);
+                        // Fetches of all posts are not notified.
+                        FetchOutcome {
+                            new_posts: None,
+                            abort_id: Some(abort_id),
+                        }
+                    });
                 }
                 // Fetch only the latest messages authored by the given peer,
                 // ie. messages with sequence numbers greater than those
@@ -74,23 +1028,616 @@ pub async fn spawn(db: Database, rx: Receiver<Task>) {
                 // Retrieve the root posts from those messages and insert them
                 // into the posts tree of the database.
                 Task::FetchLatestPosts(peer_id) => {
-                    if let Ok(Some(peer)) = db.get_peer(&peer_id) {
+                    if let Ok(Some(_)) = db.get_peer(&peer_id) {
                         info!("Fetching latest posts for peer: {}", peer_id);
-                        fetch_posts_and_update_db(&db, peer_id, peer.latest_sequence).await;
+                        activity.record(format!("Fetching latest posts for peer {}", peer_id));
+                        let (db, fetch_locks, activity, pending_fetches, progress) = (
+                            db.clone(),
+                            fetch_locks.clone(),
+                            activity.clone(),
+                            pending_fetches.clone(),
+                            progress.clone(),
+                        );
+                        likes_stale = true;
+                        fetches.spawn(async move {
+                            let new_posts = fetch_posts_and_update_db(
+                                &db,
+                                &fetch_locks,
+                                &progress,
+                                peer_id.clone(),
+                                None,
+                            )
+                            .await;
+                            activity
+                                .record(format!("Finished fetching posts for peer {}", peer_id));
+                            pending_fetches.remove(&peer_id);
+                            FetchOutcome::from(new_posts)
+                        });
+                    } else {
+                        pending_fetches.remove(&peer_id);
                     }
                 }
-                // Fetch the latest name for the given peer and update the
-                // peer entry in the peers tree of the database.
+                // Fetch the given number of most recent root posts authored
+                // by the given peer and insert them into the posts tree of
+                // the database.
+                Task::FetchRecentPosts(peer_id, limit) => {
+                    info!("Fetching {} most recent posts for peer: {}", limit, peer_id);
+                    activity.record(format!(
+                        "Fetching {} most recent posts for peer {}",
+                        limit, peer_id
+                    ));
+                    let (db, fetch_locks, activity, pending_fetches) = (
+                        db.clone(),
+                        fetch_locks.clone(),
+                        activity.clone(),
+                        pending_fetches.clone(),
+                    );
+                    likes_stale = true;
+                    fetches.spawn(async move {
+                        fetch_recent_posts_and_update_db(&db, &fetch_locks, peer_id.clone(), limit)
+                            .await;
+                        activity.record(format!("Finished fetching posts for peer {}", peer_id));
+                        pending_fetches.remove(&peer_id);
+                        FetchOutcome::default()
+                    });
+                }
+                // Fetch the latest name, description and profile image for
+                // the given peer and update the peer entry in the peers tree
+                // of the database.
                 Task::FetchLatestName(peer_id) => {
                     info!("Fetching latest name for peer: {}", peer_id);
-                    fetch_name_and_update_db(&db, peer_id).await;
+                    activity.record(format!("Fetching latest name for peer {}", peer_id));
+                    let db = db.clone();
+                    fetches.spawn(async move {
+                        fetch_profile_and_update_db(&db, &peer_id).await;
+                        FetchOutcome::default()
+                    });
+                }
+                // Fetch the latest names, descriptions and profile images for
+                // the given peers and update the peer entries in the peers
+                // tree of the database.
+                Task::FetchLatestNames(peer_ids) => {
+                    info!("Fetching latest names for {} peers", peer_ids.len());
+                    activity.record(format!(
+                        "Fetching latest names for {} peers",
+                        peer_ids.len()
+                    ));
+                    let db = db.clone();
+                    fetches.spawn(async move {
+                        fetch_profiles_and_update_db(&db, peer_ids).await;
+                        FetchOutcome::default()
+                    });
+                }
+                // Fetch the names of all peers in the database, one after
+                // the other, and update the peer entries in the peers tree.
+                Task::FetchAllNames => {
+                    let peers = db.get_peers();
+                    info!("Fetching names for all {} peers", peers.len());
+                    activity.record(format!("Fetching names for all {} peers", peers.len()));
+                    let (db, activity) = (db.clone(), activity.clone());
+                    fetches.spawn(async move {
+                        for (index, peer) in peers.into_iter().enumerate() {
+                            if index > 0 {
+                                task::sleep(NAME_FETCH_DELAY).await;
+                            }
+                            fetch_profile_and_update_db(&db, &peer.public_key).await;
+                        }
+                        activity.record("Finished fetching names for all peers");
+                        FetchOutcome::default()
+                    });
+                }
+                // Fetch and decrypt the private messages we can read and add
+                // them to the private tree of the database.
+                Task::FetchPrivateMessages => {
+                    info!("Fetching private messages");
+                    activity.record("Fetching private messages");
+                    let (db, activity) = (db.clone(), activity.clone());
+                    fetches.spawn(async move {
+                        fetch_private_messages_and_update_db(&db, &activity).await;
+                        FetchOutcome::default()
+                    });
                 }
-                // Break out of the task loop.
+                // Abort the fetch of all posts of the given peer, if it is
+                // still running.
+                Task::CancelPeer(peer_id) => match fetch_handles.remove(&peer_id) {
+                    Some((_, abort_handle)) => {
+                        info!("Cancelling fetch of all posts for peer: {}", peer_id);
+                        abort_handle.abort();
+                    }
+                    None => debug!("No fetch of all posts to cancel for peer: {}", peer_id),
+                },
+                // Break out of the task loop once the in-flight fetches have
+                // completed.
                 Task::Cancel => {
                     info!("Exiting task loop...");
+                    let outcomes = fetches.join_all().await;
+                    webhook_batch
+                        .extend(outcomes.into_iter().filter_map(|outcome| outcome.new_posts));
+                    webhook_batch.flush();
                     break;
                 }
             }
         }
+
+        status.set_running(false);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::db::{Peer, SortOrder};
+
+    const PEER: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+
+    #[test]
+    fn status_reports_whether_the_task_loop_is_running() {
+        let db = Database::init_temp();
+        let (tx, rx) = async_std::channel::unbounded();
+        let status = TaskLoopStatus::default();
+        assert!(!status.is_running());
+
+        task::block_on(async {
+            spawn(
+                db,
+                rx,
+                ActivityLog::default(),
+                PendingFetches::default(),
+                FetchProgressChannel::default(),
+                status.clone(),
+            )
+            .await;
+
+            // Wait for the spawned loop to start, then for it to exit.
+            while !status.is_running() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+            tx.send(Task::Cancel).await.unwrap();
+            while status.is_running() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        });
+    }
+
+    #[test]
+    fn failed_batch_write_does_not_advance_latest_sequence() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER).set_latest_sequence(5)).unwrap();
+
+        assert!(!commit_fetch(&db, PEER, 10, || {
+            Err(sled::Error::Unsupported("injected failure".to_string()))
+        }));
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 5);
+
+        assert!(commit_fetch(&db, PEER, 10, || Ok(())));
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 10);
+    }
+
+    fn post_msg(sequence: u64) -> Result<SsbMessageKVT, GolgiError> {
+        let msg = serde_json::json!({
+            "key": format!("%post{}", sequence),
+            "value": {
+                "previous": null,
+                "author": PEER,
+                "sequence": sequence,
+                "timestamp": 1621252800000.0,
+                "hash": "sha256",
+                "content": { "type": "post", "text": format!("post {}", sequence) },
+                "signature": "sig"
+            },
+            "timestamp": null,
+            "rts": null
+        });
+
+        Ok(serde_json::from_value(msg).unwrap())
+    }
+
+    #[test]
+    fn only_changed_like_counts_are_written() {
+        let db = Database::init_temp();
+        let posts = (1..=3)
+            .map(|sequence| {
+                let mut post = Post::builder(format!("%post{}", sequence))
+                    .sequence(sequence)
+                    .build();
+                post.like_count = 1;
+                post
+            })
+            .collect();
+        db.add_post_batch(PEER, posts).unwrap();
+
+        let like = |msg_id: &str| (msg_id.to_string(), true);
+        db.add_votes(PEER, &[like("%post1"), like("%post2")], 4)
+            .unwrap();
+        db.add_votes("@b", &[like("%post2")], 2).unwrap();
+        let like_counts = db.get_like_counts().unwrap();
+        assert_eq!(like_counts.len(), 3);
+        assert_eq!(apply_like_counts(&db, like_counts), 2);
+
+        let like_count = |key: &str| db.get_post(PEER, key).unwrap().unwrap().like_count;
+        assert_eq!(like_count("%post1"), 1);
+        assert_eq!(like_count("%post2"), 2);
+        assert_eq!(like_count("%post3"), 0);
+
+        // A second tally of the same votes writes nothing.
+        let like_counts = db.get_like_counts().unwrap();
+        assert_eq!(apply_like_counts(&db, like_counts), 0);
+    }
+
+    #[test]
+    fn empty_fetch_leaves_latest_sequence_unchanged() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER).set_latest_sequence(42))
+            .unwrap();
+
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            futures::stream::empty(),
+            42,
+        ));
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 42);
+
+        // A fetch of all posts which yields nothing does not lower it either.
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            futures::stream::empty(),
+            0,
+        ));
+        assert!(commit_fetch(&db, PEER, 0, || Ok(())));
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 42);
+    }
+
+    #[test]
+    fn fetch_records_the_time_of_the_fetch() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER).set_latest_sequence(42))
+            .unwrap();
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().last_fetched, 0);
+
+        // The time is recorded even if there are no new messages.
+        let before = Utc::now().timestamp();
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            futures::stream::empty(),
+            42,
+        ));
+        assert!(db.get_peer(PEER).unwrap().unwrap().last_fetched >= before);
+    }
+
+    #[test]
+    fn failed_attempts_are_retried_a_bounded_number_of_times() {
+        let delay = Duration::from_millis(1);
+
+        let mut attempts = 0;
+        let result = task::block_on(with_retries(3, delay, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    Err("connection refused".to_string())
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(result, Ok(3));
+
+        let mut attempts = 0;
+        let result: Result<(), String> = task::block_on(with_retries(3, delay, || {
+            attempts += 1;
+            async { Err("connection refused".to_string()) }
+        }));
+        assert!(result.is_err());
+        assert_eq!(attempts, 4);
+    }
+
+    #[test]
+    fn pending_fetches_are_only_requested_once() {
+        let pending_fetches = PendingFetches::default();
+        assert!(pending_fetches.insert(PEER));
+        assert!(!pending_fetches.clone().insert(PEER));
+
+        pending_fetches.remove(PEER);
+        assert!(pending_fetches.insert(PEER));
+    }
+
+    #[test]
+    fn interrupted_fetch_resumes_from_last_written_sequence() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+
+        // The stream stalls after 150 messages, as if the sbot stopped
+        // responding, and the fetch is abandoned.
+        let stalled_msgs =
+            futures::stream::iter((1..=150).map(post_msg)).chain(futures::stream::pending());
+        let interrupted = task::block_on(async_std::future::timeout(
+            Duration::from_millis(200),
+            store_root_posts(&db, &FetchProgressChannel::default(), PEER, stalled_msgs, 0),
+        ));
+        assert!(interrupted.is_err());
+
+        // Only the first complete chunk of messages was written.
+        let latest_sequence = db.get_peer(PEER).unwrap().unwrap().latest_sequence;
+        assert_eq!(latest_sequence, FETCH_PROGRESS_INTERVAL as u64);
+        assert_eq!(db.get_posts(PEER).unwrap().len(), FETCH_PROGRESS_INTERVAL);
+
+        // A subsequent fetch continues from the written sequence number.
+        let remaining_msgs = futures::stream::iter((latest_sequence + 1..=250).map(post_msg));
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            remaining_msgs,
+            latest_sequence,
+        ));
+
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 250);
+        assert_eq!(db.get_posts(PEER).unwrap().len(), 250);
+    }
+
+    #[test]
+    fn deleted_post_stays_deleted_after_refetch() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+
+        let msgs = futures::stream::iter((1..=3).map(post_msg));
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            msgs,
+            0,
+        ));
+        db.remove_post(PEER, "%post2").unwrap();
+
+        // Fetch the entire feed again, including the deleted post.
+        let msgs = futures::stream::iter((1..=3).map(post_msg));
+        task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            msgs,
+            0,
+        ));
+
+        assert!(db.get_post(PEER, "%post2").unwrap().is_none());
+        assert_eq!(db.get_posts(PEER).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fetch_reports_progress_after_each_chunk() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+        let progress = FetchProgressChannel::default();
+        let mut receiver = progress.subscribe();
+
+        let msg_count = FETCH_PROGRESS_INTERVAL as u64 + 5;
+        let msgs = futures::stream::iter((1..=msg_count).map(post_msg));
+        task::block_on(store_root_posts(&db, &progress, PEER, msgs, 0));
+
+        let first = receiver.try_recv().unwrap();
+        assert_eq!(first.peer, PEER);
+        assert_eq!(first.posts, FETCH_PROGRESS_INTERVAL);
+        assert_eq!(first.latest_sequence, FETCH_PROGRESS_INTERVAL as u64);
+        let second = receiver.try_recv().unwrap();
+        assert_eq!(second.posts, msg_count as usize);
+        assert_eq!(second.latest_sequence, msg_count);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn fetch_reports_the_new_posts() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+
+        let msgs = futures::stream::iter((1..=3).map(post_msg));
+        let new_posts = task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            msgs,
+            0,
+        ));
+        assert_eq!(new_posts.peer, PEER);
+        assert_eq!(new_posts.count, 3);
+        assert_eq!(new_posts.subjects, vec!["post 1", "post 2", "post 3"]);
+
+        let new_posts = task::block_on(store_root_posts(
+            &db,
+            &FetchProgressChannel::default(),
+            PEER,
+            futures::stream::empty(),
+            3,
+        ));
+        assert_eq!(new_posts.count, 0);
+    }
+
+    #[test]
+    fn recent_fetch_keeps_only_the_most_recent_posts() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+
+        let msgs = futures::stream::iter((1..=250).map(post_msg));
+        task::block_on(store_recent_root_posts(&db, PEER, msgs, 20));
+
+        let posts = db.get_posts_sorted(PEER, SortOrder::Ascending).unwrap();
+        let sequences: Vec<u64> = posts.iter().map(|post| post.sequence).collect();
+        assert_eq!(sequences, (231..=250).collect::<Vec<u64>>());
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 250);
+    }
+
+    #[test]
+    fn recent_fetch_without_posts_skips_to_the_end_of_the_feed() {
+        let db = Database::init_temp();
+        db.add_peer(Peer::new(PEER)).unwrap();
+
+        let msgs = futures::stream::iter((1..=50).map(post_msg));
+        task::block_on(store_recent_root_posts(&db, PEER, msgs, 0));
+
+        assert!(db.get_posts(PEER).unwrap().is_empty());
+        assert_eq!(db.get_peer(PEER).unwrap().unwrap().latest_sequence, 50);
+    }
+
+    /// Acquire the fetch lock for the given peer and record when the
+    /// simulated fetch starts and ends.
+    async fn simulated_fetch(locks: FetchLocks, events: Arc<StdMutex<Vec<String>>>, peer: &str) {
+        let lock = locks.get(peer);
+        let _guard = lock.lock().await;
+        events.lock().unwrap().push(format!("start {}", peer));
+        task::sleep(Duration::from_millis(50)).await;
+        events.lock().unwrap().push(format!("end {}", peer));
+    }
+
+    #[test]
+    fn fetch_pool_limits_the_number_of_fetches_in_flight() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let outputs = task::block_on(async {
+            let mut pool = FetchPool::new(2);
+            for i in 0..5 {
+                let (in_flight, max_in_flight) = (in_flight.clone(), max_in_flight.clone());
+                pool.spawn(async move {
+                    let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_in_flight.fetch_max(current, Ordering::SeqCst);
+                    task::sleep(Duration::from_millis(20)).await;
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                    i
+                });
+            }
+            // The fetches beyond the limit are queued rather than waited for.
+            assert_eq!(pool.in_flight.len(), 2);
+            assert_eq!(pool.queued.len(), 3);
+            pool.join_all().await
+        });
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+        let mut outputs = outputs;
+        outputs.sort();
+        assert_eq!(outputs, vec![0, 1, 2, 3, 4]);
+    }
+
+    /// Spawn the task loop with a simulated fetch of all posts, which runs
+    /// until it is aborted.
+    async fn spawn_with_endless_fetch(
+        rx: Receiver<Task>,
+        activity: ActivityLog,
+        status: TaskLoopStatus,
+    ) {
+        spawn_with_fetch(
+            Database::init_temp(),
+            rx,
+            activity,
+            PendingFetches::default(),
+            FetchProgressChannel::default(),
+            status,
+            |_, _, _, _| futures::future::pending(),
+        )
+        .await;
+    }
+
+    /// Wait until the given message is recorded in the given activity log.
+    async fn wait_for_activity(activity: &ActivityLog, message: &str) {
+        while !activity
+            .recent(10)
+            .iter()
+            .any(|event| event.message == message)
+        {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn task_loop_receives_tasks_while_fetches_are_in_flight() {
+        let (tx, rx) = async_std::channel::unbounded();
+        let activity = ActivityLog::default();
+        let status = TaskLoopStatus::default();
+
+        task::block_on(async {
+            spawn_with_endless_fetch(rx, activity.clone(), status.clone()).await;
+
+            tx.send(Task::FetchAllPosts(PEER.to_string()))
+                .await
+                .unwrap();
+            tx.send(Task::FetchAllNames).await.unwrap();
+            let finished = async_std::future::timeout(
+                Duration::from_secs(5),
+                wait_for_activity(&activity, "Finished fetching names for all peers"),
+            );
+            assert!(finished.await.is_ok());
+        });
+    }
+
+    #[test]
+    fn in_flight_fetch_of_all_posts_is_cancelled() {
+        let (tx, rx) = async_std::channel::unbounded();
+        let activity = ActivityLog::default();
+        let status = TaskLoopStatus::default();
+
+        task::block_on(async {
+            spawn_with_endless_fetch(rx, activity.clone(), status.clone()).await;
+
+            tx.send(Task::FetchAllPosts(PEER.to_string()))
+                .await
+                .unwrap();
+            wait_for_activity(&activity, &format!("Fetching all posts for peer {}", PEER)).await;
+            tx.send(Task::CancelPeer(PEER.to_string())).await.unwrap();
+
+            let cancelled = format!("Cancelled fetching posts for peer {}", PEER);
+            let cancelled = async_std::future::timeout(
+                Duration::from_secs(5),
+                wait_for_activity(&activity, &cancelled),
+            );
+            assert!(cancelled.await.is_ok());
+
+            // The loop exits, since the cancelled fetch has completed.
+            tx.send(Task::Cancel).await.unwrap();
+            while status.is_running() {
+                task::sleep(Duration::from_millis(10)).await;
+            }
+        });
+    }
+
+    #[test]
+    fn fetches_of_the_same_peer_are_serialized() {
+        let locks = FetchLocks::default();
+        let events = Arc::new(StdMutex::new(Vec::new()));
+
+        task::block_on(async {
+            let first = task::spawn(simulated_fetch(locks.clone(), events.clone(), "@a"));
+            let second = task::spawn(simulated_fetch(locks.clone(), events.clone(), "@a"));
+            first.await;
+            second.await;
+        });
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec!["start @a", "end @a", "start @a", "end @a"]
+        );
+    }
+
+    #[test]
+    fn fetches_of_different_peers_run_in_parallel() {
+        let locks = FetchLocks::default();
+        let events = Arc::new(StdMutex::new(Vec::new()));
+
+        task::block_on(async {
+            let first = task::spawn(simulated_fetch(locks.clone(), events.clone(), "@a"));
+            let second = task::spawn(simulated_fetch(locks.clone(), events.clone(), "@b"));
+            first.await;
+            second.await;
+        });
+
+        let events = events.lock().unwrap();
+        // Both fetches start before either of them ends.
+        assert!(events[0].starts_with("start") && events[1].starts_with("start"));
+    }
+}
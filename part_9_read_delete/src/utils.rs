@@ -1,4 +1,15 @@
-//! Public key validation.
+//! Public key, message ID and invite validation, post text and configuration
+//! helpers.
+
+use std::env;
+
+use unicode_normalization::UnicodeNormalization;
+
+/// The maximum length of a message accepted by SSB servers, in bytes.
+const MESSAGE_MAX_LENGTH: usize = 8192;
+/// The length reserved for the fields of a message other than its content
+/// (the author, previous message, signature and so on), in bytes.
+const MESSAGE_ENVELOPE_LENGTH: usize = 1024;
 
 /// Ensure that the given public key is a valid ed25519 key.
 ///
@@ -30,3 +41,579 @@ pub fn validate_public_key(public_key: &str) -> Result<(), String> {
 
     Ok(())
 }
+
+/// Ensure that the given network key (also known as the caps key) is the base64
+/// encoding of a 32 byte key.
+///
+/// Return an error string if the key is invalid.
+pub fn validate_network_key(network_key: &str) -> Result<(), String> {
+    // 32 bytes are encoded as 43 base64 characters and a single padding
+    // character.
+    if network_key.len() != 44 {
+        return Err("base64 data length is incorrect".to_string());
+    }
+
+    let (data, padding) = network_key.split_at(43);
+    if padding != "=" {
+        return Err("expected a single '=' padding character".to_string());
+    }
+
+    if !data
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+    {
+        return Err("invalid base64 character".to_string());
+    }
+
+    Ok(())
+}
+
+/// Ensure that the given message ID is a valid sha256 message reference.
+///
+/// Return an error string if the message ID is invalid.
+pub fn validate_message_id(msg_id: &str) -> Result<(), String> {
+    // Ensure the ID starts with the correct sigil link.
+    if !msg_id.starts_with('%') {
+        return Err("expected '%' sigil as first character".to_string());
+    }
+
+    // Find the dot index denoting the start of the algorithm definition tag.
+    let dot_index = match msg_id.rfind('.') {
+        Some(index) => index,
+        None => return Err("no dot index was found".to_string()),
+    };
+
+    // Check the hashing algorithm (must end with ".sha256").
+    if !&msg_id.ends_with(".sha256") {
+        return Err("hashing algorithm must be sha256".to_string());
+    }
+
+    // Obtain the base64 portion (substring) of the message ID.
+    let base64_str = &msg_id[1..dot_index];
+
+    // Ensure the length of the base64 encoded sha256 hash is correct.
+    if base64_str.len() != 44 {
+        return Err("base64 data length is incorrect".to_string());
+    }
+
+    Ok(())
+}
+
+/// Ensure that the given blob ID is a valid sha256 blob reference.
+///
+/// Return an error string if the blob ID is invalid.
+pub fn validate_blob_id(blob_id: &str) -> Result<(), String> {
+    // Ensure the ID starts with the correct sigil link.
+    if !blob_id.starts_with('&') {
+        return Err("expected '&' sigil as first character".to_string());
+    }
+
+    // Find the dot index denoting the start of the algorithm definition tag.
+    let dot_index = match blob_id.rfind('.') {
+        Some(index) => index,
+        None => return Err("no dot index was found".to_string()),
+    };
+
+    // Check the hashing algorithm (must end with ".sha256").
+    if !&blob_id.ends_with(".sha256") {
+        return Err("hashing algorithm must be sha256".to_string());
+    }
+
+    // Obtain the base64 portion (substring) of the blob ID.
+    let base64_str = &blob_id[1..dot_index];
+
+    // Ensure the length of the base64 encoded sha256 hash is correct.
+    if base64_str.len() != 44 {
+        return Err("base64 data length is incorrect".to_string());
+    }
+
+    Ok(())
+}
+
+/// Return the file extension of the image format of the given bytes (e.g.
+/// `png`), as determined from their magic number, or `None` if the bytes are
+/// not an image in a supported format.
+pub fn image_extension(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("png")
+    } else if bytes.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("jpg")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("gif")
+    } else if bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        Some("webp")
+    } else {
+        None
+    }
+}
+
+/// Ensure that the given text can be published as a post: it must not be
+/// empty and the content of the resulting message must fit within the
+/// message size limit.
+///
+/// Return an error string if the text is invalid.
+pub fn validate_post_text(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err("post text is empty".to_string());
+    }
+
+    // The text is escaped when it is encoded in the message content.
+    let content = serde_json::json!({ "type": "post", "text": text }).to_string();
+    let max_length = MESSAGE_MAX_LENGTH - MESSAGE_ENVELOPE_LENGTH;
+    if content.len() > max_length {
+        return Err(format!(
+            "post is too long ({} bytes encoded, the maximum is {})",
+            content.len(),
+            max_length
+        ));
+    }
+
+    Ok(())
+}
+
+/// The parts of an SSB invite code.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Invite {
+    /// The host of the pub which issued the invite.
+    pub host: String,
+    /// The port on which the pub is listening.
+    pub port: u16,
+    /// The public key of the pub, including the '@' sigil and the ".ed25519"
+    /// suffix.
+    pub public_key: String,
+}
+
+/// Parse the given SSB invite code, returning the host, port and public key
+/// of the pub which issued it.
+///
+/// Both the legacy format (`host:port:@key.ed25519~seed`) and the
+/// multiserver format (`net:host:port~shs:key:seed`) are accepted. Return an
+/// error string if the invite is malformed.
+pub fn parse_invite(code: &str) -> Result<Invite, String> {
+    let code = code.trim();
+
+    // Split the address of the pub from the invite seed.
+    let (address, secret) = match code.split_once('~') {
+        Some(parts) => parts,
+        None => return Err("expected '~' separating the address and seed".to_string()),
+    };
+
+    let (host, port, public_key, seed) = match address.strip_prefix("net:") {
+        Some(net_address) => {
+            let (host, port) = match net_address.rsplit_once(':') {
+                Some(parts) => parts,
+                None => return Err("expected a host and port".to_string()),
+            };
+            let (key, seed) = match secret
+                .strip_prefix("shs:")
+                .and_then(|shs| shs.split_once(':'))
+            {
+                Some(parts) => parts,
+                None => return Err("expected 'shs:' followed by a key and seed".to_string()),
+            };
+            (host, port, format!("@{}.ed25519", key), seed)
+        }
+        None => {
+            let mut parts = address.splitn(3, ':');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(host), Some(port), Some(key)) => (host, port, key.to_string(), secret),
+                _ => return Err("expected a host, port and public key".to_string()),
+            }
+        }
+    };
+
+    if host.is_empty() {
+        return Err("host is empty".to_string());
+    }
+    let port = match port.parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => return Err(format!("port {} is invalid", port)),
+    };
+    validate_public_key(&public_key).map_err(|e| format!("public key is invalid: {}", e))?;
+    if seed.is_empty() {
+        return Err("seed is empty".to_string());
+    }
+
+    Ok(Invite {
+        host: host.to_string(),
+        port,
+        public_key,
+    })
+}
+
+/// Return `true` if the environment variable with the given name is set to
+/// `true`, `yes` or `1` (ignoring case).
+pub fn env_flag(name: &str) -> bool {
+    match env::var(name) {
+        Ok(value) => matches!(value.to_lowercase().as_str(), "true" | "yes" | "1"),
+        Err(_) => false,
+    }
+}
+
+/// Decode the given post text.
+///
+/// Post text is stored as a JSON string (including quotes and escape
+/// sequences). The text is returned unchanged if it cannot be decoded.
+pub fn decode_text(text: &str) -> String {
+    serde_json::from_str::<String>(text).unwrap_or_else(|_| text.to_string())
+}
+
+/// Escape the characters of the given text which are special in XML, so that
+/// it can be included in element content and attribute values.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Unescape the XML entities for the characters escaped by `escape_xml`.
+pub fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Extract the public keys from the `publicKey` attributes of the outline
+/// elements of the given OPML document, in document order. Outlines without
+/// a public key (e.g. those grouping other outlines) are ignored.
+pub fn opml_public_keys(opml: &str) -> Vec<String> {
+    opml.split("<outline")
+        .skip(1)
+        .filter(|outline| outline.starts_with(char::is_whitespace))
+        .filter_map(|outline| {
+            let tag = &outline[..outline.find('>')?];
+            xml_attribute(tag, "publicKey")
+        })
+        .collect()
+}
+
+/// Return the unescaped value of the attribute with the given name from the
+/// given contents of an XML tag.
+fn xml_attribute(tag: &str, name: &str) -> Option<String> {
+    let pattern = format!("{}=", name);
+    let mut rest = tag;
+    while let Some(index) = rest.find(&pattern) {
+        let is_attribute = rest[..index].ends_with(char::is_whitespace);
+        rest = &rest[index + pattern.len()..];
+        if is_attribute {
+            let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+            let value = &rest[1..];
+            return value.find(quote).map(|end| unescape_xml(&value[..end]));
+        }
+    }
+
+    None
+}
+
+/// Extract the channels (hashtags) mentioned in the given post text.
+///
+/// Channel names are returned in lowercase and without the `#` prefix. Each
+/// channel is returned once, in the order in which it is first mentioned.
+pub fn extract_channels(text: &str) -> Vec<String> {
+    // Decode the text first so that a hashtag following an escaped newline is
+    // still recognised.
+    let text = decode_text(text);
+
+    let mut channels: Vec<String> = Vec::new();
+    let mut chars = text.chars().peekable();
+    let mut previous: Option<char> = None;
+
+    while let Some(c) = chars.next() {
+        // A hashtag must not be preceded by a word character (this excludes
+        // URL fragments such as `example.com/#section` and `C#`).
+        if c == '#' && !matches!(previous, Some(p) if p.is_alphanumeric() || p == '/') {
+            let mut channel = String::new();
+            while let Some(&next) = chars.peek() {
+                if next.is_alphanumeric() || next == '-' || next == '_' {
+                    channel.extend(next.to_lowercase());
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            previous = channel.chars().last().or(Some(c));
+            if !channel.is_empty() && !channels.contains(&channel) {
+                channels.push(channel);
+            }
+        } else {
+            previous = Some(c);
+        }
+    }
+
+    channels
+}
+
+/// Normalize the given text for search matching.
+///
+/// The text is converted to Unicode Normalization Form C (NFC) and lowercased
+/// so that matching is insensitive to case and to differences in how
+/// accented characters were composed (e.g. `é` vs. `e` + combining accent).
+pub fn normalize_for_search(text: &str) -> String {
+    text.nfc().collect::<String>().to_lowercase()
+}
+
+/// Return `true` if the given post text contains any of the given filter
+/// words.
+///
+/// Matching is performed against whole words and is case-insensitive; the
+/// filter words are expected to have been normalized with
+/// `normalize_for_search`.
+pub fn contains_filter_word(text: &str, filter_words: &[String]) -> bool {
+    if filter_words.is_empty() {
+        return false;
+    }
+
+    normalize_for_search(&decode_text(text))
+        .split(|c: char| !c.is_alphanumeric())
+        .any(|word| filter_words.iter().any(|filter_word| filter_word == word))
+}
+
+/// Return a CSS hex color (e.g. `#2f9e8a`) derived from the given public key,
+/// used to visually distinguish peers.
+///
+/// The hue is taken from an FNV-1a hash of the key, which (unlike the hasher
+/// of the standard library) is stable across builds; saturation and
+/// lightness are fixed so that every color is legible.
+pub fn color_for_key(public_key: &str) -> String {
+    let hash = public_key
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    let hue = (hash % 360) as f64;
+    let (saturation, lightness) = (0.65, 0.45);
+
+    // Convert the HSL color to RGB.
+    let chroma = (1.0 - (2.0 * lightness - 1.0f64).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let to_byte = |channel: f64| ((channel + m) * 255.0).round() as u8;
+
+    format!("#{:02x}{:02x}{:02x}", to_byte(r), to_byte(g), to_byte(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUB_KEY: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+    const SEED: &str = "ZS4rl2MDhPMTsRjVs0+s+GhNbXQU8pgzx6R/mXcTkrc=";
+
+    #[test]
+    fn parse_invite_accepts_legacy_and_multiserver_formats() {
+        let expected = Invite {
+            host: "pub.example.com".to_string(),
+            port: 8008,
+            public_key: PUB_KEY.to_string(),
+        };
+
+        let legacy = format!("pub.example.com:8008:{}~{}", PUB_KEY, SEED);
+        assert_eq!(parse_invite(&legacy), Ok(expected));
+
+        let key = &PUB_KEY[1..PUB_KEY.len() - ".ed25519".len()];
+        let multiserver = format!(" net:pub.example.com:8008~shs:{}:{}\n", key, SEED);
+        assert_eq!(
+            parse_invite(&multiserver).map(|invite| invite.public_key),
+            Ok(PUB_KEY.to_string())
+        );
+    }
+
+    #[test]
+    fn parse_invite_rejects_malformed_invites() {
+        let malformed = [
+            String::new(),
+            PUB_KEY.to_string(),
+            format!("pub.example.com:8008:{}", PUB_KEY),
+            format!("pub.example.com:{}~{}", PUB_KEY, SEED),
+            format!("pub.example.com:80080:{}~{}", PUB_KEY, SEED),
+            format!(":8008:{}~{}", PUB_KEY, SEED),
+            format!("pub.example.com:8008:@short.ed25519~{}", SEED),
+            format!("pub.example.com:8008:{}~", PUB_KEY),
+            format!("net:pub.example.com:8008~{}", SEED),
+        ];
+        for invite in malformed {
+            assert!(parse_invite(&invite).is_err(), "{}", invite);
+        }
+    }
+
+    #[test]
+    fn validate_message_id_requires_sigil_suffix_and_length() {
+        let msg_id = "%uv0Hk0hKcrgL9wSkXMRyvBq3XrUmSLh/bf6D3vXF6hY=.sha256";
+        assert!(validate_message_id(msg_id).is_ok());
+
+        // Missing sigil.
+        assert!(validate_message_id(&msg_id[1..]).is_err());
+        assert!(validate_message_id(&msg_id.replace('%', "@")).is_err());
+        // Wrong suffix.
+        assert!(validate_message_id(&msg_id.replace(".sha256", ".ed25519")).is_err());
+        assert!(validate_message_id("%uv0Hk0hKcrgL9wSkXMRyvBq3XrUmSLh/bf6D3vXF6hY=").is_err());
+        // Wrong length.
+        assert!(validate_message_id("%short.sha256").is_err());
+        assert!(validate_message_id(&msg_id.replace("=.", "==.")).is_err());
+    }
+
+    #[test]
+    fn validate_blob_id_requires_sigil_and_hash() {
+        let blob_id = "&uv0Hk0hKcrgL9wSkXMRyvBq3XrUmSLh/bf6D3vXF6hY=.sha256";
+        assert!(validate_blob_id(blob_id).is_ok());
+        assert!(validate_blob_id(&blob_id.replace('&', "%")).is_err());
+        assert!(validate_blob_id("&short.sha256").is_err());
+        assert!(validate_blob_id(&blob_id.replace(".sha256", ".sha512")).is_err());
+    }
+
+    #[test]
+    fn image_extension_is_detected_from_magic_number() {
+        assert_eq!(image_extension(b"\x89PNG\r\n\x1a\nrest"), Some("png"));
+        assert_eq!(image_extension(&[0xff, 0xd8, 0xff, 0xe0]), Some("jpg"));
+        assert_eq!(image_extension(b"GIF89a..."), Some("gif"));
+        assert_eq!(image_extension(b"RIFF\0\0\0\0WEBPVP8 "), Some("webp"));
+        assert_eq!(image_extension(b"<svg></svg>"), None);
+        assert_eq!(image_extension(b""), None);
+    }
+
+    #[test]
+    fn validate_post_text_rejects_empty_and_oversized_texts() {
+        assert!(validate_post_text("hello world").is_ok());
+        assert!(validate_post_text("").is_err());
+        assert!(validate_post_text(" \n\t").is_err());
+
+        let max_length = MESSAGE_MAX_LENGTH - MESSAGE_ENVELOPE_LENGTH;
+        assert!(validate_post_text(&"a".repeat(max_length - 100)).is_ok());
+        assert!(validate_post_text(&"a".repeat(max_length)).is_err());
+        // Escaped characters count towards the encoded length.
+        assert!(validate_post_text(&"\n".repeat(max_length / 2)).is_err());
+    }
+
+    #[test]
+    fn validate_network_key_requires_base64_of_32_bytes() {
+        // The network key of the main network.
+        assert!(validate_network_key("1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s=").is_ok());
+        assert!(validate_network_key("1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/s").is_err());
+        assert!(validate_network_key("1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRan/sA").is_err());
+        assert!(validate_network_key("1KHLiKZvAvjbY1ziZEHMXawbCEIM6qwjCDm3VYRa!/s=").is_err());
+        assert!(validate_network_key("").is_err());
+    }
+
+    #[test]
+    fn escape_xml_escapes_special_characters() {
+        assert_eq!(
+            escape_xml("<b>Tom & \"Jerry\"'s</b>"),
+            "&lt;b&gt;Tom &amp; &quot;Jerry&quot;&apos;s&lt;/b&gt;"
+        );
+        assert_eq!(escape_xml("glyph"), "glyph");
+        assert_eq!(
+            unescape_xml(&escape_xml("<b>Tom & \"Jerry\"'s</b>")),
+            "<b>Tom & \"Jerry\"'s</b>"
+        );
+    }
+
+    #[test]
+    fn opml_public_keys_are_extracted_from_outlines() {
+        let opml = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+                    <opml version=\"2.0\">\n<body>\n\
+                    <outline text=\"friends\">\n\
+                    <outline type=\"ssb\" text=\"glyph\" \
+                    publicKey=\"@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519\"/>\n\
+                    <outline text='myco' publicKey='@zqshk7o2Rpd/OaZ/MxH6xXONgonP1jH+edK9+GZb/NY=.ed25519'/>\n\
+                    </outline>\n\
+                    <outline text=\"publicKey=&quot;x&quot;\" publicKey=\"not a key\"/>\n\
+                    </body>\n</opml>\n";
+
+        assert_eq!(
+            opml_public_keys(opml),
+            vec![
+                "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519",
+                "@zqshk7o2Rpd/OaZ/MxH6xXONgonP1jH+edK9+GZb/NY=.ed25519",
+                "not a key",
+            ]
+        );
+        assert!(opml_public_keys("<opml><body/></opml>").is_empty());
+    }
+
+    #[test]
+    fn decode_text_unescapes_json_strings() {
+        assert_eq!(decode_text("\"hello\\nworld\""), "hello\nworld");
+        assert_eq!(decode_text("not json"), "not json");
+    }
+
+    #[test]
+    fn normalize_for_search_unifies_composition_and_case() {
+        let precomposed = "Caf\u{e9}";
+        let decomposed = "cafe\u{301}";
+        assert_ne!(precomposed, decomposed);
+        assert_eq!(
+            normalize_for_search(precomposed),
+            normalize_for_search(decomposed)
+        );
+        assert_eq!(normalize_for_search("CAF\u{c9}"), "caf\u{e9}");
+    }
+
+    #[test]
+    fn color_for_key_is_deterministic_and_distributed() {
+        let key = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+        let color = color_for_key(key);
+        assert_eq!(color, color_for_key(key));
+        assert_eq!(color.len(), 7);
+        assert!(color.starts_with('#'));
+
+        let colors: std::collections::HashSet<String> = (0..50)
+            .map(|i| color_for_key(&format!("@peer{}.ed25519", i)))
+            .collect();
+        assert!(colors.len() >= 40);
+    }
+
+    #[test]
+    fn contains_filter_word_matches_whole_words_ignoring_case() {
+        let filter_words = vec!["spoiler".to_string()];
+        assert!(contains_filter_word(
+            "\"Major SPOILER ahead\"",
+            &filter_words
+        ));
+        assert!(contains_filter_word("\"spoiler: it ends\"", &filter_words));
+    }
+
+    #[test]
+    fn contains_filter_word_ignores_partial_matches() {
+        let filter_words = vec!["spoiler".to_string()];
+        assert!(!contains_filter_word("\"no spoilers here\"", &filter_words));
+        assert!(!contains_filter_word("\"a plain post\"", &filter_words));
+        assert!(!contains_filter_word("\"spoiler\"", &[]));
+    }
+
+    #[test]
+    fn extract_channels_finds_lowercase_hashtags() {
+        assert_eq!(
+            extract_channels("\"Planting #Garlic today #permaculture #garlic\""),
+            vec!["garlic", "permaculture"]
+        );
+    }
+
+    #[test]
+    fn extract_channels_handles_escapes_and_non_tags() {
+        assert_eq!(
+            extract_channels("\"see example.com/#anchor and C#\\n#rust-lang, # nothing\""),
+            vec!["rust-lang"]
+        );
+        assert!(extract_channels("\"no channels here\"").is_empty());
+    }
+}